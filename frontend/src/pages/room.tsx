@@ -5,6 +5,7 @@ import {
   SubscribeTransport,
   simulcastEncodings,
 } from "rheomesh";
+import { ChatMessage, Media, ReceivedMessage } from "@/signaling";
 
 const peerConnectionConfig: RTCConfiguration = {
   iceServers: [
@@ -40,13 +41,36 @@ export default function Room() {
   const [localVideo, setLocalVideo] = useState<MediaStream>();
   const [localAudio, setLocalAudio] = useState<MediaStream>();
   const [subscriberIds, setSubscriberIds] = useState<Array<string>>([]);
+  const [activeSpeakers, setActiveSpeakers] = useState<Array<string>>([]);
+  const [participantId, setParticipantId] = useState("");
+  const [chatMessages, setChatMessages] = useState<Array<ChatMessage>>([]);
+  const [chatText, setChatText] = useState("");
   const [sid, setSid] = useState<number>(2);
   const [tid, setTid] = useState<number>(2);
+  const [waiting, setWaiting] = useState(false);
+  // Moderators get lobby messages of the others after they joined.
+  const joined = useRef(false);
+  const [lobby, setLobby] = useState<Array<string>>([]);
+  const [qualities, setQualities] = useState<{
+    [participantId: string]: number;
+  }>({});
 
   const ws = useRef<WebSocket | null>(null);
   const sendingVideoRef = useRef<HTMLVideoElement>(null);
   const publishTransport = useRef<PublishTransport | null>(null);
   const subscribeTransport = useRef<SubscribeTransport | null>(null);
+  // Media configuration of the room, which is given by Joined.
+  const media = useRef<Media | null>(null);
+  // ICE servers given by Joined, when the server runs the embedded TURN server.
+  const iceServers = useRef<Array<RTCIceServer>>([]);
+  const transportConfig = (): RTCConfiguration =>
+    iceServers.current.length > 0
+      ? { iceServers: iceServers.current }
+      : peerConnectionConfig;
+
+  const send = (message: ReceivedMessage) => {
+    ws.current!.send(JSON.stringify(message));
+  };
 
   useEffect(() => {
     if (router.query.room) {
@@ -56,11 +80,35 @@ export default function Room() {
 
   const connect = () => {
     const url = process.env.NEXT_PUBLIC_WS_URL as string;
-    ws.current = new WebSocket(`${url}/socket?room=${room}`);
+    const params = new URLSearchParams({ room });
+    for (const key of ["profile", "password", "lobby", "moderator"]) {
+      if (router.query[key]) {
+        params.set(key, router.query[key] as string);
+      }
+    }
+    open(`${url}/socket?${params}`);
+    setInterval(() => {
+      if (ws.current && ws.current.readyState === WebSocket.OPEN) {
+        send({ action: "Ping" });
+      }
+    }, 5000);
+  };
+
+  const open = (socketUrl: string) => {
+    ws.current = new WebSocket(socketUrl);
     ws.current.onopen = () => {
       console.debug("Connected websocket server");
-      startPublishPeer();
-      startSubscribePeer();
+      send({
+        action: "Hello",
+        protocolVersion: 2,
+        capabilities: [
+          "activeSpeakers",
+          "layerChanged",
+          "chat",
+          "dataChannel",
+          "connectionQuality",
+        ],
+      });
       setConnected(true);
     };
     ws.current.onclose = () => {
@@ -71,47 +119,36 @@ export default function Room() {
       console.error(e);
     };
     ws.current.onmessage = messageHandler;
-    setInterval(() => {
-      if (ws.current && ws.current.readyState === WebSocket.OPEN) {
-        ws.current.send(JSON.stringify({ action: "Ping" }));
-      }
-    }, 5000);
   };
 
   const startPublishPeer = () => {
     if (!publishTransport.current) {
-      publishTransport.current = new PublishTransport(peerConnectionConfig);
-      ws.current!.send(JSON.stringify({ action: "PublisherInit" }));
+      publishTransport.current = new PublishTransport(transportConfig());
+      send({ action: "PublisherInit" });
       publishTransport.current.on("icecandidate", (candidate) => {
-        ws.current!.send(
-          JSON.stringify({
-            action: "PublisherIce",
-            candidate: candidate,
-          }),
-        );
+        send({
+          action: "PublisherIce",
+          candidate: candidate,
+        });
       });
       publishTransport.current.on("negotiationneeded", (offer) => {
-        ws.current!.send(
-          JSON.stringify({
-            action: "Offer",
-            sdp: offer,
-          }),
-        );
+        send({
+          action: "Offer",
+          sdp: offer,
+        });
       });
     }
   };
 
   const startSubscribePeer = () => {
     if (!subscribeTransport.current) {
-      subscribeTransport.current = new SubscribeTransport(peerConnectionConfig);
-      ws.current!.send(JSON.stringify({ action: "SubscriberInit" }));
+      subscribeTransport.current = new SubscribeTransport(transportConfig());
+      send({ action: "SubscriberInit" });
       subscribeTransport.current.on("icecandidate", (candidate) => {
-        ws.current!.send(
-          JSON.stringify({
-            action: "SubscriberIce",
-            candidate: candidate,
-          }),
-        );
+        send({
+          action: "SubscriberIce",
+          candidate: candidate,
+        });
       });
     }
   };
@@ -122,7 +159,7 @@ export default function Room() {
     switch (message.action) {
       case "Offer":
         subscribeTransport.current!.setOffer(message.sdp).then((answer) => {
-          ws.current!.send(JSON.stringify({ action: "Answer", sdp: answer }));
+          send({ action: "Answer", sdp: answer });
         });
         break;
       case "Answer":
@@ -136,34 +173,77 @@ export default function Room() {
         break;
       case "Published":
         message.publisherIds.forEach((publisherId: string) => {
-          ws.current!.send(
-            JSON.stringify({
-              action: "Subscribe",
-              publisherId: publisherId,
-            }),
-          );
-          subscribeTransport
-            .current!.subscribe(publisherId)
-            .then((subscriber) => {
-              const stream = new MediaStream([subscriber.track]);
-              if (subscriber.track.kind === "audio") {
-                setRecevingAudio((prev) => ({
-                  ...prev,
-                  [publisherId]: stream,
-                }));
-              } else {
-                setRecevingVideo((prev) => ({
-                  ...prev,
-                  [publisherId]: stream,
-                }));
-              }
-            });
+          send({
+            action: "Subscribe",
+            publisherId: publisherId,
+          });
+          receive(publisherId);
         });
 
+        break;
+      case "Redirect":
+        // The room is on another server, so peers are started again there.
+        ws.current!.onclose = null;
+        ws.current!.close();
+        publishTransport.current?.close();
+        publishTransport.current = null;
+        subscribeTransport.current?.close();
+        subscribeTransport.current = null;
+        open(message.url);
+        break;
+      case "Welcome":
+        console.debug(
+          `Protocol version ${message.protocolVersion}: ${message.capabilities}`,
+        );
+        break;
+      case "Error":
+        console.error(`${message.code}: ${message.message}`);
+        break;
+      case "Joined":
+        setParticipantId(message.participantId);
+        media.current = message.media;
+        iceServers.current = message.iceServers;
+        joined.current = true;
+        // The publish transport is created when the participant publishes.
+        startSubscribePeer();
+        break;
+      case "LobbyJoined":
+        if (joined.current) {
+          setLobby((prev) => [...prev, message.participantId]);
+        } else {
+          setWaiting(true);
+        }
+        break;
+      case "Admitted":
+        setWaiting(false);
+        setLobby((prev) => prev.filter((id) => id !== message.participantId));
+        break;
+      case "LobbyLeft":
+        setLobby((prev) => prev.filter((id) => id !== message.participantId));
+        break;
+      case "Chat":
+        setChatMessages((prev) => [...prev, message.message]);
+        break;
+      case "ChatHistory":
+        setChatMessages((prev) => [...message.messages, ...prev]);
         break;
       case "Subscribed":
         setSubscriberIds((prev) => [...prev, message.subscriberId]);
         break;
+      case "LayerChanged":
+        console.debug(
+          `Layer of ${message.subscriberId} is changed: sid=${message.sid}, tid=${message.tid}`,
+        );
+        break;
+      case "ActiveSpeakers":
+        setActiveSpeakers(message.publisherIds);
+        break;
+      case "ConnectionQuality":
+        setQualities((prev) => ({
+          ...prev,
+          [message.participantId]: message.score,
+        }));
+        break;
       case "Pong":
         console.debug("pong");
         break;
@@ -173,6 +253,25 @@ export default function Room() {
     }
   };
 
+  const receive = (publisherId: string) => {
+    subscribeTransport
+      .current!.subscribe(publisherId)
+      .then((subscriber) => {
+        const stream = new MediaStream([subscriber.track]);
+        if (subscriber.track.kind === "audio") {
+          setRecevingAudio((prev) => ({
+            ...prev,
+            [publisherId]: stream,
+          }));
+        } else {
+          setRecevingVideo((prev) => ({
+            ...prev,
+            [publisherId]: stream,
+          }));
+        }
+      });
+  };
+
   const capture = async () => {
     const stream = await navigator.mediaDevices.getDisplayMedia({
       video: true,
@@ -187,23 +286,48 @@ export default function Room() {
   };
 
   const publish = async (stream: MediaStream) => {
+    startPublishPeer();
     stream.getTracks().forEach(async (track) => {
       const publisher = await publishTransport.current!.publish(
         track,
-        simulcastEncodings(),
-      );
-      ws.current!.send(
-        JSON.stringify({
-          action: "Offer",
-          sdp: publisher.offer,
-        }),
-      );
-      ws.current!.send(
-        JSON.stringify({ action: "Publish", trackId: publisher.id }),
+        encodings(),
       );
+      send({
+        action: "Offer",
+        sdp: publisher.offer,
+      });
+      send({ action: "Publish", trackId: publisher.id });
     });
   };
 
+  // Publishers follow the media configuration of the room, and use simulcast unless it is disabled.
+  const encodings = (): Array<RTCRtpEncodingParameters> => {
+    const maxBitrate = media.current?.maxBitrate ?? undefined;
+    if (!media.current || media.current.simulcast) {
+      return simulcastEncodings().map((encoding) => ({
+        ...encoding,
+        maxBitrate:
+          maxBitrate && encoding.maxBitrate
+            ? Math.min(maxBitrate, encoding.maxBitrate)
+            : (maxBitrate ?? encoding.maxBitrate),
+      }));
+    }
+    const encoding: RTCRtpEncodingParameters & { scalabilityMode?: string } =
+      { maxBitrate };
+    if (media.current.scalabilityMode) {
+      encoding.scalabilityMode = media.current.scalabilityMode;
+    }
+    return [encoding];
+  };
+
+  const sendChat = () => {
+    if (chatText.length === 0) {
+      return;
+    }
+    send({ action: "SendChat", text: chatText });
+    setChatText("");
+  };
+
   const restart = async () => {
     await restartPublish();
     await restartSubscribe();
@@ -213,12 +337,10 @@ export default function Room() {
     if (!publishTransport.current) return;
     try {
       const offer = await publishTransport.current.restartIce();
-      ws.current!.send(
-        JSON.stringify({
-          action: "Offer",
-          sdp: offer,
-        }),
-      );
+      send({
+        action: "Offer",
+        sdp: offer,
+      });
     } catch (err) {
       console.error(err);
     }
@@ -226,36 +348,28 @@ export default function Room() {
 
   const restartSubscribe = async () => {
     if (!subscribeTransport.current) return;
-    ws.current!.send(
-      JSON.stringify({
-        action: "RestartICE",
-      }),
-    );
+    send({
+      action: "RestartICE",
+    });
   };
 
   const stop = async () => {
     localVideo?.getTracks().forEach((track) => {
-      ws.current!.send(
-        JSON.stringify({ action: "StopPublish", publisherId: track.id }),
-      );
+      send({ action: "StopPublish", publisherId: track.id });
       track.stop();
     });
     setLocalVideo(undefined);
     localAudio?.getTracks().forEach((track) => {
-      ws.current!.send(
-        JSON.stringify({ action: "StopPublish", publisherId: track.id }),
-      );
+      send({ action: "StopPublish", publisherId: track.id });
       track.stop();
     });
     setLocalAudio(undefined);
 
     subscriberIds.forEach((id) => {
-      ws.current!.send(
-        JSON.stringify({
-          action: "StopSubscribe",
-          subscriberId: id,
-        }),
-      );
+      send({
+        action: "StopSubscribe",
+        subscriberId: id,
+      });
     });
     setSubscriberIds([]);
     publishTransport.current?.close();
@@ -269,14 +383,12 @@ export default function Room() {
 
   const setPrefferedLayer = (sid: number, tid: number) => {
     subscriberIds.forEach((id) => {
-      ws.current!.send(
-        JSON.stringify({
-          action: "SetPreferredLayer",
-          subscriberId: id,
-          sid: sid,
-          tid: tid,
-        }),
-      );
+      send({
+        action: "SetPreferredLayer",
+        subscriberId: id,
+        sid: sid,
+        tid: tid,
+      });
     });
   };
 
@@ -303,11 +415,38 @@ export default function Room() {
           Connect
         </button>
       </div>
+      {waiting && <div className="mt-2">Waiting for a moderator</div>}
+      {Object.entries(qualities).map(([id, score]) => (
+        <div className="mt-2" key={id}>
+          <span>{id === participantId ? "me" : id.slice(0, 8)}</span>
+          <span className="ml-2 font-mono">
+            {"▮".repeat(score)}
+            {"▯".repeat(5 - score)}
+          </span>
+        </div>
+      ))}
+      {lobby.map((id) => (
+        <div className="mt-2" key={id}>
+          <span>{id}</span>
+          <button
+            onClick={() => send({ action: "Admit", participantId: id })}
+            className="ml-2 bg-blue-500 text-white px-4 py-1 rounded-md hover:bg-blue-600"
+          >
+            Admit
+          </button>
+          <button
+            onClick={() => send({ action: "Deny", participantId: id })}
+            className="ml-2 bg-red-500 text-white px-4 py-1 rounded-md hover:bg-red-600"
+          >
+            Deny
+          </button>
+        </div>
+      ))}
       <div className="mt-2">
         <button
           id="capture"
           onClick={capture}
-          disabled={localVideo !== undefined || !connected}
+          disabled={localVideo !== undefined || !connected || waiting}
           className="bg-blue-500 text-white px-4 py-1 rounded-md hover:bg-blue-600 disabled:opacity-50 disabled:hover:bg-blue-500"
         >
           Capture
@@ -345,6 +484,30 @@ export default function Room() {
           Stop
         </button>
       </div>
+      <div className="mt-2">
+        <input
+          type="text"
+          value={chatText}
+          onChange={(e) => setChatText(e.target.value)}
+          onKeyDown={(e) => e.key === "Enter" && sendChat()}
+          disabled={!connected}
+          className="border px-2 py-1 rounded-md"
+        />
+        <button
+          onClick={sendChat}
+          disabled={!connected}
+          className="bg-blue-500 text-white px-4 py-1 rounded-md hover:bg-blue-600 disabled:opacity-50 disabled:hover:bg-blue-500"
+        >
+          Send
+        </button>
+        <ul>
+          {chatMessages.map((m) => (
+            <li key={m.id}>
+              {m.from === participantId ? "me" : m.from.slice(0, 8)}: {m.text}
+            </li>
+          ))}
+        </ul>
+      </div>
       <h3>My Screen</h3>
       <video
         autoPlay
@@ -375,7 +538,16 @@ export default function Room() {
         </div>
       ))}
       {Object.keys(recevingAudio).map((key) => (
-        <div key={key}>
+        <div
+          key={key}
+          className={
+            activeSpeakers[0] === key
+              ? "ring-2 ring-green-500"
+              : activeSpeakers.includes(key)
+                ? "ring-1 ring-green-300"
+                : ""
+          }
+        >
           {recevingAudio[key] && (
             <audio
               id={key}
@@ -0,0 +1,287 @@
+// Generated by server/build.rs from the signaling messages. Do not edit.
+
+export type Candidate = {
+  address: string;
+  port: number;
+  protocol: string;
+  candidateType: string;
+};
+
+export type CandidatePair = {
+  local: Candidate;
+  remote: Candidate;
+};
+
+/** Optional features. The server only sends notifications of the capabilities which the client declares. */
+export type Capability =
+  | "activeSpeakers"
+  | "layerChanged"
+  | "chat"
+  | "dataChannel"
+  | "connectionQuality";
+
+export type ChatMessage = {
+  id: string;
+  from: string;
+  to?: string | null;
+  text: string;
+  sentAt: number;
+};
+
+export type ErrorCode =
+  | "invalidMessage"
+  | "unknownAction"
+  | "unsupportedVersion"
+  | "notAdmitted"
+  | "notModerator"
+  | "noTransport";
+
+/** An ICE server given to clients in Joined, in the form of RTCIceServer of browsers. */
+export type IceServer = {
+  urls: Array<string>;
+  username: string;
+  credential: string;
+};
+
+export type Layer = {
+  sid: number;
+  tid?: number | null;
+};
+
+/** Media configuration of a room, which is sent to participants when they join. */
+export type Media = {
+  profile: Profile;
+  codecs: Array<string>;
+  simulcast: boolean;
+  maxBitrate?: number | null;
+  scalabilityMode?: string | null;
+};
+
+/** A set of codecs and encodings which suits some clients. */
+export type Profile =
+  | "default"
+  | "audioOnly"
+  | "h264"
+  | "vp9Svc"
+  | "av1";
+
+/** Messages from clients to the server. */
+export type ReceivedMessage =
+  | { action: "Ping" }
+  | {
+      action: "Hello";
+      protocolVersion: number;
+      capabilities?: Array<string>;
+    }
+  | { action: "PublisherInit" }
+  | { action: "SubscriberInit" }
+  | { action: "PublisherClose" }
+  | { action: "SubscriberClose" }
+  | { action: "RequestPublish" }
+  | {
+      action: "PublisherIce";
+      candidate: RTCIceCandidateInit;
+    }
+  | {
+      action: "SubscriberIce";
+      candidate: RTCIceCandidateInit;
+    }
+  | {
+      action: "Offer";
+      sdp: RTCSessionDescriptionInit;
+    }
+  | {
+      action: "Subscribe";
+      publisherId: string;
+    }
+  | {
+      action: "Answer";
+      sdp: RTCSessionDescriptionInit;
+    }
+  | {
+      action: "Publish";
+      trackId: string;
+    }
+  | {
+      action: "StopPublish";
+      publisherId: string;
+    }
+  | {
+      action: "StopSubscribe";
+      subscriberId: string;
+    }
+  | {
+      action: "PublishData";
+      label: string;
+    }
+  | {
+      action: "StopPublishData";
+      dataPublisherId: string;
+    }
+  | {
+      action: "SubscribeData";
+      dataPublisherId: string;
+    }
+  | {
+      action: "StopSubscribeData";
+      dataSubscriberId: string;
+    }
+  | {
+      action: "SendChat";
+      text: string;
+      to?: string | null;
+    }
+  | {
+      action: "SetPreferredLayer";
+      subscriberId: string;
+      sid: number;
+      tid?: number | null;
+    }
+  | { action: "RestartICE" }
+  | {
+      action: "SetViewport";
+      tiles: Array<Tile>;
+    }
+  | {
+      action: "Admit";
+      participantId: string;
+    }
+  | {
+      action: "Deny";
+      participantId: string;
+    }
+  | { action: "GetStats" };
+
+/** Messages from the server to clients. */
+export type SendingMessage =
+  | { action: "Pong" }
+  | {
+      action: "Welcome";
+      protocolVersion: number;
+      capabilities: Array<Capability>;
+      participantId: string;
+    }
+  | {
+      action: "Error";
+      code: ErrorCode;
+      message: string;
+      requestAction?: string | null;
+    }
+  | {
+      action: "Redirect";
+      url: string;
+    }
+  | {
+      action: "Joined";
+      participantId: string;
+      moderator: boolean;
+      media: Media;
+      iceServers: Array<IceServer>;
+    }
+  | { action: "StartAsPublisher" }
+  | {
+      action: "Answer";
+      sdp: RTCSessionDescriptionInit;
+    }
+  | {
+      action: "Offer";
+      sdp: RTCSessionDescriptionInit;
+    }
+  | {
+      action: "PublisherIce";
+      candidate: RTCIceCandidateInit;
+    }
+  | {
+      action: "SubscriberIce";
+      candidate: RTCIceCandidateInit;
+    }
+  | {
+      action: "Published";
+      publisherIds: Array<string>;
+    }
+  | {
+      action: "DataPublished";
+      dataPublisherIds: Array<string>;
+    }
+  | {
+      action: "DataSubscribed";
+      dataSubscriberId: string;
+      dataPublisherId: string;
+    }
+  | {
+      action: "Chat";
+      message: ChatMessage;
+    }
+  | {
+      action: "ChatHistory";
+      messages: Array<ChatMessage>;
+    }
+  | {
+      action: "Subscribed";
+      subscriberId: string;
+    }
+  | {
+      action: "LayerChanged";
+      subscriberId: string;
+      sid: number;
+      tid?: number | null;
+    }
+  | {
+      action: "ActiveSpeakers";
+      publisherIds: Array<string>;
+      levels: Array<number>;
+    }
+  | {
+      action: "LobbyJoined";
+      participantId: string;
+    }
+  | {
+      action: "Admitted";
+      participantId: string;
+    }
+  | {
+      action: "LobbyLeft";
+      participantId: string;
+    }
+  | {
+      action: "Stats";
+      publish?: TransportStats | null;
+      subscribe?: TransportStats | null;
+    }
+  | {
+      action: "ConnectionQuality";
+      participantId: string;
+      score: number;
+    };
+
+/** A participant on the screen of a client, with the size of its tile in CSS pixels. */
+export type Tile = {
+  publisherId: string;
+  width: number;
+  height: number;
+};
+
+/** An RTP stream of a publisher which the server receives, or of a subscriber which the server sends. Simulcast publishers have a stream for each layer. */
+export type TrackStats = {
+  publisherId: string;
+  subscriberId?: string | null;
+  kind: string;
+  ssrc: number;
+  rid?: string | null;
+  bitrate?: number | null;
+  packets: number;
+  packetsLost?: number | null;
+  fractionLost?: number | null;
+  jitter?: number | null;
+  roundTripTime?: number | null;
+  nackCount: number;
+  pliCount: number;
+  layer?: Layer | null;
+};
+
+export type TransportStats = {
+  transportId: string;
+  connectionState: string;
+  selectedCandidatePair?: CandidatePair | null;
+  tracks: Array<TrackStats>;
+};
@@ -0,0 +1,536 @@
+// Generates JSON Schema and TypeScript definitions of the signaling messages from their Rust definitions,
+// so clients follow the serde attributes of ReceivedMessage and SendingMessage.
+// The output is compared with the checked-in files by a test in protocol.rs.
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+use serde_json::{json, Map, Value};
+use syn::{Attribute, Expr, Fields, GenericArgument, Item, Lit, LitStr, PathArguments, Type};
+
+// Files which define the messages and the types in them.
+const SOURCES: [&str; 7] = [
+    "src/websocket.rs",
+    "src/media/mod.rs",
+    "src/subscription.rs",
+    "src/chat.rs",
+    "src/protocol.rs",
+    "src/turn/mod.rs",
+    "src/stats.rs",
+];
+const ROOTS: [&str; 2] = ["ReceivedMessage", "SendingMessage"];
+
+fn main() {
+    for source in SOURCES {
+        println!("cargo:rerun-if-changed={}", source);
+    }
+
+    let mut definitions = BTreeMap::new();
+    for source in SOURCES {
+        let text = fs::read_to_string(source).expect("failed to read source");
+        let file = syn::parse_file(&text).expect("failed to parse source");
+        for item in file.items {
+            if let Some(definition) = Definition::parse(&item) {
+                definitions.insert(definition.name.clone(), definition);
+            }
+        }
+    }
+
+    let mut reachable = BTreeMap::new();
+    for root in ROOTS {
+        collect(root, &definitions, &mut reachable);
+    }
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is not set");
+    let schema = serde_json::to_string_pretty(&json_schema(&reachable)).unwrap() + "\n";
+    fs::write(Path::new(&out_dir).join("signaling.schema.json"), schema)
+        .expect("failed to write JSON Schema");
+    fs::write(
+        Path::new(&out_dir).join("signaling.ts"),
+        typescript(&reachable),
+    )
+    .expect("failed to write TypeScript");
+}
+
+struct Definition {
+    name: String,
+    description: Option<String>,
+    shape: Shape,
+}
+
+enum Shape {
+    Struct(Vec<Field>),
+    // Enums of unit variants are strings.
+    Strings(Vec<String>),
+    // Internally tagged enums.
+    Tagged { tag: String, variants: Vec<Variant> },
+}
+
+struct Variant {
+    name: String,
+    description: Option<String>,
+    fields: Vec<Field>,
+}
+
+struct Field {
+    name: String,
+    ty: Ty,
+    // Missing fields are accepted.
+    optional: bool,
+}
+
+enum Ty {
+    String,
+    Boolean,
+    Integer { unsigned: bool },
+    Number,
+    Nullable(Box<Ty>),
+    Array(Box<Ty>),
+    Named(String),
+}
+
+#[derive(Default)]
+struct SerdeAttributes {
+    tag: Option<String>,
+    rename: Option<String>,
+    rename_all: Option<String>,
+    default: bool,
+    skip: bool,
+    // The fallback of unknown tags, which is not a message of the protocol.
+    other: bool,
+}
+
+impl SerdeAttributes {
+    fn parse(attrs: &[Attribute]) -> Self {
+        let mut result = Self::default();
+        for attr in attrs.iter().filter(|a| a.path().is_ident("serde")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("tag") {
+                    result.tag = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("rename") {
+                    result.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("rename_all") {
+                    let rule = meta.value()?.parse::<LitStr>()?.value();
+                    if rule != "camelCase" {
+                        return Err(meta.error("only camelCase is supported"));
+                    }
+                    result.rename_all = Some(rule);
+                } else if meta.path.is_ident("default") {
+                    result.default = true;
+                } else if meta.path.is_ident("skip") {
+                    result.skip = true;
+                } else if meta.path.is_ident("other") {
+                    result.other = true;
+                } else {
+                    return Err(meta.error("unsupported serde attribute"));
+                }
+                Ok(())
+            })
+            .expect("failed to parse serde attribute");
+        }
+        result
+    }
+}
+
+impl Definition {
+    fn parse(item: &Item) -> Option<Self> {
+        let (attrs, name) = match item {
+            Item::Struct(item) => (&item.attrs, item.ident.to_string()),
+            Item::Enum(item) => (&item.attrs, item.ident.to_string()),
+            _ => return None,
+        };
+        if !derives_serde(attrs) {
+            return None;
+        }
+        let serde = SerdeAttributes::parse(attrs);
+        let shape = match item {
+            Item::Struct(item) => Shape::Struct(fields(&item.fields, serde.rename_all.is_some())),
+            Item::Enum(item) => {
+                let variant_name = |variant: &syn::Variant| {
+                    let attributes = SerdeAttributes::parse(&variant.attrs);
+                    attributes.rename.unwrap_or_else(|| {
+                        let name = variant.ident.to_string();
+                        if serde.rename_all.is_some() {
+                            lower_first(&name)
+                        } else {
+                            name
+                        }
+                    })
+                };
+                match &serde.tag {
+                    Some(tag) => Shape::Tagged {
+                        tag: tag.clone(),
+                        variants: item
+                            .variants
+                            .iter()
+                            .filter(|variant| !SerdeAttributes::parse(&variant.attrs).other)
+                            .map(|variant| Variant {
+                                name: variant_name(variant),
+                                description: description(&variant.attrs),
+                                fields: fields(
+                                    &variant.fields,
+                                    SerdeAttributes::parse(&variant.attrs).rename_all.is_some(),
+                                ),
+                            })
+                            .collect(),
+                    },
+                    None => {
+                        if item
+                            .variants
+                            .iter()
+                            .any(|v| !matches!(v.fields, Fields::Unit))
+                        {
+                            panic!("{} must be internally tagged", name);
+                        }
+                        Shape::Strings(item.variants.iter().map(variant_name).collect())
+                    }
+                }
+            }
+            _ => unreachable!(),
+        };
+        Some(Self {
+            name,
+            description: description(attrs),
+            shape,
+        })
+    }
+
+    fn dependencies(&self) -> Vec<&str> {
+        let fields: Vec<&Field> = match &self.shape {
+            Shape::Struct(fields) => fields.iter().collect(),
+            Shape::Strings(_) => Vec::new(),
+            Shape::Tagged { variants, .. } => variants.iter().flat_map(|v| &v.fields).collect(),
+        };
+        fields.iter().filter_map(|f| f.ty.named()).collect()
+    }
+}
+
+impl Ty {
+    fn parse(ty: &Type) -> Self {
+        let Type::Path(path) = ty else {
+            panic!("unsupported type");
+        };
+        let segment = path.path.segments.last().expect("empty type path");
+        let argument = || match &segment.arguments {
+            PathArguments::AngleBracketed(args) => match args.args.first() {
+                Some(GenericArgument::Type(ty)) => Ty::parse(ty),
+                _ => panic!("unsupported type argument of {}", segment.ident),
+            },
+            _ => panic!("{} needs a type argument", segment.ident),
+        };
+        match segment.ident.to_string().as_str() {
+            "String" => Ty::String,
+            "bool" => Ty::Boolean,
+            "u8" | "u16" | "u32" | "u64" | "usize" => Ty::Integer { unsigned: true },
+            "i8" | "i16" | "i32" | "i64" | "isize" => Ty::Integer { unsigned: false },
+            "f32" | "f64" => Ty::Number,
+            "Option" => Ty::Nullable(Box::new(argument())),
+            "Vec" => Ty::Array(Box::new(argument())),
+            name => Ty::Named(name.to_owned()),
+        }
+    }
+
+    fn named(&self) -> Option<&str> {
+        match self {
+            Ty::Nullable(ty) | Ty::Array(ty) => ty.named(),
+            Ty::Named(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn json_schema(&self) -> Value {
+        match self {
+            Ty::String => json!({ "type": "string" }),
+            Ty::Boolean => json!({ "type": "boolean" }),
+            Ty::Integer { unsigned: true } => json!({ "type": "integer", "minimum": 0 }),
+            Ty::Integer { unsigned: false } => json!({ "type": "integer" }),
+            Ty::Number => json!({ "type": "number" }),
+            Ty::Nullable(ty) => json!({ "anyOf": [ty.json_schema(), { "type": "null" }] }),
+            Ty::Array(ty) => json!({ "type": "array", "items": ty.json_schema() }),
+            Ty::Named(name) => json!({ "$ref": format!("#/$defs/{}", name) }),
+        }
+    }
+
+    fn typescript(&self) -> String {
+        match self {
+            Ty::String => "string".to_owned(),
+            Ty::Boolean => "boolean".to_owned(),
+            Ty::Integer { .. } | Ty::Number => "number".to_owned(),
+            Ty::Nullable(ty) => format!("{} | null", ty.typescript()),
+            Ty::Array(ty) => format!("Array<{}>", ty.typescript()),
+            Ty::Named(name) => match EXTERNAL_TYPES.iter().find(|t| t.name == name) {
+                Some(external) => external.typescript.to_owned(),
+                None => name.clone(),
+            },
+        }
+    }
+}
+
+// Types of webrtc-rs in messages. Browsers have the same dictionaries.
+struct External {
+    name: &'static str,
+    typescript: &'static str,
+    json_schema: fn() -> Value,
+}
+
+const EXTERNAL_TYPES: [External; 2] = [
+    External {
+        name: "RTCSessionDescription",
+        typescript: "RTCSessionDescriptionInit",
+        json_schema: || {
+            json!({
+                "type": "object",
+                "properties": {
+                    "type": { "type": "string", "enum": ["offer", "pranswer", "answer", "rollback"] },
+                    "sdp": { "type": "string" },
+                },
+                "required": ["type", "sdp"],
+            })
+        },
+    },
+    External {
+        name: "RTCIceCandidateInit",
+        typescript: "RTCIceCandidateInit",
+        json_schema: || {
+            json!({
+                "type": "object",
+                "properties": {
+                    "candidate": { "type": "string" },
+                    "sdpMid": { "anyOf": [{ "type": "string" }, { "type": "null" }] },
+                    "sdpMLineIndex": {
+                        "anyOf": [{ "type": "integer", "minimum": 0 }, { "type": "null" }],
+                    },
+                    "usernameFragment": { "anyOf": [{ "type": "string" }, { "type": "null" }] },
+                },
+                "required": ["candidate"],
+            })
+        },
+    },
+];
+
+fn derives_serde(attrs: &[Attribute]) -> bool {
+    attrs
+        .iter()
+        .filter(|a| a.path().is_ident("derive"))
+        .any(|a| {
+            let mut found = false;
+            let _ = a.parse_nested_meta(|meta| {
+                found |= meta.path.is_ident("Serialize") || meta.path.is_ident("Deserialize");
+                Ok(())
+            });
+            found
+        })
+}
+
+fn description(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|a| a.path().is_ident("doc"))
+        .filter_map(|a| match &a.meta.require_name_value().ok()?.value {
+            Expr::Lit(expr) => match &expr.lit {
+                Lit::Str(s) => Some(s.value().trim().to_owned()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(" "))
+}
+
+fn fields(fields: &Fields, camel_case: bool) -> Vec<Field> {
+    match fields {
+        Fields::Unit => Vec::new(),
+        Fields::Named(named) => named
+            .named
+            .iter()
+            .filter_map(|field| {
+                let serde = SerdeAttributes::parse(&field.attrs);
+                if serde.skip {
+                    return None;
+                }
+                let ident = field.ident.as_ref().unwrap().to_string();
+                let name =
+                    serde
+                        .rename
+                        .unwrap_or_else(|| if camel_case { camel(&ident) } else { ident });
+                let ty = Ty::parse(&field.ty);
+                Some(Field {
+                    optional: serde.default || matches!(ty, Ty::Nullable(_)),
+                    name,
+                    ty,
+                })
+            })
+            .collect(),
+        Fields::Unnamed(_) => panic!("tuple variants are not supported"),
+    }
+}
+
+fn collect<'a>(
+    name: &str,
+    definitions: &'a BTreeMap<String, Definition>,
+    reachable: &mut BTreeMap<String, &'a Definition>,
+) {
+    if reachable.contains_key(name) || EXTERNAL_TYPES.iter().any(|t| t.name == name) {
+        return;
+    }
+    let definition = definitions
+        .get(name)
+        .unwrap_or_else(|| panic!("{} is not defined in {:?}", name, SOURCES));
+    reachable.insert(name.to_owned(), definition);
+    for dependency in definition.dependencies() {
+        collect(dependency, definitions, reachable);
+    }
+}
+
+fn camel(snake: &str) -> String {
+    let mut parts = snake.split('_');
+    let mut result = parts.next().unwrap_or_default().to_owned();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            result.extend(first.to_uppercase());
+            result.push_str(chars.as_str());
+        }
+    }
+    result
+}
+
+fn lower_first(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn object_schema(fields: &[Field], tag: Option<(&str, &str)>) -> Map<String, Value> {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    if let Some((tag, name)) = tag {
+        properties.insert(tag.to_owned(), json!({ "const": name }));
+        required.push(tag.to_owned());
+    }
+    for field in fields {
+        properties.insert(field.name.clone(), field.ty.json_schema());
+        if !field.optional {
+            required.push(field.name.clone());
+        }
+    }
+    let mut schema = Map::new();
+    schema.insert("type".to_owned(), json!("object"));
+    schema.insert("properties".to_owned(), Value::Object(properties));
+    schema.insert("required".to_owned(), json!(required));
+    schema
+}
+
+fn json_schema(definitions: &BTreeMap<String, &Definition>) -> Value {
+    let mut defs = Map::new();
+    for (name, definition) in definitions {
+        let mut schema = match &definition.shape {
+            Shape::Struct(fields) => object_schema(fields, None),
+            Shape::Strings(names) => {
+                let mut schema = Map::new();
+                schema.insert("type".to_owned(), json!("string"));
+                schema.insert("enum".to_owned(), json!(names));
+                schema
+            }
+            Shape::Tagged { tag, variants } => {
+                let variants: Vec<Value> = variants
+                    .iter()
+                    .map(|variant| {
+                        let mut schema = object_schema(&variant.fields, Some((tag, &variant.name)));
+                        if let Some(description) = &variant.description {
+                            schema.insert("description".to_owned(), json!(description));
+                        }
+                        Value::Object(schema)
+                    })
+                    .collect();
+                let mut schema = Map::new();
+                schema.insert("oneOf".to_owned(), json!(variants));
+                schema
+            }
+        };
+        if let Some(description) = &definition.description {
+            schema.insert("description".to_owned(), json!(description));
+        }
+        defs.insert(name.clone(), Value::Object(schema));
+    }
+    for external in EXTERNAL_TYPES {
+        defs.insert(external.name.to_owned(), (external.json_schema)());
+    }
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "livecamera signaling messages",
+        "$defs": defs,
+    })
+}
+
+fn typescript_fields(fields: &[Field], tag: Option<(&str, &str)>, indent: &str) -> String {
+    let mut lines = Vec::new();
+    if let Some((tag, name)) = tag {
+        lines.push(format!("{}{}: \"{}\";", indent, tag, name));
+    }
+    for field in fields {
+        let optional = if field.optional { "?" } else { "" };
+        lines.push(format!(
+            "{}{}{}: {};",
+            indent,
+            field.name,
+            optional,
+            field.ty.typescript()
+        ));
+    }
+    lines.join("\n")
+}
+
+fn typescript(definitions: &BTreeMap<String, &Definition>) -> String {
+    let mut output =
+        String::from("// Generated by server/build.rs from the signaling messages. Do not edit.\n");
+    for (name, definition) in definitions {
+        output.push('\n');
+        if let Some(description) = &definition.description {
+            output.push_str(&format!("/** {} */\n", description));
+        }
+        match &definition.shape {
+            Shape::Struct(fields) => {
+                output.push_str(&format!(
+                    "export type {} = {{\n{}\n}};\n",
+                    name,
+                    typescript_fields(fields, None, "  ")
+                ));
+            }
+            Shape::Strings(names) => {
+                output.push_str(&format!("export type {} =\n", name));
+                let names: Vec<String> = names.iter().map(|n| format!("  | \"{}\"", n)).collect();
+                output.push_str(&names.join("\n"));
+                output.push_str(";\n");
+            }
+            Shape::Tagged { tag, variants } => {
+                output.push_str(&format!("export type {} =\n", name));
+                let variants: Vec<String> = variants
+                    .iter()
+                    .map(|variant| {
+                        let comment = match &variant.description {
+                            Some(description) => format!("  /** {} */\n", description),
+                            None => String::new(),
+                        };
+                        if variant.fields.is_empty() {
+                            format!("{}  | {{ {}: \"{}\" }}", comment, tag, variant.name)
+                        } else {
+                            format!(
+                                "{}  | {{\n{}\n    }}",
+                                comment,
+                                typescript_fields(
+                                    &variant.fields,
+                                    Some((tag, &variant.name)),
+                                    "      "
+                                )
+                            )
+                        }
+                    })
+                    .collect();
+                output.push_str(&variants.join("\n"));
+                output.push_str(";\n");
+            }
+        }
+    }
+    output
+}
@@ -0,0 +1,71 @@
+// Spans of HTTP requests, like the default of tracing-actix-web. Participants give room passwords and moderator
+// tokens in query parameters of /socket, so their values are redacted from the target.
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    Error, HttpMessage,
+};
+use tracing::{field::Empty, Span};
+use tracing_actix_web::{DefaultRootSpanBuilder, RequestId, RootSpanBuilder};
+
+const SECRET_PARAMETERS: [&str; 2] = ["password", "moderator"];
+
+pub struct RedactedSpan;
+
+impl RootSpanBuilder for RedactedSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let route = request
+            .match_pattern()
+            .unwrap_or_else(|| "default".to_owned());
+        let target = request
+            .uri()
+            .path_and_query()
+            .map(|p| redact(p.as_str()))
+            .unwrap_or_default();
+        let request_id = request.extensions().get::<RequestId>().copied();
+        tracing::info_span!(
+            "HTTP request",
+            http.method = %request.method(),
+            http.route = %route,
+            http.client_ip = %request.connection_info().realip_remote_addr().unwrap_or(""),
+            http.target = %target,
+            http.status_code = Empty,
+            otel.status_code = Empty,
+            request_id = ?request_id,
+            exception.message = Empty,
+            exception.details = Empty,
+        )
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+fn redact(target: &str) -> String {
+    let Some((path, query)) = target.split_once('?') else {
+        return target.to_owned();
+    };
+    let parameters: Vec<String> = query
+        .split('&')
+        .map(|parameter| match parameter.split_once('=') {
+            Some((name, _)) if SECRET_PARAMETERS.contains(&name) => format!("{}=REDACTED", name),
+            _ => parameter.to_owned(),
+        })
+        .collect();
+    format!("{}?{}", path, parameters.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted() {
+        assert_eq!(
+            redact("/socket?room=a&password=secret&moderator=token&lobby=true"),
+            "/socket?room=a&password=REDACTED&moderator=REDACTED&lobby=true"
+        );
+        assert_eq!(redact("/rooms/a"), "/rooms/a");
+    }
+}
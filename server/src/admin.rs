@@ -0,0 +1,57 @@
+// Control endpoints for operators: room settings, recordings, mixers and stats. They are served on the signaling
+// port, so they require ADMIN_TOKEN as a bearer token, and they are disabled when it is not given.
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    HttpResponse,
+};
+
+use crate::config;
+
+pub async fn authorize<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let response = match (config::admin_token(), token) {
+        (None, _) => HttpResponse::Forbidden().body("ADMIN_TOKEN is not configured"),
+        (Some(expected), Some(token)) if matches(&expected, token) => {
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body);
+        }
+        (Some(_), _) => HttpResponse::Unauthorized().finish(),
+    };
+    tracing::warn!("Unauthorized request to {}", req.path());
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+// Compares in constant time, so the token is not guessed from response times.
+fn matches(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_matched_exactly() {
+        assert!(matches("secret", "secret"));
+        assert!(!matches("secret", "secreT"));
+        assert!(!matches("secret", "secret2"));
+        assert!(!matches("secret", ""));
+    }
+}
@@ -0,0 +1,185 @@
+// Conversions between Opus, which is used in rooms, and G.711, which is used by most phones.
+use audiopus::{
+    coder::{Decoder, Encoder},
+    packet::Packet,
+    Application, Channels, MutSignals, SampleRate,
+};
+
+pub const SAMPLE_RATE: u32 = 48000;
+// The longest Opus frame is 120ms.
+const MAX_FRAME_SAMPLES: usize = 5760;
+// Recommended by RFC 6716 as the maximum packet size.
+const MAX_PACKET_SIZE: usize = 1275;
+
+pub struct OpusDecoder(Decoder);
+
+impl OpusDecoder {
+    pub fn new() -> Result<Self, audiopus::Error> {
+        Ok(Self(Decoder::new(SampleRate::Hz48000, Channels::Mono)?))
+    }
+
+    /// Decodes a packet into 48kHz mono samples.
+    pub fn decode(&mut self, payload: &[u8]) -> Result<Vec<i16>, audiopus::Error> {
+        let mut samples = vec![0i16; MAX_FRAME_SAMPLES];
+        let packet = Packet::try_from(payload)?;
+        let signals = MutSignals::try_from(&mut samples)?;
+        let decoded = self.0.decode(Some(packet), signals, false)?;
+        samples.truncate(decoded);
+        Ok(samples)
+    }
+}
+
+pub struct OpusEncoder(Encoder);
+
+impl OpusEncoder {
+    pub fn new() -> Result<Self, audiopus::Error> {
+        Ok(Self(Encoder::new(
+            SampleRate::Hz48000,
+            Channels::Mono,
+            Application::Voip,
+        )?))
+    }
+
+    /// Encodes 48kHz mono samples. The length has to be a valid Opus frame size, like 960 for 20ms.
+    pub fn encode(&mut self, samples: &[i16]) -> Result<Vec<u8>, audiopus::Error> {
+        let mut payload = vec![0u8; MAX_PACKET_SIZE];
+        let encoded = self.0.encode(samples, &mut payload)?;
+        payload.truncate(encoded);
+        Ok(payload)
+    }
+}
+
+/// Reduces the sample rate by averaging every `factor` samples.
+pub fn downsample(samples: &[i16], factor: usize) -> Vec<i16> {
+    samples
+        .chunks(factor)
+        .map(|chunk| (chunk.iter().map(|s| *s as i32).sum::<i32>() / chunk.len() as i32) as i16)
+        .collect()
+}
+
+/// Increases the sample rate by interpolating between samples.
+pub fn upsample(samples: &[i16], factor: usize) -> Vec<i16> {
+    let mut upsampled = Vec::with_capacity(samples.len() * factor);
+    for (i, sample) in samples.iter().enumerate() {
+        let current = *sample as i32;
+        let next = samples.get(i + 1).map(|s| *s as i32).unwrap_or(current);
+        for step in 0..factor as i32 {
+            upsampled.push((current + (next - current) * step / factor as i32) as i16);
+        }
+    }
+    upsampled
+}
+
+pub fn encode_ulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let mut pcm = sample as i32;
+    let sign = if pcm < 0 {
+        pcm = -pcm;
+        0x80
+    } else {
+        0
+    };
+    pcm = pcm.min(CLIP) + BIAS;
+    let mut exponent = 7;
+    while exponent > 0 && pcm & (0x80 << exponent) == 0 {
+        exponent -= 1;
+    }
+    let mantissa = (pcm >> (exponent + 3)) & 0x0f;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+pub fn encode_alaw(sample: i16) -> u8 {
+    let mut pcm = (sample as i32) >> 3;
+    let mask = if pcm >= 0 {
+        0xd5
+    } else {
+        pcm = -pcm - 1;
+        0x55
+    };
+    let Some(segment) = (0..8).find(|segment| pcm < 0x20 << segment) else {
+        return (0x7f ^ mask) as u8;
+    };
+    let mantissa = if segment < 2 {
+        (pcm >> 1) & 0x0f
+    } else {
+        (pcm >> segment) & 0x0f
+    };
+    ((segment << 4 | mantissa) ^ mask) as u8
+}
+
+pub fn decode_ulaw(value: u8) -> i16 {
+    let value = !value as i32;
+    let exponent = (value >> 4) & 0x07;
+    let mantissa = value & 0x0f;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if value & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
+
+pub fn decode_alaw(value: u8) -> i16 {
+    let value = (value ^ 0x55) as i32;
+    let segment = (value & 0x70) >> 4;
+    let mut magnitude = (value & 0x0f) << 4;
+    magnitude += match segment {
+        0 => 8,
+        _ => 0x108,
+    };
+    if segment > 1 {
+        magnitude <<= segment - 1;
+    }
+    if value & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn g711_is_decoded() {
+        // Values of the ITU-T G.711 tables.
+        assert_eq!(decode_ulaw(0xff), 0);
+        assert_eq!(decode_ulaw(0x7f), 0);
+        assert_eq!(decode_ulaw(0x80), 32124);
+        assert_eq!(decode_ulaw(0x00), -32124);
+        assert_eq!(decode_alaw(0xd5), 8);
+        assert_eq!(decode_alaw(0x55), -8);
+        assert_eq!(decode_alaw(0xaa), 32256);
+        assert_eq!(decode_alaw(0x2a), -32256);
+    }
+
+    #[test]
+    fn g711_keeps_samples_within_a_step() {
+        for sample in [-32000i16, -1000, -100, 0, 100, 1000, 32000] {
+            // Steps are 1/16 of the segment, so the error grows with the magnitude.
+            let tolerance = sample.unsigned_abs() / 16 + 8;
+            let ulaw = decode_ulaw(encode_ulaw(sample));
+            assert!(ulaw.abs_diff(sample) <= tolerance, "{} {}", sample, ulaw);
+            let alaw = decode_alaw(encode_alaw(sample));
+            assert!(alaw.abs_diff(sample) <= tolerance, "{} {}", sample, alaw);
+        }
+    }
+
+    #[test]
+    fn samples_are_resampled_between_8khz_and_48khz() {
+        let factor = (SAMPLE_RATE / 8000) as usize;
+        // 20ms of G.711 is one 20ms frame at 48kHz.
+        assert_eq!(upsample(&[0; 160], factor).len(), 960);
+        assert_eq!(downsample(&[0; 960], factor).len(), 160);
+        assert_eq!(
+            upsample(&[0, 600], factor),
+            vec![0, 100, 200, 300, 400, 500, 600, 600, 600, 600, 600, 600]
+        );
+        assert_eq!(
+            downsample(&[0, 100, 200, 300, 400, 500, 600], factor),
+            vec![250, 600]
+        );
+    }
+}
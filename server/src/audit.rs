@@ -0,0 +1,220 @@
+// Typed events of room activity, for auditing. They are written to the sink which AUDIT_SINK configures, one JSON
+// object per line, in the order they are emitted. Webhooks are fed with the same events.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+use crate::webhook;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum Event {
+    #[serde(rename_all = "camelCase")]
+    RoomCreated { room_id: String },
+    #[serde(rename_all = "camelCase")]
+    RoomClosed { room_id: String },
+    #[serde(rename_all = "camelCase")]
+    ParticipantJoined {
+        room_id: String,
+        participant_id: String,
+        moderator: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    ParticipantLeft {
+        room_id: String,
+        participant_id: String,
+    },
+    // A moderator denied the participant who waited in the lobby.
+    #[serde(rename_all = "camelCase")]
+    ParticipantDenied {
+        room_id: String,
+        participant_id: String,
+        moderator_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    TrackPublished {
+        room_id: String,
+        participant_id: String,
+        publisher_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    TrackUnpublished {
+        room_id: String,
+        participant_id: String,
+        publisher_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Subscribed {
+        room_id: String,
+        participant_id: String,
+        publisher_id: String,
+        subscriber_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unsubscribed {
+        room_id: String,
+        participant_id: String,
+        subscriber_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    RecordingStarted { room_id: String },
+    #[serde(rename_all = "camelCase")]
+    RecordingStopped { room_id: String },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Record {
+    // Unix time in milliseconds.
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Record {
+    fn new(event: Event) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        Self { time, event }
+    }
+}
+
+/// Destination of audit records. Sinks are written on the runtime which starts them, because HTTP clients are not
+/// `Send`.
+#[async_trait(?Send)]
+pub trait Sink {
+    async fn write(&mut self, record: &Record) -> std::io::Result<()>;
+}
+
+/// Writes JSON lines to the standard output.
+pub struct Stdout;
+
+#[async_trait(?Send)]
+impl Sink for Stdout {
+    async fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        tokio::io::stdout().write_all(&line).await
+    }
+}
+
+/// Appends JSON lines to a file.
+pub struct File(tokio::fs::File);
+
+impl File {
+    pub async fn open(path: &str) -> std::io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self(file))
+    }
+}
+
+#[async_trait(?Send)]
+impl Sink for File {
+    async fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.0.write_all(&line).await
+    }
+}
+
+/// Posts each record as JSON to an HTTP endpoint.
+pub struct Webhook(webhook::Endpoint);
+
+#[async_trait(?Send)]
+impl Sink for Webhook {
+    async fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        let body = serde_json::to_vec(record)?;
+        match self.0.post(&[], &body).await? {
+            200..=299 => Ok(()),
+            status => Err(std::io::Error::other(format!(
+                "webhook is rejected with {}",
+                status
+            ))),
+        }
+    }
+}
+
+/// "stdout", an http:// or https:// URL of a webhook, or a path of a JSON-lines file.
+pub async fn open(sink: &str) -> std::io::Result<Box<dyn Sink>> {
+    if sink == "stdout" {
+        return Ok(Box::new(Stdout));
+    }
+    if sink.starts_with("http://") || sink.starts_with("https://") {
+        return webhook::Endpoint::parse(sink)
+            .map(|endpoint| Box::new(Webhook(endpoint)) as Box<dyn Sink>)
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid webhook URL")
+            });
+    }
+    Ok(Box::new(File::open(sink).await?))
+}
+
+/// Handle to emit events. Each sink is written by its own task, so emitting does not wait for sinks, and a slow
+/// sink does not delay the others.
+#[derive(Clone, Default)]
+pub struct Log {
+    // Empty when auditing is disabled.
+    senders: Vec<mpsc::UnboundedSender<Record>>,
+}
+
+impl Log {
+    pub fn start(sinks: Vec<Box<dyn Sink>>) -> Self {
+        let senders = sinks
+            .into_iter()
+            .map(|mut sink| {
+                let (sender, mut receiver) = mpsc::unbounded_channel::<Record>();
+                actix::spawn(async move {
+                    while let Some(record) = receiver.recv().await {
+                        if let Err(err) = sink.write(&record).await {
+                            tracing::error!(
+                                "Failed to write audit event {:?}: {}",
+                                record.event,
+                                err
+                            );
+                        }
+                    }
+                });
+                sender
+            })
+            .collect();
+        Self { senders }
+    }
+
+    pub fn emit(&self, event: Event) {
+        if self.senders.is_empty() {
+            return;
+        }
+        let record = Record::new(event);
+        for sender in &self.senders {
+            let _ = sender.send(record.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_flat_json() {
+        let record = Record {
+            time: 1,
+            event: Event::TrackPublished {
+                room_id: "room".to_owned(),
+                participant_id: "alice".to_owned(),
+                publisher_id: "track".to_owned(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"time":1,"event":"trackPublished","roomId":"room","participantId":"alice","publisherId":"track"}"#
+        );
+    }
+}
@@ -0,0 +1,67 @@
+// Chat messages between participants, which are sent over signaling.
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+// Longer messages are rejected, because every message is kept in the history and sent to late joiners.
+pub const MAX_TEXT_LENGTH: usize = 4096;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatMessage {
+    pub id: String,
+    pub from: String,
+    // Only the participant receives the message. Direct messages are not kept in the history.
+    pub to: Option<String>,
+    pub text: String,
+    // Unix time in milliseconds.
+    pub sent_at: u64,
+}
+
+impl ChatMessage {
+    pub fn new(from: String, to: Option<String>, text: String) -> Self {
+        let sent_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            from,
+            to,
+            text,
+            sent_at,
+        }
+    }
+}
+
+/// Latest messages of a room, for participants who join later.
+pub struct History {
+    messages: VecDeque<ChatMessage>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, message: ChatMessage) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    pub fn messages(&self) -> Vec<ChatMessage> {
+        self.messages.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use super::{Error, Registry};
+
+/// Registry in the process, for a single node and for development.
+#[derive(Default)]
+pub struct MemoryRegistry {
+    homes: Mutex<HashMap<String, (String, Instant)>>,
+    relay_addresses: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+#[async_trait]
+impl Registry for MemoryRegistry {
+    async fn claim(&self, room_id: &str, node: &str, ttl: Duration) -> Result<String, Error> {
+        let mut homes = self.homes.lock().unwrap();
+        let now = Instant::now();
+        match homes.get(room_id) {
+            Some((home, expires)) if *expires > now => Ok(home.clone()),
+            _ => {
+                homes.insert(room_id.to_owned(), (node.to_owned(), now + ttl));
+                Ok(node.to_owned())
+            }
+        }
+    }
+
+    async fn refresh(&self, room_id: &str, node: &str, ttl: Duration) -> Result<bool, Error> {
+        let mut homes = self.homes.lock().unwrap();
+        match homes.get_mut(room_id) {
+            Some((home, expires)) if home == node => {
+                *expires = Instant::now() + ttl;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn release(&self, room_id: &str, node: &str) -> Result<(), Error> {
+        let mut homes = self.homes.lock().unwrap();
+        if homes.get(room_id).is_some_and(|(home, _)| home == node) {
+            homes.remove(room_id);
+        }
+        Ok(())
+    }
+
+    async fn register(&self, node: &str, relay_address: &str, ttl: Duration) -> Result<(), Error> {
+        self.relay_addresses.lock().unwrap().insert(
+            node.to_owned(),
+            (relay_address.to_owned(), Instant::now() + ttl),
+        );
+        Ok(())
+    }
+
+    async fn relay_address(&self, node: &str) -> Result<Option<String>, Error> {
+        let relay_addresses = self.relay_addresses.lock().unwrap();
+        Ok(relay_addresses
+            .get(node)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(relay_address, _)| relay_address.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(30);
+
+    #[actix_web::test]
+    async fn first_node_is_home_until_released() {
+        let registry = MemoryRegistry::default();
+        assert_eq!(registry.claim("room", "a", TTL).await.unwrap(), "a");
+        assert_eq!(registry.claim("room", "b", TTL).await.unwrap(), "a");
+        assert!(!registry.refresh("room", "b", TTL).await.unwrap());
+        assert!(registry.refresh("room", "a", TTL).await.unwrap());
+
+        registry.release("room", "b").await.unwrap();
+        assert_eq!(registry.claim("room", "b", TTL).await.unwrap(), "a");
+        registry.release("room", "a").await.unwrap();
+        assert_eq!(registry.claim("room", "b", TTL).await.unwrap(), "b");
+    }
+
+    #[actix_web::test]
+    async fn expired_claim_is_taken() {
+        let registry = MemoryRegistry::default();
+        registry.claim("room", "a", Duration::ZERO).await.unwrap();
+        assert_eq!(registry.claim("room", "b", TTL).await.unwrap(), "b");
+    }
+    #[actix_web::test]
+    async fn registered_nodes_expire() {
+        let registry = MemoryRegistry::default();
+        assert_eq!(registry.relay_address("a").await.unwrap(), None);
+        registry.register("a", "10.0.0.1:9443", TTL).await.unwrap();
+        assert_eq!(
+            registry.relay_address("a").await.unwrap().as_deref(),
+            Some("10.0.0.1:9443")
+        );
+        registry
+            .register("a", "10.0.0.1:9443", Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(registry.relay_address("a").await.unwrap(), None);
+    }
+}
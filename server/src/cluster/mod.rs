@@ -0,0 +1,151 @@
+// Cluster mode. Every room has a home node which is recorded in a registry shared by the nodes, and clients
+// which connect to another node are routed to the home node. Media goes to the home node directly, because
+// ICE candidates of a room are addresses of its node. With relay routing, the room spans the nodes instead.
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+
+pub mod memory;
+pub mod redis;
+pub mod relay;
+pub mod route;
+
+// Claims of a node expire when it stops refreshing them, so another node can take its rooms.
+const CLAIM_TTL: Duration = Duration::from_secs(30);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Redis(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Redis(message) => write!(f, "redis: {}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Home nodes of rooms. Nodes are identified by their URLs.
+#[async_trait]
+pub trait Registry: Send + Sync {
+    /// Records the node as the home of the room unless another node has it. Returns the home node.
+    async fn claim(&self, room_id: &str, node: &str, ttl: Duration) -> Result<String, Error>;
+    /// Extends the claim of the node. Returns false if the node is not the home anymore.
+    async fn refresh(&self, room_id: &str, node: &str, ttl: Duration) -> Result<bool, Error>;
+    async fn release(&self, room_id: &str, node: &str) -> Result<(), Error>;
+    /// Records the address which the node receives relayed media on, until the TTL passes.
+    async fn register(&self, node: &str, relay_address: &str, ttl: Duration) -> Result<(), Error>;
+    /// Relay address of a registered node.
+    async fn relay_address(&self, node: &str) -> Result<Option<String>, Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    // Signaling is relayed to the home node, so clients do not know about the cluster.
+    Proxy,
+    // Clients are told to connect to the home node.
+    Redirect,
+    // Clients join the room on any node, and publishers are relayed between the nodes of the room.
+    Relay,
+}
+
+pub enum Home {
+    Local,
+    Remote(String),
+}
+
+pub struct Cluster {
+    pub node_url: String,
+    pub routing: Routing,
+    registry: Box<dyn Registry>,
+    // Rooms whose home is this node.
+    rooms: std::sync::Mutex<HashSet<String>>,
+}
+
+impl Cluster {
+    pub fn start(node_url: String, routing: Routing, registry: Box<dyn Registry>) -> Arc<Self> {
+        let cluster = Arc::new(Self {
+            node_url,
+            routing,
+            registry,
+            rooms: std::sync::Mutex::new(HashSet::new()),
+        });
+        tokio::spawn(refresh(Arc::downgrade(&cluster)));
+        cluster
+    }
+
+    pub async fn home(&self, room_id: &str) -> Result<Home, Error> {
+        let node = self
+            .registry
+            .claim(room_id, &self.node_url, CLAIM_TTL)
+            .await?;
+        if node != self.node_url {
+            return Ok(Home::Remote(node));
+        }
+        self.rooms.lock().unwrap().insert(room_id.to_owned());
+        Ok(Home::Local)
+    }
+
+    // This is called when a room of this node is closed.
+    pub async fn release(&self, room_id: &str) {
+        self.rooms.lock().unwrap().remove(room_id);
+        if let Err(err) = self.registry.release(room_id, &self.node_url).await {
+            tracing::error!("Failed to release room {}: {}", room_id, err);
+        }
+    }
+}
+
+async fn refresh(cluster: Weak<Cluster>) {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(cluster) = cluster.upgrade() else {
+            return;
+        };
+        if cluster.routing == Routing::Relay {
+            relay::register(&cluster).await;
+        }
+        let rooms: Vec<String> = cluster.rooms.lock().unwrap().iter().cloned().collect();
+        for room_id in rooms {
+            match cluster
+                .registry
+                .refresh(&room_id, &cluster.node_url, CLAIM_TTL)
+                .await
+            {
+                Ok(true) => {}
+                // The room keeps working for the participants on this node, but new participants go to the other node.
+                Ok(false) => tracing::warn!("Room {} is claimed by another node", room_id),
+                Err(err) => tracing::error!("Failed to refresh room {}: {}", room_id, err),
+            }
+        }
+    }
+}
+
+// Host and port of a node URL. Only plain ws:// is supported between nodes.
+fn authority(node_url: &str) -> std::io::Result<&str> {
+    node_url
+        .strip_prefix("ws://")
+        .or_else(|| node_url.strip_prefix("http://"))
+        .map(|authority| authority.trim_end_matches('/'))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "node URL must be ws:// or http://",
+            )
+        })
+}
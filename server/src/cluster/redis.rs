@@ -0,0 +1,288 @@
+// Registry on Redis or a compatible server like Valkey, with a minimal RESP client.
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+use super::{Error, Registry};
+
+const KEY_PREFIX: &str = "livecamera:room:";
+const NODE_KEY_PREFIX: &str = "livecamera:node:";
+// Claims are only changed by their node, so refreshing and releasing compare the value atomically.
+const REFRESH_SCRIPT: &str =
+    "if redis.call('GET', KEYS[1]) == ARGV[1] then return redis.call('PEXPIRE', KEYS[1], ARGV[2]) else return 0 end";
+const RELEASE_SCRIPT: &str =
+    "if redis.call('GET', KEYS[1]) == ARGV[1] then return redis.call('DEL', KEYS[1]) else return 0 end";
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+/// Address of the server from `redis://[:password@]host[:port][/db]`.
+#[derive(Debug, PartialEq)]
+struct Address {
+    host: String,
+    port: u16,
+    password: Option<String>,
+    db: Option<u32>,
+}
+
+impl Address {
+    fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("redis://")?;
+        let (authority, db) = match rest.split_once('/') {
+            Some((authority, "")) => (authority, None),
+            Some((authority, db)) => (authority, Some(db.parse().ok()?)),
+            None => (rest, None),
+        };
+        let (password, host) = match authority.rsplit_once('@') {
+            Some((user_info, host)) => {
+                // Only the password is used, as the default user.
+                let password = user_info.rsplit(':').next().unwrap_or(user_info);
+                (Some(password.to_owned()), host)
+            }
+            None => (None, authority),
+        };
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (host, 6379),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            host: host.to_owned(),
+            port,
+            password,
+            db,
+        })
+    }
+}
+
+pub struct RedisRegistry {
+    address: Address,
+    // Connected on the first command, and again after an error.
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl RedisRegistry {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let address =
+            Address::parse(url).ok_or_else(|| Error::Redis(format!("invalid URL {}", url)))?;
+        Ok(Self {
+            address,
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>, Error> {
+        let stream = TcpStream::connect((self.address.host.as_str(), self.address.port)).await?;
+        let mut connection = BufReader::new(stream);
+        if let Some(password) = &self.address.password {
+            command(&mut connection, &["AUTH", password]).await?;
+        }
+        if let Some(db) = self.address.db {
+            command(&mut connection, &["SELECT", &db.to_string()]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn command(&self, args: &[&str]) -> Result<Reply, Error> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+        let result = command(connection.as_mut().unwrap(), args).await;
+        // The connection may be in the middle of a reply.
+        if matches!(result, Err(Error::Io(_))) {
+            *connection = None;
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Registry for RedisRegistry {
+    async fn claim(&self, room_id: &str, node: &str, ttl: Duration) -> Result<String, Error> {
+        let key = format!("{}{}", KEY_PREFIX, room_id);
+        let ttl = ttl.as_millis().to_string();
+        // The claim may expire between SET and GET.
+        for _ in 0..2 {
+            let reply = self.command(&["SET", &key, node, "NX", "PX", &ttl]).await?;
+            if reply == Reply::Status("OK".to_owned()) {
+                return Ok(node.to_owned());
+            }
+            if let Reply::Bulk(Some(home)) = self.command(&["GET", &key]).await? {
+                return String::from_utf8(home)
+                    .map_err(|_| Error::Redis(format!("home of {} is not UTF-8", room_id)));
+            }
+        }
+        Err(Error::Redis(format!("failed to claim {}", room_id)))
+    }
+
+    async fn refresh(&self, room_id: &str, node: &str, ttl: Duration) -> Result<bool, Error> {
+        let key = format!("{}{}", KEY_PREFIX, room_id);
+        let ttl = ttl.as_millis().to_string();
+        let reply = self
+            .command(&["EVAL", REFRESH_SCRIPT, "1", &key, node, &ttl])
+            .await?;
+        Ok(reply == Reply::Integer(1))
+    }
+
+    async fn release(&self, room_id: &str, node: &str) -> Result<(), Error> {
+        let key = format!("{}{}", KEY_PREFIX, room_id);
+        self.command(&["EVAL", RELEASE_SCRIPT, "1", &key, node])
+            .await?;
+        Ok(())
+    }
+
+    async fn register(&self, node: &str, relay_address: &str, ttl: Duration) -> Result<(), Error> {
+        let key = format!("{}{}", NODE_KEY_PREFIX, node);
+        let ttl = ttl.as_millis().to_string();
+        self.command(&["SET", &key, relay_address, "PX", &ttl])
+            .await?;
+        Ok(())
+    }
+
+    async fn relay_address(&self, node: &str) -> Result<Option<String>, Error> {
+        let key = format!("{}{}", NODE_KEY_PREFIX, node);
+        match self.command(&["GET", &key]).await? {
+            Reply::Bulk(Some(relay_address)) => String::from_utf8(relay_address)
+                .map(Some)
+                .map_err(|_| Error::Redis(format!("relay address of {} is not UTF-8", node))),
+            _ => Ok(None),
+        }
+    }
+}
+
+async fn command(connection: &mut BufReader<TcpStream>, args: &[&str]) -> Result<Reply, Error> {
+    connection.get_mut().write_all(&encode(args)).await?;
+    read_reply(connection).await
+}
+
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+async fn read_reply<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<Reply, Error> {
+    let line = read_line(reader).await?;
+    let (kind, rest) = line.split_at(1);
+    let len = || {
+        rest.parse::<i64>()
+            .map_err(|_| Error::Redis(format!("invalid reply {}", line)))
+    };
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_owned())),
+        "-" => Err(Error::Redis(rest.to_owned())),
+        ":" => Ok(Reply::Integer(len()?)),
+        "$" => {
+            let len = len()?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut buf = vec![0; len as usize + 2];
+            reader.read_exact(&mut buf).await?;
+            buf.truncate(len as usize);
+            Ok(Reply::Bulk(Some(buf)))
+        }
+        "*" => {
+            let len = len()?;
+            if len < 0 {
+                return Ok(Reply::Array(None));
+            }
+            let mut replies = Vec::new();
+            for _ in 0..len {
+                replies.push(Box::pin(read_reply(reader)).await?);
+            }
+            Ok(Reply::Array(Some(replies)))
+        }
+        _ => Err(Error::Redis(format!("invalid reply {}", line))),
+    }
+}
+
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<String, Error> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    let line = line.trim_end_matches("\r\n");
+    if line.is_empty() {
+        return Err(Error::Redis("empty reply".to_owned()));
+    }
+    Ok(line.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_parsed() {
+        assert_eq!(
+            Address::parse("redis://localhost"),
+            Some(Address {
+                host: "localhost".to_owned(),
+                port: 6379,
+                password: None,
+                db: None,
+            })
+        );
+        assert_eq!(
+            Address::parse("redis://:secret@10.0.0.1:6380/2"),
+            Some(Address {
+                host: "10.0.0.1".to_owned(),
+                port: 6380,
+                password: Some("secret".to_owned()),
+                db: Some(2),
+            })
+        );
+        assert_eq!(Address::parse("http://localhost"), None);
+        assert_eq!(Address::parse("redis://localhost:port"), None);
+    }
+
+    #[test]
+    fn commands_are_encoded() {
+        assert_eq!(
+            encode(&["GET", "livecamera:room:a"]),
+            b"*2\r\n$3\r\nGET\r\n$17\r\nlivecamera:room:a\r\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn replies_are_read() {
+        let mut input: &[u8] =
+            b"+OK\r\n:1\r\n$-1\r\n$4\r\nnode\r\n*2\r\n:1\r\n$0\r\n\r\n-ERR wrong\r\n";
+        assert_eq!(
+            read_reply(&mut input).await.unwrap(),
+            Reply::Status("OK".to_owned())
+        );
+        assert_eq!(read_reply(&mut input).await.unwrap(), Reply::Integer(1));
+        assert_eq!(read_reply(&mut input).await.unwrap(), Reply::Bulk(None));
+        assert_eq!(
+            read_reply(&mut input).await.unwrap(),
+            Reply::Bulk(Some(b"node".to_vec()))
+        );
+        assert_eq!(
+            read_reply(&mut input).await.unwrap(),
+            Reply::Array(Some(vec![Reply::Integer(1), Reply::Bulk(Some(Vec::new()))]))
+        );
+        assert!(matches!(
+            read_reply(&mut input).await,
+            Err(Error::Redis(message)) if message == "ERR wrong"
+        ));
+    }
+}
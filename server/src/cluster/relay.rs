@@ -0,0 +1,446 @@
+// Relay routing. Every node of a room has its own router, and relays its publishers to the routers of the
+// other nodes, so subscribers connect to their own node. The home node knows all nodes of the room, and tells
+// them about a node which joins. Messages are signed with CLUSTER_SECRET like webhooks, and media is only relayed
+// to the addresses which nodes register in the registry.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use rheomesh::publisher::Publisher;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::Cluster;
+use crate::{config, room, webhook, websocket::SendingMessage};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Signed messages are accepted within this time, so captured messages are not replayed later.
+const MAX_MESSAGE_AGE: Duration = Duration::from_secs(60);
+
+/// A node of a room, and the router which receives relayed media on it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Member {
+    pub node_url: String,
+    pub relay_ip: String,
+    pub relay_port: u16,
+    pub router_id: String,
+}
+
+impl Member {
+    async fn local(cluster: &Cluster, room: &room::Room) -> Self {
+        Self {
+            node_url: cluster.node_url.clone(),
+            relay_ip: config::relay_ip().expect("relay IP is checked when the cluster starts"),
+            relay_port: config::worker_config().relay_server_tcp_port,
+            router_id: room.router.lock().await.id.clone(),
+        }
+    }
+
+    fn relay_address(&self) -> String {
+        relay_address(&self.relay_ip, self.relay_port)
+    }
+}
+
+fn relay_address(relay_ip: &str, relay_port: u16) -> String {
+    format!("{}:{}", relay_ip, relay_port)
+}
+
+/// Registers the relay address of this node, so other nodes accept it as a member of their rooms.
+pub async fn register(cluster: &Cluster) {
+    let relay_address = relay_address(
+        &config::relay_ip().expect("relay IP is checked when the cluster starts"),
+        config::worker_config().relay_server_tcp_port,
+    );
+    if let Err(err) = cluster
+        .registry
+        .register(&cluster.node_url, &relay_address, super::CLAIM_TTL)
+        .await
+    {
+        tracing::error!("Failed to register {}: {}", cluster.node_url, err);
+    }
+}
+
+// Only registered nodes join rooms, so media is not relayed to addresses which a request chose.
+async fn is_registered(cluster: &Cluster, member: &Member) -> bool {
+    match cluster.registry.relay_address(&member.node_url).await {
+        Ok(relay_address) => relay_address == Some(member.relay_address()),
+        Err(err) => {
+            tracing::error!("Failed to look up {}: {}", member.node_url, err);
+            false
+        }
+    }
+}
+
+/// Messages between nodes, which are posted to `/cluster`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "action")]
+pub enum NodeMessage {
+    // The reply is the other nodes of the room.
+    #[serde(rename_all = "camelCase")]
+    Join { room_id: String, node: Member },
+    #[serde(rename_all = "camelCase")]
+    Leave { room_id: String, node_url: String },
+    #[serde(rename_all = "camelCase")]
+    Published {
+        room_id: String,
+        publisher_ids: Vec<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unpublished {
+        room_id: String,
+        publisher_ids: Vec<String>,
+    },
+}
+
+impl NodeMessage {
+    fn room_id(&self) -> &str {
+        match self {
+            NodeMessage::Join { room_id, .. }
+            | NodeMessage::Leave { room_id, .. }
+            | NodeMessage::Published { room_id, .. }
+            | NodeMessage::Unpublished { room_id, .. } => room_id,
+        }
+    }
+}
+
+/// Other nodes of a room. They are empty unless relay routing is used.
+#[derive(Default)]
+pub struct Links {
+    // The home node, when the room is on another node first.
+    home: std::sync::Mutex<Option<String>>,
+    nodes: Mutex<Vec<Member>>,
+    // Publishers on this node, which are relayed to nodes joining later too.
+    publishers: Mutex<HashMap<String, Arc<Mutex<Publisher>>>>,
+    // Publishers which are relayed from the other nodes.
+    relayed: std::sync::Mutex<HashSet<String>>,
+}
+
+impl Links {
+    // The home keeps the room while other nodes are linked, because it introduces the nodes to each other.
+    pub async fn keeps_room(&self) -> bool {
+        self.home.lock().unwrap().is_none() && !self.nodes.lock().await.is_empty()
+    }
+
+    pub fn relayed_publisher_ids(&self) -> Vec<String> {
+        self.relayed.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub async fn publish(
+        &self,
+        room_id: &str,
+        publisher_id: String,
+        publisher: Arc<Mutex<Publisher>>,
+    ) {
+        let mut publishers = self.publishers.lock().await;
+        publishers.insert(publisher_id.clone(), publisher.clone());
+        let publisher = [(publisher_id, publisher)];
+        for node in self.nodes.lock().await.iter() {
+            relay(room_id, node, &publisher).await;
+        }
+    }
+
+    // Relayed publishers are removed by rheomesh when the publisher is closed.
+    pub async fn unpublish(&self, room_id: &str, publisher_id: &str) {
+        if self.publishers.lock().await.remove(publisher_id).is_none() {
+            return;
+        }
+        let message = NodeMessage::Unpublished {
+            room_id: room_id.to_owned(),
+            publisher_ids: vec![publisher_id.to_owned()],
+        };
+        for node in self.nodes.lock().await.iter() {
+            if let Err(err) = send(&node.node_url, &message).await {
+                tracing::error!("Failed to unpublish on {}: {}", node.node_url, err);
+            }
+        }
+    }
+
+    async fn add_node(&self, room_id: &str, member: Member) {
+        let publishers = self.publishers.lock().await;
+        let mut nodes = self.nodes.lock().await;
+        if nodes.iter().any(|node| node.node_url == member.node_url) {
+            return;
+        }
+        tracing::info!("Room {} is linked to {}", room_id, member.node_url);
+        let publishers: Vec<_> = publishers
+            .iter()
+            .map(|(id, publisher)| (id.clone(), publisher.clone()))
+            .collect();
+        relay(room_id, &member, &publishers).await;
+        nodes.push(member);
+    }
+
+    // This is called when the room on this node is closed.
+    pub async fn leave(&self, room_id: &str, node_url: &str) {
+        let nodes = std::mem::take(&mut *self.nodes.lock().await);
+        let message = NodeMessage::Leave {
+            room_id: room_id.to_owned(),
+            node_url: node_url.to_owned(),
+        };
+        for node in nodes {
+            if let Err(err) = send(&node.node_url, &message).await {
+                tracing::error!(
+                    "Failed to leave room {} on {}: {}",
+                    room_id,
+                    node.node_url,
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Links the room on this node to the room on its home node.
+pub fn join(cluster: Arc<Cluster>, room: Arc<room::Room>, home: String) {
+    *room.links.home.lock().unwrap() = Some(home.clone());
+    actix::spawn(async move {
+        let message = NodeMessage::Join {
+            room_id: room.id.clone(),
+            node: Member::local(&cluster, &room).await,
+        };
+        let members = send(&home, &message).await.and_then(|body| {
+            serde_json::from_slice::<Vec<Member>>(&body).map_err(std::io::Error::from)
+        });
+        match members {
+            Ok(members) => {
+                for member in members {
+                    room.links.add_node(&room.id, member).await;
+                }
+            }
+            Err(err) => tracing::error!("Failed to join room {} on {}: {}", room.id, home, err),
+        }
+    });
+}
+
+// Publishers are announced after they are relayed, so the node can subscribe them.
+async fn relay(room_id: &str, node: &Member, publishers: &[(String, Arc<Mutex<Publisher>>)]) {
+    let mut publisher_ids = Vec::new();
+    for (id, publisher) in publishers {
+        let result = publisher
+            .lock()
+            .await
+            .relay_to(
+                node.relay_ip.clone(),
+                node.relay_port,
+                node.router_id.clone(),
+            )
+            .await;
+        match result {
+            Ok(_) => publisher_ids.push(id.clone()),
+            Err(err) => tracing::error!("Failed to relay {} to {}: {}", id, node.node_url, err),
+        }
+    }
+    if publisher_ids.is_empty() {
+        return;
+    }
+    let message = NodeMessage::Published {
+        room_id: room_id.to_owned(),
+        publisher_ids,
+    };
+    if let Err(err) = send(&node.node_url, &message).await {
+        tracing::error!(
+            "Failed to announce publishers to {}: {}",
+            node.node_url,
+            err
+        );
+    }
+}
+
+// Posts the message to the node, and returns the response body.
+async fn send(node_url: &str, message: &NodeMessage) -> std::io::Result<Vec<u8>> {
+    let authority = super::authority(node_url)?;
+    let body = serde_json::to_vec(message)?;
+    let secret =
+        config::cluster_secret().expect("cluster secret is checked when the cluster starts");
+    let timestamp = webhook::timestamp();
+    let mut response = awc::Client::new()
+        .post(format!("http://{}/cluster", authority))
+        .timeout(REQUEST_TIMEOUT)
+        .content_type("application/json")
+        .insert_header((
+            webhook::SIGNATURE_HEADER,
+            format!("sha256={}", webhook::sign(&secret, timestamp, &body)),
+        ))
+        .insert_header((webhook::TIMESTAMP_HEADER, timestamp.to_string()))
+        .send_body(body)
+        .await
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+    if !response.status().is_success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("request is rejected: {}", response.status()),
+        ));
+    }
+    let body = response
+        .body()
+        .await
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+    Ok(body.to_vec())
+}
+
+// Whether the body is signed with the secret recently.
+fn is_signed(req: &HttpRequest, body: &[u8], secret: &str, now: u64) -> bool {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let signature =
+        header(webhook::SIGNATURE_HEADER).and_then(|value| value.strip_prefix("sha256="));
+    let timestamp = header(webhook::TIMESTAMP_HEADER).and_then(|value| value.parse::<u64>().ok());
+    let (Some(signature), Some(timestamp)) = (signature, timestamp) else {
+        return false;
+    };
+    now.abs_diff(timestamp) <= MAX_MESSAGE_AGE.as_secs()
+        && webhook::verify(secret, timestamp, body, signature)
+}
+
+// Endpoint for other nodes. It is on the public listener, so only signed messages are accepted.
+pub async fn receive(
+    req: HttpRequest,
+    body: web::Bytes,
+    room_owner: web::Data<Mutex<room::RoomOwner>>,
+) -> impl Responder {
+    // Messages are rejected when CLUSTER_SECRET is not given.
+    let signed = config::cluster_secret()
+        .is_some_and(|secret| is_signed(&req, &body, &secret, webhook::timestamp()));
+    if !signed {
+        tracing::warn!(
+            "Rejected an unsigned cluster message from {:?}",
+            req.peer_addr()
+        );
+        return HttpResponse::Unauthorized().finish();
+    }
+    let message: NodeMessage = match serde_json::from_slice(&body) {
+        Ok(message) => message,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let (room, cluster) = {
+        let owner = room_owner.lock().await;
+        (
+            owner.find_by_id(message.room_id().to_owned()),
+            owner.cluster.clone(),
+        )
+    };
+    let (Some(room), Some(cluster)) = (room, cluster) else {
+        return HttpResponse::NotFound().finish();
+    };
+    match message {
+        NodeMessage::Join { room_id, node } => {
+            if !is_registered(&cluster, &node).await {
+                tracing::warn!("Rejected {} which is not registered", node.node_url);
+                return HttpResponse::Forbidden().finish();
+            }
+            let mut members = vec![Member::local(&cluster, &room).await];
+            members.extend(room.links.nodes.lock().await.iter().cloned());
+            if room.links.home.lock().unwrap().is_none() {
+                let message = NodeMessage::Join {
+                    room_id,
+                    node: node.clone(),
+                };
+                for member in members.iter().skip(1) {
+                    if let Err(err) = send(&member.node_url, &message).await {
+                        tracing::error!("Failed to introduce {}: {}", node.node_url, err);
+                    }
+                }
+            }
+            room.links.add_node(&room.id, node).await;
+            HttpResponse::Ok().json(members)
+        }
+        NodeMessage::Leave { node_url, .. } => {
+            tracing::info!("Room {} is unlinked from {}", room.id, node_url);
+            room.links
+                .nodes
+                .lock()
+                .await
+                .retain(|node| node.node_url != node_url);
+            if room.is_empty() {
+                room::close(&room_owner, &room).await;
+            }
+            HttpResponse::NoContent().finish()
+        }
+        NodeMessage::Published { publisher_ids, .. } => {
+            room.links
+                .relayed
+                .lock()
+                .unwrap()
+                .extend(publisher_ids.iter().cloned());
+            room.broadcast(SendingMessage::Published { publisher_ids });
+            HttpResponse::NoContent().finish()
+        }
+        NodeMessage::Unpublished { publisher_ids, .. } => {
+            let mut relayed = room.links.relayed.lock().unwrap();
+            publisher_ids.iter().for_each(|id| {
+                relayed.remove(id);
+            });
+            HttpResponse::NoContent().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn node_messages_match_json() {
+        let message = NodeMessage::Join {
+            room_id: "room".to_owned(),
+            node: Member {
+                node_url: "ws://10.0.0.2:4000".to_owned(),
+                relay_ip: "10.0.0.2".to_owned(),
+                relay_port: 9443,
+                router_id: "router".to_owned(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({
+                "action": "Join",
+                "roomId": "room",
+                "node": {
+                    "nodeUrl": "ws://10.0.0.2:4000",
+                    "relayIp": "10.0.0.2",
+                    "relayPort": 9443,
+                    "routerId": "router",
+                },
+            })
+        );
+        let message: NodeMessage = serde_json::from_value(json!({
+            "action": "Published",
+            "roomId": "room",
+            "publisherIds": ["a"],
+        }))
+        .unwrap();
+        assert_eq!(message.room_id(), "room");
+    }
+
+    #[test]
+    fn only_recently_signed_messages_are_accepted() {
+        let body = br#"{"action":"Leave","roomId":"room","nodeUrl":"ws://10.0.0.2:4000"}"#;
+        let request = |timestamp: u64, signature: String| {
+            actix_web::test::TestRequest::default()
+                .insert_header((webhook::SIGNATURE_HEADER, format!("sha256={}", signature)))
+                .insert_header((webhook::TIMESTAMP_HEADER, timestamp.to_string()))
+                .to_http_request()
+        };
+        let now = 1700000000;
+        let signed = request(now, webhook::sign("secret", now, body));
+        assert!(is_signed(&signed, body, "secret", now));
+        assert!(is_signed(&signed, body, "secret", now + 60));
+        assert!(!is_signed(&signed, body, "secret", now + 61));
+        assert!(!is_signed(&signed, body, "other", now));
+        assert!(!is_signed(&signed, b"{}", "secret", now));
+
+        let forged = request(now, webhook::sign("other", now, body));
+        assert!(!is_signed(&forged, body, "secret", now));
+        let unsigned = actix_web::test::TestRequest::default().to_http_request();
+        assert!(!is_signed(&unsigned, body, "secret", now));
+    }
+}
@@ -0,0 +1,217 @@
+// Routes WebSocket clients of a room on another node to its home node.
+use actix::{Actor, ActorContext, AsyncContext, Handler, Message, StreamHandler};
+use actix_codec::Framed;
+use actix_http::ws::{Codec, Frame};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+use crate::{
+    encoding::Encoding,
+    websocket::{self, SendingMessage},
+};
+
+/// A request with this header comes from another node, so it is not routed again.
+pub const PROXIED_HEADER: &str = "x-livecamera-proxied";
+
+/// Starts the WebSocket of a client whose room is on `node_url`.
+pub fn start(
+    routing: super::Routing,
+    node_url: &str,
+    req: &HttpRequest,
+    stream: actix_web::web::Payload,
+    encoding: Encoding,
+    protocol: Option<&'static str>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/socket");
+    match routing {
+        super::Routing::Proxy => {
+            tracing::info!("Proxying WebSocket to {}", node_url);
+            let proxy = Proxy::new(node_url.to_owned(), path.to_owned(), protocol);
+            websocket::start_socket(proxy, req, stream, protocol)
+        }
+        // Rooms are not on another node with relay routing, so this is only for completeness.
+        super::Routing::Redirect | super::Routing::Relay => {
+            tracing::info!("Redirecting WebSocket to {}", node_url);
+            let redirect = Redirect {
+                url: format!("{}{}", node_url.trim_end_matches('/'), path),
+                encoding,
+            };
+            websocket::start_socket(redirect, req, stream, protocol)
+        }
+    }
+}
+
+struct Redirect {
+    url: String,
+    encoding: Encoding,
+}
+
+impl Actor for Redirect {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let message = SendingMessage::Redirect {
+            url: self.url.clone(),
+        };
+        websocket::send(ctx, self.encoding, &message);
+        ctx.close(Some(ws::CloseCode::Normal.into()));
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Redirect {
+    fn handle(&mut self, _msg: Result<ws::Message, ws::ProtocolError>, _ctx: &mut Self::Context) {}
+}
+
+/// Relays frames between a client and the home node.
+struct Proxy {
+    node_url: String,
+    path: String,
+    protocol: Option<&'static str>,
+    // Frames from the client, which are sent after the upstream connection is established.
+    upstream: mpsc::UnboundedSender<ws::Message>,
+    pending: Option<mpsc::UnboundedReceiver<ws::Message>>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+enum Downstream {
+    Frame(Frame),
+    Closed,
+}
+
+impl Proxy {
+    fn new(node_url: String, path: String, protocol: Option<&'static str>) -> Self {
+        let (upstream, pending) = mpsc::unbounded_channel();
+        Self {
+            node_url,
+            path,
+            protocol,
+            upstream,
+            pending: Some(pending),
+        }
+    }
+}
+
+impl Actor for Proxy {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let address = ctx.address();
+        let node_url = self.node_url.clone();
+        let path = self.path.clone();
+        let protocol = self.protocol;
+        let mut pending = self.pending.take().expect("proxy is started twice");
+        actix::spawn(async move {
+            let framed = match connect(&node_url, &path, protocol).await {
+                Ok(framed) => framed,
+                Err(err) => {
+                    tracing::error!("Failed to connect to {}: {}", node_url, err);
+                    address.do_send(Downstream::Closed);
+                    return;
+                }
+            };
+            let (mut sink, mut stream) = framed.split();
+            actix::spawn(async move {
+                while let Some(message) = pending.recv().await {
+                    if sink.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                let _ = sink.close().await;
+            });
+            while let Some(Ok(frame)) = stream.next().await {
+                address.do_send(Downstream::Frame(frame));
+            }
+            address.do_send(Downstream::Closed);
+        });
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Proxy {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Pong(_)) => {}
+            Ok(message) => {
+                let _ = self.upstream.send(message);
+            }
+            Err(err) => {
+                tracing::warn!("Proxied client error: {}", err);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl Handler<Downstream> for Proxy {
+    type Result = ();
+
+    fn handle(&mut self, msg: Downstream, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            Downstream::Frame(Frame::Text(text)) => match String::from_utf8(text.to_vec()) {
+                Ok(text) => ctx.text(text),
+                Err(_) => ctx.stop(),
+            },
+            Downstream::Frame(Frame::Binary(bin)) => ctx.binary(bin),
+            Downstream::Frame(Frame::Ping(msg)) => {
+                let _ = self.upstream.send(ws::Message::Pong(msg));
+            }
+            Downstream::Frame(Frame::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Downstream::Frame(_) => {}
+            Downstream::Closed => ctx.stop(),
+        }
+    }
+}
+
+// Opens a WebSocket to the same path on the home node.
+async fn connect(
+    node_url: &str,
+    path: &str,
+    protocol: Option<&str>,
+) -> std::io::Result<Framed<TcpStream, Codec>> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+    let authority = super::authority(node_url)?;
+    let mut stream = TcpStream::connect(authority).await?;
+
+    let key = base64::engine::general_purpose::STANDARD.encode(uuid::Uuid::new_v4().as_bytes());
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n{}: 1\r\n",
+        path, authority, key, PROXIED_HEADER
+    );
+    if let Some(protocol) = protocol {
+        request.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // The response is read byte by byte, because frames may follow it immediately.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 8192 {
+            return Err(invalid("handshake response is too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    if !response.starts_with(b"HTTP/1.1 101") {
+        let status = String::from_utf8_lossy(&response);
+        let status = status.lines().next().unwrap_or_default();
+        return Err(invalid(&format!("handshake is rejected: {}", status)));
+    }
+    Ok(Framed::new(stream, Codec::new().client_mode()))
+}
@@ -0,0 +1,309 @@
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+use crate::cluster;
+
+// IP addresses of your server which are announced to clients, separated by commas like
+// `203.0.113.1,192.168.1.10,2001:db8::1`. Addresses of the network interfaces are announced when it is not given.
+// They are read once, because every transport announces them.
+pub fn public_ips() -> Vec<IpAddr> {
+    static PUBLIC_IPS: OnceLock<Vec<IpAddr>> = OnceLock::new();
+    PUBLIC_IPS.get_or_init(read_public_ips).clone()
+}
+
+fn read_public_ips() -> Vec<IpAddr> {
+    let ips = match env::var("PUBLIC_IP") {
+        Ok(ips) => ips
+            .split(',')
+            .map(|ip| {
+                ip.trim()
+                    .parse::<IpAddr>()
+                    .expect("failed to parse public IP address")
+            })
+            .collect(),
+        Err(_) => interface_ips(),
+    };
+    if ips.is_empty() {
+        panic!("no IP address to announce, PUBLIC_IP is required");
+    }
+    ips
+}
+
+// Loopback and link-local addresses are not reachable from clients.
+fn interface_ips() -> Vec<IpAddr> {
+    let interfaces = webrtc::util::ifaces::ifaces().expect("failed to list network interfaces");
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for ip in interfaces.iter().filter_map(|i| i.addr).map(|a| a.ip()) {
+        let link_local = match ip {
+            IpAddr::V4(ip) => ip.is_link_local(),
+            IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+        };
+        if !ip.is_loopback() && !ip.is_unspecified() && !link_local && !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    ips
+}
+
+// The IPv4 address of your server, for the SIP gateway, TURN and relays between nodes which only support IPv4.
+// It is None when PUBLIC_IP only has IPv6 addresses.
+pub fn public_ip() -> Option<Ipv4Addr> {
+    public_ips().into_iter().find_map(|ip| match ip {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    })
+}
+
+// Bearer token of the control endpoints, like /rooms and /stats. They are disabled when it is not given.
+pub fn admin_token() -> Option<String> {
+    env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+// Port of the HTTP server.
+pub fn port() -> u16 {
+    env::var("PORT")
+        .map(|port| port.parse::<u16>().expect("failed to parse PORT"))
+        .unwrap_or(4000)
+}
+
+// Ports which rheomesh uses to relay media between nodes. They have to be distinct when nodes share a host.
+pub fn worker_config() -> rheomesh::config::WorkerConfig {
+    let port = |name: &str, default: u16| {
+        env::var(name)
+            .map(|port| {
+                port.parse::<u16>()
+                    .unwrap_or_else(|_| panic!("failed to parse {}", name))
+            })
+            .unwrap_or(default)
+    };
+    rheomesh::config::WorkerConfig {
+        relay_sender_port: port("RELAY_SENDER_PORT", 9441),
+        relay_server_udp_port: port("RELAY_SERVER_UDP_PORT", 9442),
+        relay_server_tcp_port: port("RELAY_SERVER_TCP_PORT", 9443),
+    }
+}
+
+// Address of this node which other nodes relay media to. It is the public IPv4 address unless RELAY_IP is given.
+pub fn relay_ip() -> Option<String> {
+    env::var("RELAY_IP")
+        .ok()
+        .or_else(|| public_ip().map(|ip| ip.to_string()))
+}
+
+// This is used for every transport created in this server, so clients and server-side peers share the same network settings.
+pub fn transport_config() -> rheomesh::config::WebRTCTransportConfig {
+    let mut config = rheomesh::config::WebRTCTransportConfig {
+        announced_ips: public_ips(),
+        ..Default::default()
+    };
+    // Clients are given the embedded TURN server instead, and this server is reached on its public IPs.
+    if turn_port().is_some() {
+        return config_port_range(config);
+    }
+    config.configuration.ice_servers = vec![
+        RTCIceServer {
+            urls: vec!["stun:ice.home.h3poteto.dev:3478".to_owned()],
+            username: "root".to_owned(),
+            credential: "homecluster".to_owned(),
+        },
+        RTCIceServer {
+            urls: vec!["turn:ice.home.h3poteto.dev:3478?transport=udp".to_owned()],
+            username: "root".to_owned(),
+            credential: "homecluster".to_owned(),
+        },
+        RTCIceServer {
+            urls: vec!["turns:ice.home.h3poteto.dev:5349?transport=tcp".to_owned()],
+            username: "root".to_owned(),
+            credential: "homecluster".to_owned(),
+        },
+    ];
+    config_port_range(config)
+}
+
+// Port range of your server. Transports behind the ICE mux bind ephemeral ports of the OS instead, since clients only
+// reach them through the mux. These ports do not have to be opened on firewalls.
+fn config_port_range(
+    mut config: rheomesh::config::WebRTCTransportConfig,
+) -> rheomesh::config::WebRTCTransportConfig {
+    if ice_udp_port().is_none() {
+        config.port_range = Some(rheomesh::config::PortRange {
+            min: 31300,
+            max: 31331,
+        });
+    }
+    config
+}
+
+// All transports share this UDP port for ICE when it is given, instead of the port range.
+pub fn ice_udp_port() -> Option<u16> {
+    env::var("ICE_UDP_PORT")
+        .ok()
+        .map(|port| port.parse::<u16>().expect("failed to parse ICE_UDP_PORT"))
+}
+
+// ICE-TCP is offered on this port, only with ICE_UDP_PORT.
+pub fn ice_tcp_port() -> Option<u16> {
+    env::var("ICE_TCP_PORT")
+        .ok()
+        .map(|port| port.parse::<u16>().expect("failed to parse ICE_TCP_PORT"))
+}
+
+// Embedded TURN/STUN server on this port for both UDP and TCP. It is started only when TURN_PORT is given.
+pub fn turn_port() -> Option<u16> {
+    env::var("TURN_PORT")
+        .ok()
+        .map(|port| port.parse::<u16>().expect("failed to parse TURN_PORT"))
+}
+
+pub fn turn_realm() -> String {
+    env::var("TURN_REALM").unwrap_or_else(|_| "livecamera".to_owned())
+}
+
+// Static long-term credentials of the TURN server, like "alice:secret1,bob:secret2". They are not given to participants.
+pub fn turn_users() -> Vec<(String, String)> {
+    env::var("TURN_USERS")
+        .map(|users| {
+            users
+                .split(',')
+                .filter(|user| !user.is_empty())
+                .map(|user| {
+                    let (name, password) = user
+                        .split_once(':')
+                        .expect("TURN_USERS must be a list of user:password");
+                    (name.to_owned(), password.to_owned())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Shared secret of TURN REST API credentials. Each participant is given a temporary credential when it is given.
+pub fn turn_secret() -> Option<String> {
+    env::var("TURN_SECRET").ok()
+}
+
+// How long TURN REST API credentials are valid.
+pub fn turn_credential_ttl() -> Duration {
+    let secs = env::var("TURN_CREDENTIAL_TTL_SECS")
+        .map(|secs| {
+            secs.parse::<u64>()
+                .expect("failed to parse TURN_CREDENTIAL_TTL_SECS")
+        })
+        .unwrap_or(86400);
+    Duration::from_secs(secs)
+}
+
+// SIP gateway for phone calls. It is started only when SIP_PORT is given.
+pub fn sip_port() -> Option<u16> {
+    env::var("SIP_PORT")
+        .ok()
+        .map(|port| port.parse::<u16>().expect("failed to parse SIP_PORT"))
+}
+
+// How often active speakers are sent to the room. Detection is disabled by default, or with 0.
+pub fn active_speaker_interval() -> Option<Duration> {
+    let millis = env::var("ACTIVE_SPEAKER_INTERVAL_MS")
+        .map(|millis| {
+            millis
+                .parse::<u64>()
+                .expect("failed to parse ACTIVE_SPEAKER_INTERVAL_MS")
+        })
+        .unwrap_or(0);
+    (millis > 0).then(|| Duration::from_millis(millis))
+}
+
+// How often connection quality of each participant is sent to the room. Reports are disabled with 0.
+pub fn connection_quality_interval() -> Option<Duration> {
+    let millis = env::var("CONNECTION_QUALITY_INTERVAL_MS")
+        .map(|millis| {
+            millis
+                .parse::<u64>()
+                .expect("failed to parse CONNECTION_QUALITY_INTERVAL_MS")
+        })
+        .unwrap_or(5000);
+    (millis > 0).then(|| Duration::from_millis(millis))
+}
+
+// How many chat messages of a room are sent to participants who join later.
+pub fn chat_history_size() -> usize {
+    env::var("CHAT_HISTORY_SIZE")
+        .map(|size| {
+            size.parse::<usize>()
+                .expect("failed to parse CHAT_HISTORY_SIZE")
+        })
+        .unwrap_or(100)
+}
+
+// URL of this node which other nodes and clients use, like ws://10.0.0.1:4000. Cluster mode is enabled when it is given.
+pub fn cluster_node_url() -> Option<String> {
+    env::var("CLUSTER_NODE_URL").ok()
+}
+
+// Registry of room homes shared by the nodes: "memory" for a single node, or a redis:// URL.
+pub fn cluster_registry() -> String {
+    env::var("CLUSTER_REGISTRY").unwrap_or_else(|_| "memory".to_owned())
+}
+
+// How clients of a room on another node are routed: "proxy", "redirect" or "relay".
+pub fn cluster_routing() -> cluster::Routing {
+    match env::var("CLUSTER_ROUTING").as_deref() {
+        Ok("proxy") | Err(_) => cluster::Routing::Proxy,
+        Ok("redirect") => cluster::Routing::Redirect,
+        Ok("relay") => cluster::Routing::Relay,
+        Ok(routing) => panic!("unknown CLUSTER_ROUTING {}", routing),
+    }
+}
+
+// Shared secret of the nodes, which signs messages between them. It is required for relay routing.
+pub fn cluster_secret() -> Option<String> {
+    env::var("CLUSTER_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
+// SQLite file which stores persistent rooms and their settings. It is created if it does not exist.
+pub fn database_path() -> String {
+    env::var("DATABASE_PATH").unwrap_or_else(|_| "livecamera.db".to_owned())
+}
+
+// Destination of audit events: "stdout", an http:// or https:// URL of a webhook, or a path of a JSON-lines file. Events are not
+// recorded when it is not given.
+pub fn audit_sink() -> Option<String> {
+    env::var("AUDIT_SINK").ok()
+}
+
+// Endpoints which are notified of room lifecycle, as a comma separated list of http:// or https:// URLs.
+pub fn webhook_urls() -> Vec<String> {
+    env::var("WEBHOOK_URLS")
+        .map(|urls| {
+            urls.split(',')
+                .filter(|url| !url.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Secret of HMAC signatures of webhooks. It is required with WEBHOOK_URLS.
+pub fn webhook_secret() -> Option<String> {
+    env::var("WEBHOOK_SECRET").ok()
+}
+
+// How many times a webhook is sent before it is given up.
+pub fn webhook_max_attempts() -> u32 {
+    env::var("WEBHOOK_MAX_ATTEMPTS")
+        .map(|attempts| {
+            attempts
+                .parse::<u32>()
+                .expect("failed to parse WEBHOOK_MAX_ATTEMPTS")
+        })
+        .unwrap_or(5)
+}
@@ -0,0 +1,343 @@
+// Chooses simulcast and SVC layers for subscribers automatically.
+// This is only a loss-based controller, without bandwidth estimation from REMB or TWCC: rheomesh consumes RTCP of
+// subscribers internally and builds its own interceptors, so those are not available here, and webrtc-rs does not
+// fill available_outgoing_bitrate of its stats. Packet loss from receiver reports, which webrtc-rs collects into
+// stats, is used instead like the loss-based part of GCC.
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use actix::Addr;
+use rheomesh::{
+    subscribe_transport::SubscribeTransport, subscriber::Subscriber, transport::Transport,
+};
+use tokio::sync::Mutex;
+use webrtc::stats::StatsReportType;
+
+use crate::{
+    stats,
+    websocket::{SendingMessage, WebSocket},
+};
+
+const CONTROL_INTERVAL: Duration = Duration::from_secs(2);
+// Thresholds of the loss-based controller of GCC (draft-ietf-rmcat-gcc section 6).
+const DECREASE_LOSS: f64 = 0.1;
+const INCREASE_LOSS: f64 = 0.02;
+// A higher layer is tried after this many intervals without loss, so a short burst does not cause flapping.
+const INCREASE_INTERVALS: u32 = 3;
+// Clients used to default to the highest layers, so it is the ceiling until they set one.
+const DEFAULT_LAYER: Layer = Layer {
+    sid: 2,
+    tid: Some(2),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Layer {
+    sid: u8,
+    tid: Option<u8>,
+}
+
+impl Layer {
+    // Spatial layers are dropped first, and then temporal layers of the lowest spatial layer.
+    fn lower(self) -> Option<Self> {
+        if self.sid > 0 {
+            return Some(Layer {
+                sid: self.sid - 1,
+                tid: self.tid,
+            });
+        }
+        match self.tid {
+            Some(tid) if tid > 0 => Some(Layer {
+                sid: 0,
+                tid: Some(tid - 1),
+            }),
+            _ => None,
+        }
+    }
+
+    fn higher(self, ceiling: Layer) -> Option<Self> {
+        if let (0, Some(tid), Some(max)) = (self.sid, self.tid, ceiling.tid) {
+            if tid < max {
+                return Some(Layer {
+                    sid: 0,
+                    tid: Some(tid + 1),
+                });
+            }
+        }
+        (self.sid < ceiling.sid).then_some(Layer {
+            sid: self.sid + 1,
+            tid: self.tid,
+        })
+    }
+}
+
+/// Layer of a subscriber, which is selected from its packet loss below the ceiling.
+#[derive(Debug)]
+struct Selection {
+    ceiling: Layer,
+    current: Layer,
+    increase_intervals: u32,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self {
+            ceiling: DEFAULT_LAYER,
+            current: DEFAULT_LAYER,
+            increase_intervals: 0,
+        }
+    }
+}
+
+impl Selection {
+    // The client prefers the layer, so it starts from it.
+    fn set_ceiling(&mut self, layer: Layer) {
+        self.ceiling = layer;
+        self.current = layer;
+        self.increase_intervals = 0;
+    }
+
+    // Returns the layer to switch to after an interval with the loss. The current layer is updated by the caller
+    // once the subscriber is switched.
+    fn next(&mut self, loss: f64) -> Option<Layer> {
+        if loss > DECREASE_LOSS {
+            self.increase_intervals = 0;
+            return self.current.lower();
+        }
+        if loss >= INCREASE_LOSS {
+            self.increase_intervals = 0;
+            return None;
+        }
+        self.increase_intervals += 1;
+        if self.increase_intervals < INCREASE_INTERVALS {
+            return None;
+        }
+        self.increase_intervals = 0;
+        self.current.higher(self.ceiling)
+    }
+}
+
+struct State {
+    publisher_id: String,
+    subscriber: Arc<Mutex<Subscriber>>,
+    selection: Selection,
+    packets_lost: i64,
+    packets_received: u64,
+}
+
+/// Congestion controller of a subscribe transport. The layer which a client prefers is the ceiling.
+/// Only subscribers of simulcast or SVC video are added, because other tracks do not have layers to switch.
+pub struct Controller {
+    subscribe_transport: Arc<SubscribeTransport>,
+    states: Mutex<HashMap<String, State>>,
+}
+
+impl Controller {
+    pub fn new(subscribe_transport: Arc<SubscribeTransport>) -> Self {
+        Self {
+            subscribe_transport,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn add(
+        &self,
+        subscriber_id: String,
+        publisher_id: String,
+        subscriber: Arc<Mutex<Subscriber>>,
+    ) {
+        self.states.lock().await.insert(
+            subscriber_id,
+            State {
+                publisher_id,
+                subscriber,
+                selection: Selection::default(),
+                packets_lost: 0,
+                packets_received: 0,
+            },
+        );
+    }
+
+    pub async fn remove(&self, subscriber_id: &str) {
+        self.states.lock().await.remove(subscriber_id);
+    }
+
+    /// Sets the layer which the client prefers, and starts from it.
+    pub async fn set_ceiling(&self, subscriber_id: &str, sid: u8, tid: Option<u8>) {
+        let mut states = self.states.lock().await;
+        let Some(state) = states.get_mut(subscriber_id) else {
+            return;
+        };
+        let layer = Layer { sid, tid };
+        let mut subscriber = state.subscriber.lock().await;
+        if let Err(err) = subscriber.set_preferred_layer(sid, tid).await {
+            tracing::error!("Failed to set preferred layer: {}", err);
+            return;
+        }
+        state.selection.set_ceiling(layer);
+    }
+
+    /// Layers which are forwarded to subscribers now.
+    pub async fn layers(&self) -> stats::Layers {
+        self.states
+            .lock()
+            .await
+            .iter()
+            .map(|(subscriber_id, state)| {
+                let layer = stats::Layer {
+                    sid: state.selection.current.sid,
+                    tid: state.selection.current.tid,
+                };
+                (state.publisher_id.clone(), (subscriber_id.clone(), layer))
+            })
+            .collect()
+    }
+
+    pub async fn run(self: Arc<Self>, address: Addr<WebSocket>) {
+        let mut interval = tokio::time::interval(CONTROL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if self.states.lock().await.is_empty() {
+                continue;
+            }
+            let packets = self.video_packets().await;
+
+            let mut states = self.states.lock().await;
+            for (subscriber_id, state) in states.iter_mut() {
+                let Some((lost, received)) = packets.get(&state.publisher_id) else {
+                    continue;
+                };
+                let lost_delta = (lost - state.packets_lost).max(0) as u64;
+                let received_delta = received.saturating_sub(state.packets_received);
+                state.packets_lost = *lost;
+                state.packets_received = *received;
+                if lost_delta + received_delta == 0 {
+                    continue;
+                }
+
+                let loss = lost_delta as f64 / (lost_delta + received_delta) as f64;
+                let Some(layer) = state.selection.next(loss) else {
+                    continue;
+                };
+
+                let mut subscriber = state.subscriber.lock().await;
+                if let Err(err) = subscriber.set_preferred_layer(layer.sid, layer.tid).await {
+                    tracing::debug!("Failed to switch layer of {}: {}", subscriber_id, err);
+                    continue;
+                }
+                tracing::debug!(
+                    "Switched layer of {} to {:?} with loss {:.3}",
+                    subscriber_id,
+                    layer,
+                    loss
+                );
+                state.selection.current = layer;
+                address.do_send(SendingMessage::LayerChanged {
+                    subscriber_id: subscriber_id.clone(),
+                    sid: layer.sid,
+                    tid: layer.tid,
+                });
+            }
+        }
+    }
+
+    // Cumulative lost and received packets reported by the client, for each video publisher.
+    async fn video_packets(&self) -> HashMap<String, (i64, u64)> {
+        let report = self.subscribe_transport.get_stats().await;
+        report
+            .reports
+            .values()
+            .filter_map(|stats| match stats {
+                StatsReportType::OutboundRTP(outbound) if outbound.kind == "video" => {
+                    report.reports.values().find_map(|stats| match stats {
+                        StatsReportType::RemoteInboundRTP(remote)
+                            if remote.local_id == outbound.id =>
+                        {
+                            Some((
+                                outbound.track_identifier.clone(),
+                                (remote.packets_lost, remote.packets_received),
+                            ))
+                        }
+                        _ => None,
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_spatial_layers_before_temporal_layers() {
+        let layer = |sid, tid| Layer { sid, tid };
+        assert_eq!(layer(2, Some(2)).lower(), Some(layer(1, Some(2))));
+        assert_eq!(layer(0, Some(2)).lower(), Some(layer(0, Some(1))));
+        assert_eq!(layer(0, Some(0)).lower(), None);
+        assert_eq!(layer(0, None).lower(), None);
+
+        let ceiling = layer(1, Some(2));
+        assert_eq!(layer(0, Some(1)).higher(ceiling), Some(layer(0, Some(2))));
+        assert_eq!(layer(0, Some(2)).higher(ceiling), Some(layer(1, Some(2))));
+        assert_eq!(layer(1, Some(2)).higher(ceiling), None);
+        assert_eq!(layer(0, None).higher(layer(2, None)), Some(layer(1, None)));
+    }
+    #[test]
+    fn loss_thresholds_switch_layers() {
+        let layer = |sid, tid| Layer { sid, tid };
+        let mut selection = Selection::default();
+        // Loss above 10% lowers the layer at once.
+        assert_eq!(selection.next(0.11), Some(layer(1, Some(2))));
+        selection.current = layer(1, Some(2));
+        // Loss between 2% and 10% keeps the layer.
+        assert_eq!(selection.next(0.1), None);
+        assert_eq!(selection.next(0.02), None);
+        // Loss below 2% raises the layer after three intervals in a row.
+        assert_eq!(selection.next(0.0), None);
+        assert_eq!(selection.next(0.01), None);
+        assert_eq!(selection.next(0.05), None);
+        assert_eq!(selection.next(0.0), None);
+        assert_eq!(selection.next(0.0), None);
+        assert_eq!(selection.next(0.0), Some(layer(2, Some(2))));
+        selection.current = layer(2, Some(2));
+        // A burst resets the intervals.
+        assert_eq!(selection.next(0.0), None);
+        assert_eq!(selection.next(0.2), Some(layer(1, Some(2))));
+        assert_eq!(selection.next(0.0), None);
+    }
+
+    #[test]
+    fn layers_stay_below_the_ceiling() {
+        let layer = |sid, tid| Layer { sid, tid };
+        let mut selection = Selection::default();
+        selection.set_ceiling(layer(1, Some(1)));
+        assert_eq!(selection.current, layer(1, Some(1)));
+        for _ in 0..INCREASE_INTERVALS * 2 {
+            assert_eq!(selection.next(0.0), None);
+        }
+
+        assert_eq!(selection.next(0.5), Some(layer(0, Some(1))));
+        selection.current = layer(0, Some(1));
+        assert_eq!(selection.next(0.5), Some(layer(0, Some(0))));
+        selection.current = layer(0, Some(0));
+        assert_eq!(selection.next(0.5), None);
+        let recovered: Vec<_> = (0..INCREASE_INTERVALS * 3)
+            .filter_map(|_| {
+                let next = selection.next(0.0);
+                if let Some(layer) = next {
+                    selection.current = layer;
+                }
+                next
+            })
+            .collect();
+        assert_eq!(recovered, vec![layer(0, Some(1)), layer(1, Some(1))]);
+
+        // A lower ceiling applies at once, like for video which leaves the screen.
+        selection.set_ceiling(layer(0, Some(0)));
+        assert_eq!(selection.current, layer(0, Some(0)));
+        for _ in 0..INCREASE_INTERVALS * 2 {
+            assert_eq!(selection.next(0.0), None);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Encodings of signaling messages. Text frames are always JSON and binary frames are always MessagePack,
+// so the negotiated encoding only decides how the server sends messages.
+// MessagePack carries the same field names as JSON, so both encodings have the same schema.
+use std::{fmt, io::Cursor};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+pub const JSON_PROTOCOL: &str = "json";
+pub const MESSAGEPACK_PROTOCOL: &str = "msgpack";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    /// Chooses the encoding from `?encoding=` or the first known WebSocket subprotocol.
+    /// Returns the encoding and the subprotocol which must be accepted in the handshake.
+    pub fn negotiate(query: Option<&str>, protocols: Option<&str>) -> (Self, Option<&'static str>) {
+        if let Some(protocol) = protocols
+            .into_iter()
+            .flat_map(|p| p.split(','))
+            .find_map(|p| match p.trim() {
+                JSON_PROTOCOL => Some((Encoding::Json, JSON_PROTOCOL)),
+                MESSAGEPACK_PROTOCOL => Some((Encoding::MessagePack, MESSAGEPACK_PROTOCOL)),
+                _ => None,
+            })
+        {
+            return (protocol.0, Some(protocol.1));
+        }
+        match query {
+            Some(MESSAGEPACK_PROTOCOL) => (Encoding::MessagePack, None),
+            _ => (Encoding::Json, None),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Malformed(String),
+    Encode(rmp_serde::encode::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Malformed(message) => write!(f, "{}", message),
+            Error::Encode(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(err: rmp_serde::decode::Error) -> Self {
+        Error::Malformed(err.to_string())
+    }
+}
+
+// Structs are encoded as maps with their field names, like JSON.
+pub fn to_msgpack<T: Serialize>(message: &T) -> Result<Vec<u8>, Error> {
+    rmp_serde::to_vec_named(message).map_err(Error::Encode)
+}
+
+pub fn from_msgpack<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    let mut deserializer = rmp_serde::Deserializer::new(Cursor::new(bytes));
+    deserializer.set_max_depth(MAX_DEPTH);
+    let message = T::deserialize(&mut deserializer)?;
+    if deserializer.position() != bytes.len() as u64 {
+        return Err(Error::Malformed("trailing bytes after message".to_owned()));
+    }
+    Ok(message)
+}
+
+// Also used to read the action of a message which can not be parsed.
+pub fn decode_value(bytes: &[u8]) -> Result<Value, Error> {
+    from_msgpack(bytes)
+}
+
+// Nesting of signaling messages is shallow, so deeper input is rejected instead of overflowing the stack.
+const MAX_DEPTH: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map};
+
+    use super::*;
+
+    fn round_trip(value: Value) {
+        let bytes = to_msgpack(&value).unwrap();
+        assert_eq!(decode_value(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        for n in [
+            json!(0),
+            json!(127),
+            json!(128),
+            json!(255),
+            json!(256),
+            json!(65536),
+            json!(u64::MAX),
+            json!(-1),
+            json!(-32),
+            json!(-33),
+            json!(-129),
+            json!(-32769),
+            json!(i64::MIN),
+            json!(1.5),
+            json!(-0.25),
+        ] {
+            round_trip(n);
+        }
+    }
+
+    #[test]
+    fn strings_and_collections_round_trip() {
+        round_trip(json!(""));
+        round_trip(json!("a".repeat(31)));
+        round_trip(json!("a".repeat(32)));
+        round_trip(json!("日本語".repeat(100)));
+        round_trip(json!("a".repeat(70000)));
+        round_trip(json!((0..20).collect::<Vec<u32>>()));
+        round_trip(json!({ "nested": [{ "a": null, "b": true }, false, []] }));
+        let map: Map<String, Value> = (0..20).map(|i| (i.to_string(), json!(i))).collect();
+        round_trip(Value::Object(map));
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let bytes = to_msgpack(&json!({ "action": "Ping" })).unwrap();
+        assert!(matches!(
+            decode_value(&bytes[..bytes.len() - 1]),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_value(&[bytes.as_slice(), &[0xc0]].concat()),
+            Err(Error::Malformed(_))
+        ));
+        // A map with an integer key.
+        assert!(matches!(
+            decode_value(&[0x81, 0x01, 0xc0]),
+            Err(Error::Malformed(_))
+        ));
+        // Binary data.
+        assert!(matches!(
+            decode_value(&[0xc4, 0x01, 0x00]),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_value(&[0x91; MAX_DEPTH + 2]),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn encoding_is_negotiated() {
+        assert_eq!(
+            Encoding::negotiate(None, Some("foo, msgpack")),
+            (Encoding::MessagePack, Some(MESSAGEPACK_PROTOCOL))
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("msgpack"), Some("json")),
+            (Encoding::Json, Some(JSON_PROTOCOL))
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("msgpack"), None),
+            (Encoding::MessagePack, None)
+        );
+        assert_eq!(Encoding::negotiate(None, None), (Encoding::Json, None));
+    }
+}
@@ -0,0 +1,406 @@
+// Minimal fragmented MP4 (ISO BMFF / CMAF) writer for H.264 and Opus.
+
+#[derive(Debug, Clone)]
+pub enum Codec {
+    H264 {
+        sps: Vec<u8>,
+        pps: Vec<u8>,
+        width: u16,
+        height: u16,
+    },
+    Opus {
+        channels: u8,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackInfo {
+    pub id: u32,
+    pub timescale: u32,
+    pub codec: Codec,
+}
+
+impl TrackInfo {
+    /// RFC 6381 codecs parameter used in HLS playlists.
+    pub fn codecs_parameter(&self) -> String {
+        match &self.codec {
+            Codec::H264 { sps, .. } if sps.len() >= 4 => {
+                format!("avc1.{:02x}{:02x}{:02x}", sps[1], sps[2], sps[3])
+            }
+            Codec::H264 { .. } => "avc1.42e01f".to_owned(),
+            Codec::Opus { .. } => "opus".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub duration: u32,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+/// Samples of one track in a `moof`.
+#[derive(Debug, Clone)]
+pub struct TrackFragment {
+    pub track_id: u32,
+    pub base_decode_time: u64,
+    pub samples: Vec<Sample>,
+}
+
+fn write_box(buf: &mut Vec<u8>, kind: &[u8; 4], f: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(kind);
+    f(buf);
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    buf: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    f: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(buf, kind, |buf| {
+        buf.push(version);
+        buf.extend_from_slice(&flags.to_be_bytes()[1..]);
+        f(buf);
+    });
+}
+
+fn u16be(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn u32be(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn u64be(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+/// Builds the initialization segment (`ftyp` + `moov`) referenced by `EXT-X-MAP`.
+pub fn init_segment(tracks: &[TrackInfo]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_box(&mut buf, b"ftyp", |buf| {
+        buf.extend_from_slice(b"iso6");
+        u32be(buf, 0);
+        for brand in [b"iso6", b"cmfc", b"mp41"] {
+            buf.extend_from_slice(brand);
+        }
+    });
+    write_box(&mut buf, b"moov", |buf| {
+        write_full_box(buf, b"mvhd", 0, 0, |buf| {
+            u32be(buf, 0);
+            u32be(buf, 0);
+            u32be(buf, 1000);
+            u32be(buf, 0);
+            u32be(buf, 0x00010000);
+            u16be(buf, 0x0100);
+            buf.extend_from_slice(&[0; 10]);
+            MATRIX.iter().for_each(|v| u32be(buf, *v));
+            buf.extend_from_slice(&[0; 24]);
+            u32be(buf, tracks.iter().map(|t| t.id).max().unwrap_or(0) + 1);
+        });
+        for track in tracks {
+            write_trak(buf, track);
+        }
+        write_box(buf, b"mvex", |buf| {
+            for track in tracks {
+                write_full_box(buf, b"trex", 0, 0, |buf| {
+                    u32be(buf, track.id);
+                    u32be(buf, 1);
+                    u32be(buf, 0);
+                    u32be(buf, 0);
+                    u32be(buf, 0);
+                });
+            }
+        });
+    });
+    buf
+}
+
+fn write_trak(buf: &mut Vec<u8>, track: &TrackInfo) {
+    let (is_video, width, height) = match &track.codec {
+        Codec::H264 { width, height, .. } => (true, *width, *height),
+        Codec::Opus { .. } => (false, 0, 0),
+    };
+    write_box(buf, b"trak", |buf| {
+        // track_enabled | track_in_movie
+        write_full_box(buf, b"tkhd", 0, 0x3, |buf| {
+            u32be(buf, 0);
+            u32be(buf, 0);
+            u32be(buf, track.id);
+            u32be(buf, 0);
+            u32be(buf, 0);
+            buf.extend_from_slice(&[0; 8]);
+            u16be(buf, 0);
+            u16be(buf, 0);
+            u16be(buf, if is_video { 0 } else { 0x0100 });
+            u16be(buf, 0);
+            MATRIX.iter().for_each(|v| u32be(buf, *v));
+            u32be(buf, (width as u32) << 16);
+            u32be(buf, (height as u32) << 16);
+        });
+        write_box(buf, b"mdia", |buf| {
+            write_full_box(buf, b"mdhd", 0, 0, |buf| {
+                u32be(buf, 0);
+                u32be(buf, 0);
+                u32be(buf, track.timescale);
+                u32be(buf, 0);
+                // language: und
+                u16be(buf, 0x55c4);
+                u16be(buf, 0);
+            });
+            write_full_box(buf, b"hdlr", 0, 0, |buf| {
+                u32be(buf, 0);
+                buf.extend_from_slice(if is_video { b"vide" } else { b"soun" });
+                buf.extend_from_slice(&[0; 12]);
+                buf.extend_from_slice(if is_video {
+                    b"VideoHandler\0"
+                } else {
+                    b"SoundHandler\0"
+                });
+            });
+            write_box(buf, b"minf", |buf| {
+                if is_video {
+                    write_full_box(buf, b"vmhd", 0, 1, |buf| buf.extend_from_slice(&[0; 8]));
+                } else {
+                    write_full_box(buf, b"smhd", 0, 0, |buf| buf.extend_from_slice(&[0; 4]));
+                }
+                write_box(buf, b"dinf", |buf| {
+                    write_full_box(buf, b"dref", 0, 0, |buf| {
+                        u32be(buf, 1);
+                        // self-contained
+                        write_full_box(buf, b"url ", 0, 1, |_| {});
+                    });
+                });
+                write_box(buf, b"stbl", |buf| {
+                    write_full_box(buf, b"stsd", 0, 0, |buf| {
+                        u32be(buf, 1);
+                        write_sample_entry(buf, &track.codec);
+                    });
+                    write_full_box(buf, b"stts", 0, 0, |buf| u32be(buf, 0));
+                    write_full_box(buf, b"stsc", 0, 0, |buf| u32be(buf, 0));
+                    write_full_box(buf, b"stsz", 0, 0, |buf| {
+                        u32be(buf, 0);
+                        u32be(buf, 0);
+                    });
+                    write_full_box(buf, b"stco", 0, 0, |buf| u32be(buf, 0));
+                });
+            });
+        });
+    });
+}
+
+fn write_sample_entry(buf: &mut Vec<u8>, codec: &Codec) {
+    match codec {
+        Codec::H264 {
+            sps,
+            pps,
+            width,
+            height,
+        } => write_box(buf, b"avc1", |buf| {
+            buf.extend_from_slice(&[0; 6]);
+            u16be(buf, 1);
+            buf.extend_from_slice(&[0; 16]);
+            u16be(buf, *width);
+            u16be(buf, *height);
+            u32be(buf, 0x00480000);
+            u32be(buf, 0x00480000);
+            u32be(buf, 0);
+            u16be(buf, 1);
+            buf.extend_from_slice(&[0; 32]);
+            u16be(buf, 0x0018);
+            u16be(buf, 0xffff);
+            write_box(buf, b"avcC", |buf| {
+                buf.push(1);
+                buf.push(sps.get(1).copied().unwrap_or(0x42));
+                buf.push(sps.get(2).copied().unwrap_or(0xe0));
+                buf.push(sps.get(3).copied().unwrap_or(0x1f));
+                // 4 bytes NAL unit length
+                buf.push(0xff);
+                buf.push(0xe1);
+                u16be(buf, sps.len() as u16);
+                buf.extend_from_slice(sps);
+                buf.push(1);
+                u16be(buf, pps.len() as u16);
+                buf.extend_from_slice(pps);
+            });
+        }),
+        Codec::Opus { channels } => write_box(buf, b"Opus", |buf| {
+            buf.extend_from_slice(&[0; 6]);
+            u16be(buf, 1);
+            buf.extend_from_slice(&[0; 8]);
+            u16be(buf, *channels as u16);
+            u16be(buf, 16);
+            u32be(buf, 0);
+            u32be(buf, 48000 << 16);
+            write_box(buf, b"dOps", |buf| {
+                buf.push(0);
+                buf.push(*channels);
+                // pre-skip
+                u16be(buf, 312);
+                u32be(buf, 48000);
+                u16be(buf, 0);
+                buf.push(0);
+            });
+        }),
+    }
+}
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+/// Builds a `moof` + `mdat` pair containing the given samples.
+pub fn media_fragment(sequence: u32, fragments: &[TrackFragment]) -> Vec<u8> {
+    // The data offsets depend on the moof size, which does not depend on the offsets themselves.
+    let moof_size = write_moof(sequence, fragments, 0).len();
+    let mut buf = write_moof(sequence, fragments, moof_size as u32 + 8);
+    write_box(&mut buf, b"mdat", |buf| {
+        for fragment in fragments {
+            for sample in &fragment.samples {
+                buf.extend_from_slice(&sample.data);
+            }
+        }
+    });
+    buf
+}
+
+fn write_moof(sequence: u32, fragments: &[TrackFragment], data_start: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_box(&mut buf, b"moof", |buf| {
+        write_full_box(buf, b"mfhd", 0, 0, |buf| u32be(buf, sequence));
+        let mut data_offset = data_start;
+        for fragment in fragments {
+            write_box(buf, b"traf", |buf| {
+                // default-base-is-moof
+                write_full_box(buf, b"tfhd", 0, 0x020000, |buf| {
+                    u32be(buf, fragment.track_id)
+                });
+                write_full_box(buf, b"tfdt", 1, 0, |buf| {
+                    u64be(buf, fragment.base_decode_time)
+                });
+                // data-offset, sample-duration, sample-size, sample-flags
+                write_full_box(buf, b"trun", 0, 0x000701, |buf| {
+                    u32be(buf, fragment.samples.len() as u32);
+                    u32be(buf, data_offset);
+                    for sample in &fragment.samples {
+                        u32be(buf, sample.duration);
+                        u32be(buf, sample.data.len() as u32);
+                        u32be(
+                            buf,
+                            if sample.keyframe {
+                                SAMPLE_FLAGS_SYNC
+                            } else {
+                                SAMPLE_FLAGS_NON_SYNC
+                            },
+                        );
+                    }
+                });
+            });
+            data_offset += fragment
+                .samples
+                .iter()
+                .map(|s| s.data.len() as u32)
+                .sum::<u32>();
+        }
+    });
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Types and payloads of the boxes at the top level, or in the payload of a box.
+    fn boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            let kind = data[offset + 4..offset + 8].try_into().unwrap();
+            boxes.push((kind, &data[offset + 8..offset + size]));
+            offset += size;
+        }
+        assert_eq!(offset, data.len());
+        boxes
+    }
+
+    #[test]
+    fn writes_init_segment() {
+        let tracks = [
+            TrackInfo {
+                id: 1,
+                timescale: 90000,
+                codec: Codec::H264 {
+                    sps: vec![0x67, 0x42, 0xc0, 0x1f],
+                    pps: vec![0x68],
+                    width: 640,
+                    height: 480,
+                },
+            },
+            TrackInfo {
+                id: 2,
+                timescale: 48000,
+                codec: Codec::Opus { channels: 2 },
+            },
+        ];
+        assert_eq!(tracks[0].codecs_parameter(), "avc1.42c01f");
+        assert_eq!(tracks[1].codecs_parameter(), "opus");
+
+        let init = init_segment(&tracks);
+        let top: Vec<[u8; 4]> = boxes(&init).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(top, [*b"ftyp", *b"moov"]);
+        let moov: Vec<[u8; 4]> = boxes(boxes(&init)[1].1)
+            .iter()
+            .map(|(kind, _)| *kind)
+            .collect();
+        assert_eq!(moov, [*b"mvhd", *b"trak", *b"trak", *b"mvex"]);
+    }
+
+    #[test]
+    fn points_trun_to_mdat() {
+        let sample = |data: Vec<u8>, keyframe| Sample {
+            duration: 3000,
+            keyframe,
+            data,
+        };
+        let fragment = media_fragment(
+            7,
+            &[
+                TrackFragment {
+                    track_id: 1,
+                    base_decode_time: 0,
+                    samples: vec![sample(vec![1, 1], true), sample(vec![2], false)],
+                },
+                TrackFragment {
+                    track_id: 2,
+                    base_decode_time: 0,
+                    samples: vec![sample(vec![3, 3, 3], true)],
+                },
+            ],
+        );
+        let top = boxes(&fragment);
+        assert_eq!(top[0].0, *b"moof");
+        assert_eq!(top[1], (*b"mdat", &[1, 1, 2, 3, 3, 3][..]));
+
+        let mdat_payload = fragment.len() - 6;
+        let offsets: Vec<usize> = boxes(top[0].1)
+            .iter()
+            .filter(|(kind, _)| kind == b"traf")
+            .map(|(_, traf)| {
+                let trun = boxes(traf)[2];
+                assert_eq!(trun.0, *b"trun");
+                u32::from_be_bytes(trun.1[8..12].try_into().unwrap()) as usize
+            })
+            .collect();
+        assert_eq!(offsets, [mdat_payload, mdat_payload + 3]);
+    }
+}
@@ -0,0 +1,215 @@
+pub const NALU_TYPE_IDR: u8 = 5;
+pub const NALU_TYPE_SPS: u8 = 7;
+pub const NALU_TYPE_PPS: u8 = 8;
+
+pub fn nalu_type(nalu: &[u8]) -> u8 {
+    nalu.first().map(|b| b & 0x1f).unwrap_or(0)
+}
+
+/// Splits AVC formatted (4 bytes length prefixed) NAL units.
+pub fn split_avc(data: &[u8]) -> Vec<&[u8]> {
+    let mut nalus = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= data.len() {
+        let len = u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ]) as usize;
+        offset += 4;
+        if offset + len > data.len() {
+            break;
+        }
+        nalus.push(&data[offset..offset + len]);
+        offset += len;
+    }
+    nalus
+}
+
+/// Picture size read from a sequence parameter set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u16,
+    pub height: u16,
+}
+
+struct BitReader {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl BitReader {
+    fn new(rbsp: &[u8]) -> Self {
+        // Remove emulation prevention bytes (0x000003).
+        let mut data = Vec::with_capacity(rbsp.len());
+        let mut zeros = 0;
+        for &b in rbsp {
+            if zeros >= 2 && b == 3 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            data.push(b);
+        }
+        Self { data, position: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit as u32)
+    }
+
+    fn bits(&mut self, n: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.bit()?;
+        }
+        Some(value)
+    }
+
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i32> {
+        let value = self.ue()?;
+        if value % 2 == 0 {
+            Some(-((value / 2) as i32))
+        } else {
+            Some(value.div_ceil(2) as i32)
+        }
+    }
+}
+
+/// Reads the picture size from a SPS NAL unit, including its header byte.
+pub fn parse_sps(sps: &[u8]) -> Option<Resolution> {
+    let mut reader = BitReader::new(sps.get(1..)?);
+    let profile_idc = reader.bits(8)?;
+    // constraint flags and level_idc
+    reader.bits(16)?;
+    // seq_parameter_set_id
+    reader.ue()?;
+
+    let mut chroma_format_idc = 1;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = reader.ue()?;
+        if chroma_format_idc == 3 {
+            // separate_colour_plane_flag
+            reader.bit()?;
+        }
+        // bit_depth_luma_minus8, bit_depth_chroma_minus8
+        reader.ue()?;
+        reader.ue()?;
+        // qpprime_y_zero_transform_bypass_flag
+        reader.bit()?;
+        if reader.bit()? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if reader.bit()? == 1 {
+                    let size = if i < 6 { 16 } else { 64 };
+                    let mut last = 8i32;
+                    let mut next = 8i32;
+                    for _ in 0..size {
+                        if next != 0 {
+                            next = (last + reader.se()? + 256) % 256;
+                        }
+                        if next != 0 {
+                            last = next;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // log2_max_frame_num_minus4
+    reader.ue()?;
+    let pic_order_cnt_type = reader.ue()?;
+    if pic_order_cnt_type == 0 {
+        reader.ue()?;
+    } else if pic_order_cnt_type == 1 {
+        reader.bit()?;
+        reader.se()?;
+        reader.se()?;
+        for _ in 0..reader.ue()? {
+            reader.se()?;
+        }
+    }
+    // max_num_ref_frames, gaps_in_frame_num_value_allowed_flag
+    reader.ue()?;
+    reader.bit()?;
+
+    let width_in_mbs = reader.ue()? + 1;
+    let height_in_map_units = reader.ue()? + 1;
+    let frame_mbs_only_flag = reader.bit()?;
+    if frame_mbs_only_flag == 0 {
+        // mb_adaptive_frame_field_flag
+        reader.bit()?;
+    }
+    // direct_8x8_inference_flag
+    reader.bit()?;
+
+    let mut crop = (0, 0, 0, 0);
+    if reader.bit()? == 1 {
+        crop = (reader.ue()?, reader.ue()?, reader.ue()?, reader.ue()?);
+    }
+    let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+        0 => (1, 2 - frame_mbs_only_flag),
+        1 => (2, 2 * (2 - frame_mbs_only_flag)),
+        2 => (2, 2 - frame_mbs_only_flag),
+        _ => (1, 2 - frame_mbs_only_flag),
+    };
+
+    let width = width_in_mbs * 16 - crop_unit_x * (crop.0 + crop.1);
+    let height =
+        (2 - frame_mbs_only_flag) * height_in_map_units * 16 - crop_unit_y * (crop.2 + crop.3);
+    Some(Resolution {
+        width: u16::try_from(width).ok()?,
+        height: u16::try_from(height).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_avc() {
+        let data = [0, 0, 0, 2, 0x67, 1, 0, 0, 0, 1, 0x65, 0, 0, 0, 9, 0x41];
+        let nalus = split_avc(&data);
+        assert_eq!(nalus, vec![&[0x67, 1][..], &[0x65][..]]);
+        assert_eq!(nalu_type(nalus[0]), NALU_TYPE_SPS);
+        assert_eq!(nalu_type(nalus[1]), NALU_TYPE_IDR);
+    }
+
+    #[test]
+    fn reads_cropped_resolution() {
+        // 1920x1088 cropped to 1080 lines, in Baseline and High profiles.
+        let baseline = [
+            0x67, 0x42, 0xc0, 0x28, 0xed, 0x00, 0xf0, 0x04, 0x4f, 0xca, 0x80,
+        ];
+        let high = [
+            0x67, 0x64, 0xc0, 0x28, 0xac, 0xda, 0x01, 0xe0, 0x08, 0x9f, 0x95,
+        ];
+        let expected = Resolution {
+            width: 1920,
+            height: 1080,
+        };
+        assert_eq!(parse_sps(&baseline), Some(expected));
+        assert_eq!(parse_sps(&high), Some(expected));
+        assert_eq!(parse_sps(&baseline[..4]), None);
+    }
+}
@@ -0,0 +1,385 @@
+use std::{fmt, sync::Arc, time::Duration, time::Instant};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use tokio::{sync::mpsc, sync::Mutex, task::JoinHandle};
+use webrtc::{
+    api::media_engine::{MIME_TYPE_H264, MIME_TYPE_OPUS},
+    rtp::{codecs::h264::H264Packet, packetizer::Depacketizer},
+    track::track_remote::TrackRemote,
+};
+
+use crate::{audit, loopback::LoopbackSubscriber, room, settings};
+
+mod fmp4;
+mod h264;
+mod muxer;
+mod playlist;
+
+use muxer::{Frame, Kind, Muxer};
+use playlist::Stream;
+
+const PART_TARGET: Duration = Duration::from_millis(500);
+const SEGMENT_TARGET: Duration = Duration::from_secs(2);
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum Error {
+    RoomNotFound,
+    AlreadyStarted,
+    RecordingDisabled,
+    NoPublisher,
+    UnsupportedCodec(String),
+    Rheomesh(rheomesh::error::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RoomNotFound => write!(f, "room is not found"),
+            Error::AlreadyStarted => write!(f, "egress is already started"),
+            Error::RecordingDisabled => write!(f, "recording is disabled in this room"),
+            Error::NoPublisher => write!(f, "at least one publisher is required"),
+            Error::UnsupportedCodec(mime_type) => write!(f, "unsupported codec: {}", mime_type),
+            Error::Rheomesh(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<rheomesh::error::Error> for Error {
+    fn from(err: rheomesh::error::Error) -> Self {
+        Error::Rheomesh(err)
+    }
+}
+
+/// Packages publishers of a room into LL-HLS. Only H.264 video and Opus audio are supported,
+/// because media is not transcoded.
+pub struct Egress {
+    subscriber: Arc<LoopbackSubscriber>,
+    stream: Arc<Stream>,
+    muxer: JoinHandle<()>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl Egress {
+    pub async fn start(
+        room: &room::Room,
+        video_publisher_id: Option<String>,
+        audio_publisher_id: Option<String>,
+    ) -> Result<Self, Error> {
+        if video_publisher_id.is_none() && audio_publisher_id.is_none() {
+            return Err(Error::NoPublisher);
+        }
+        let subscriber = Arc::new(LoopbackSubscriber::new(room).await?);
+        let tracks = async {
+            let video = match &video_publisher_id {
+                Some(id) => Some(Self::subscribe(&subscriber, id, MIME_TYPE_H264).await?),
+                None => None,
+            };
+            let audio = match &audio_publisher_id {
+                Some(id) => Some(Self::subscribe(&subscriber, id, MIME_TYPE_OPUS).await?),
+                None => None,
+            };
+            Ok::<_, Error>((video, audio))
+        }
+        .await;
+        let (video, audio) = match tracks {
+            Ok(tracks) => tracks,
+            Err(err) => {
+                subscriber.close().await;
+                return Err(err);
+            }
+        };
+
+        let stream = Arc::new(Stream::new(PART_TARGET, SEGMENT_TARGET));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let muxer = Muxer::new(
+            stream.clone(),
+            PART_TARGET,
+            SEGMENT_TARGET,
+            video.is_some(),
+            audio.is_some(),
+        );
+        let muxer = tokio::spawn(muxer.run(receiver));
+        let mut readers = Vec::new();
+        if let Some(track) = video {
+            readers.push(tokio::spawn(read_video(
+                track,
+                subscriber.clone(),
+                sender.clone(),
+            )));
+        }
+        if let Some(track) = audio {
+            readers.push(tokio::spawn(read_audio(track, sender)));
+        }
+        tracing::info!(
+            "HLS egress started in room {}: video={:?}, audio={:?}",
+            room.id,
+            video_publisher_id,
+            audio_publisher_id
+        );
+
+        Ok(Self {
+            subscriber,
+            stream,
+            muxer,
+            readers,
+        })
+    }
+
+    async fn subscribe(
+        subscriber: &LoopbackSubscriber,
+        publisher_id: &str,
+        mime_type: &str,
+    ) -> Result<Arc<TrackRemote>, Error> {
+        let track = subscriber.subscribe(publisher_id).await?;
+        let codec = track.codec().capability.mime_type;
+        if !codec.eq_ignore_ascii_case(mime_type) {
+            return Err(Error::UnsupportedCodec(codec));
+        }
+        Ok(track)
+    }
+
+    pub async fn stop(self) {
+        // Readers are aborted first, so the muxer flushes the last part after senders are dropped.
+        for reader in &self.readers {
+            reader.abort();
+        }
+        if let Err(err) = self.muxer.await {
+            tracing::error!("HLS muxer failed: {}", err);
+        }
+        self.subscriber.close().await;
+    }
+}
+
+async fn read_video(
+    track: Arc<TrackRemote>,
+    subscriber: Arc<LoopbackSubscriber>,
+    sender: mpsc::UnboundedSender<Frame>,
+) {
+    let mut depacketizer = H264Packet::default();
+    depacketizer.is_avc = true;
+    let mut data = Vec::new();
+    let mut timestamp = None;
+    let mut keyframe_received = false;
+    let mut keyframe_requested: Option<Instant> = None;
+
+    loop {
+        if !keyframe_received
+            && keyframe_requested
+                .map(|t| t.elapsed() > KEYFRAME_INTERVAL)
+                .unwrap_or(true)
+        {
+            subscriber.request_keyframe(&track).await;
+            keyframe_requested = Some(Instant::now());
+        }
+        let packet = match track.read_rtp().await {
+            Ok((packet, _)) => packet,
+            Err(err) => {
+                tracing::debug!("HLS video reader finished: {}", err);
+                break;
+            }
+        };
+        // A new timestamp without the marker bit means the previous frame lost its last packet.
+        if timestamp.is_some_and(|t| t != packet.header.timestamp) {
+            data.clear();
+        }
+        timestamp = Some(packet.header.timestamp);
+        match depacketizer.depacketize(&packet.payload) {
+            Ok(payload) => data.extend_from_slice(&payload),
+            Err(err) => tracing::trace!("Failed to depacketize H.264: {}", err),
+        }
+        if !packet.header.marker || data.is_empty() {
+            continue;
+        }
+
+        let frame = std::mem::take(&mut data);
+        timestamp = None;
+        let keyframe = h264::split_avc(&frame)
+            .iter()
+            .any(|n| h264::nalu_type(n) == h264::NALU_TYPE_IDR);
+        keyframe_received |= keyframe;
+        let frame = Frame {
+            kind: Kind::Video,
+            timestamp: packet.header.timestamp,
+            arrival: Instant::now(),
+            keyframe,
+            data: frame,
+        };
+        if sender.send(frame).is_err() {
+            break;
+        }
+    }
+}
+
+async fn read_audio(track: Arc<TrackRemote>, sender: mpsc::UnboundedSender<Frame>) {
+    while let Ok((packet, _)) = track.read_rtp().await {
+        if packet.payload.is_empty() {
+            continue;
+        }
+        let frame = Frame {
+            kind: Kind::Audio,
+            timestamp: packet.header.timestamp,
+            arrival: Instant::now(),
+            keyframe: true,
+            data: packet.payload.to_vec(),
+        };
+        if sender.send(frame).is_err() {
+            break;
+        }
+    }
+    tracing::debug!("HLS audio reader finished");
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StartRequest {
+    video_publisher_id: Option<String>,
+    audio_publisher_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockingReload {
+    #[serde(rename = "_HLS_msn")]
+    msn: Option<u64>,
+    #[serde(rename = "_HLS_part")]
+    part: Option<usize>,
+}
+
+async fn find_room(
+    room_owner: &Mutex<room::RoomOwner>,
+    room_id: &str,
+) -> Result<Arc<room::Room>, Error> {
+    room_owner
+        .lock()
+        .await
+        .find_by_id(room_id.to_string())
+        .ok_or(Error::RoomNotFound)
+}
+
+pub async fn start(
+    path: web::Path<String>,
+    body: web::Json<StartRequest>,
+    room_owner: web::Data<Mutex<room::RoomOwner>>,
+) -> impl Responder {
+    let room_id = path.into_inner();
+    let result = async {
+        let room = find_room(&room_owner, &room_id).await?;
+        if room.settings.recording == settings::RecordingPolicy::Disabled {
+            return Err(Error::RecordingDisabled);
+        }
+        let mut hls = room.hls.lock().await;
+        if hls.is_some() {
+            return Err(Error::AlreadyStarted);
+        }
+        let body = body.into_inner();
+        let egress = Egress::start(&room, body.video_publisher_id, body.audio_publisher_id).await?;
+        *hls = Some(egress);
+        room.audit.emit(audit::Event::RecordingStarted {
+            room_id: room.id.clone(),
+        });
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => HttpResponse::Created().finish(),
+        Err(Error::RoomNotFound) => HttpResponse::NotFound().body(Error::RoomNotFound.to_string()),
+        Err(Error::AlreadyStarted) => {
+            HttpResponse::Conflict().body(Error::AlreadyStarted.to_string())
+        }
+        Err(Error::RecordingDisabled) => {
+            HttpResponse::Forbidden().body(Error::RecordingDisabled.to_string())
+        }
+        Err(err) => {
+            tracing::error!("Failed to start HLS egress: {}", err);
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+    }
+}
+
+pub async fn stop(
+    path: web::Path<String>,
+    room_owner: web::Data<Mutex<room::RoomOwner>>,
+) -> impl Responder {
+    let Ok(room) = find_room(&room_owner, &path.into_inner()).await else {
+        return HttpResponse::NotFound().finish();
+    };
+    let egress = room.hls.lock().await.take();
+    match egress {
+        Some(egress) => {
+            egress.stop().await;
+            room.audit.emit(audit::Event::RecordingStopped {
+                room_id: room.id.clone(),
+            });
+            HttpResponse::NoContent().finish()
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+pub async fn file(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    room_owner: web::Data<Mutex<room::RoomOwner>>,
+) -> impl Responder {
+    let (room_id, file) = path.into_inner();
+    let Ok(room) = find_room(&room_owner, &room_id).await else {
+        return HttpResponse::NotFound().finish();
+    };
+    let Some(stream) = room.hls.lock().await.as_ref().map(|e| e.stream.clone()) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    if file == "playlist.m3u8" {
+        let reload = web::Query::<BlockingReload>::from_query(req.query_string()).ok();
+        if let Some(msn) = reload.as_ref().and_then(|r| r.msn) {
+            // Segments which are too far ahead are not waited for, as required by the LL-HLS spec.
+            if stream.is_too_far(msn) {
+                return HttpResponse::BadRequest().finish();
+            }
+            let part = reload.as_ref().and_then(|r| r.part).unwrap_or(0);
+            stream.wait_part(msn, part).await;
+        }
+        return HttpResponse::Ok()
+            .content_type("application/vnd.apple.mpegurl")
+            .insert_header(("Cache-Control", "no-cache"))
+            .body(stream.playlist());
+    }
+    if file == "init.mp4" {
+        return match stream.init() {
+            Some(init) => HttpResponse::Ok()
+                .content_type("video/mp4")
+                .body(init.as_ref().clone()),
+            None => HttpResponse::NotFound().finish(),
+        };
+    }
+    if let Some(msn) = file
+        .strip_prefix("segment")
+        .and_then(|f| f.strip_suffix(".m4s"))
+        .and_then(|f| f.parse::<u64>().ok())
+    {
+        return match stream.segment(msn) {
+            Some(data) => HttpResponse::Ok()
+                .content_type("video/iso.segment")
+                .body(data),
+            None => HttpResponse::NotFound().finish(),
+        };
+    }
+    if let Some((msn, index)) = file
+        .strip_prefix("part")
+        .and_then(|f| f.strip_suffix(".m4s"))
+        .and_then(|f| f.split_once('.'))
+        .and_then(|(msn, index)| Some((msn.parse::<u64>().ok()?, index.parse::<usize>().ok()?)))
+    {
+        // Parts announced by EXT-X-PRELOAD-HINT are requested before they exist.
+        stream.wait_part(msn, index).await;
+        return match stream.part(msn, index) {
+            Some(data) => HttpResponse::Ok()
+                .content_type("video/iso.segment")
+                .body(data.as_ref().clone()),
+            None => HttpResponse::NotFound().finish(),
+        };
+    }
+    HttpResponse::NotFound().finish()
+}
@@ -0,0 +1,264 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use super::{
+    fmp4::{self, Codec, Sample, TrackFragment, TrackInfo},
+    h264,
+    playlist::{Part, Stream},
+};
+
+const VIDEO_TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+const VIDEO_TIMESCALE: u32 = 90000;
+const AUDIO_TIMESCALE: u32 = 48000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Video,
+    Audio,
+}
+
+/// A complete access unit read from RTP. H.264 frames are AVC formatted.
+#[derive(Debug)]
+pub struct Frame {
+    pub kind: Kind,
+    pub timestamp: u32,
+    pub arrival: Instant,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+struct TrackState {
+    track_id: u32,
+    timescale: u32,
+    last_rtp: Option<u32>,
+    decode_time: u64,
+    // A sample is written once the next one arrives, because its duration is unknown until then.
+    pending: Option<(u64, bool, Vec<u8>)>,
+    buffer: Option<TrackFragment>,
+    last_duration: u64,
+}
+
+impl TrackState {
+    fn new(track_id: u32, timescale: u32) -> Self {
+        Self {
+            track_id,
+            timescale,
+            last_rtp: None,
+            decode_time: 0,
+            pending: None,
+            buffer: None,
+            last_duration: 0,
+        }
+    }
+
+    fn decode_time(&mut self, rtp_timestamp: u32, arrival: Instant, origin: Instant) -> u64 {
+        match self.last_rtp {
+            // Tracks are aligned by the arrival time of their first frame.
+            None => {
+                let elapsed = arrival.saturating_duration_since(origin);
+                self.decode_time = (elapsed.as_secs_f64() * self.timescale as f64) as u64;
+            }
+            Some(last) => {
+                let diff = rtp_timestamp.wrapping_sub(last) as i32 as i64;
+                self.decode_time = (self.decode_time as i64 + diff).max(0) as u64;
+            }
+        }
+        self.last_rtp = Some(rtp_timestamp);
+        self.decode_time
+    }
+
+    fn push(&mut self, decode_time: u64, keyframe: bool, data: Vec<u8>) {
+        if let Some((previous_time, previous_keyframe, previous_data)) = self.pending.take() {
+            let duration = decode_time.saturating_sub(previous_time).max(1);
+            self.last_duration = duration;
+            let track_id = self.track_id;
+            let fragment = self.buffer.get_or_insert_with(|| TrackFragment {
+                track_id,
+                base_decode_time: previous_time,
+                samples: Vec::new(),
+            });
+            fragment.samples.push(Sample {
+                duration: duration as u32,
+                keyframe: previous_keyframe,
+                data: previous_data,
+            });
+        }
+        self.pending = Some((decode_time, keyframe, data));
+    }
+
+    fn buffered(&self) -> u64 {
+        self.buffer
+            .as_ref()
+            .map(|b| b.samples.iter().map(|s| s.duration as u64).sum())
+            .unwrap_or(0)
+    }
+
+    fn seconds(&self, ticks: u64) -> f64 {
+        ticks as f64 / self.timescale as f64
+    }
+}
+
+pub struct Muxer {
+    stream: Arc<Stream>,
+    part_target: Duration,
+    segment_target: Duration,
+    video: Option<TrackState>,
+    audio: Option<TrackState>,
+    origin: Option<Instant>,
+    sequence: u32,
+    segment_duration: f64,
+}
+
+impl Muxer {
+    pub fn new(
+        stream: Arc<Stream>,
+        part_target: Duration,
+        segment_target: Duration,
+        has_video: bool,
+        has_audio: bool,
+    ) -> Self {
+        Self {
+            stream,
+            part_target,
+            segment_target,
+            video: has_video.then(|| TrackState::new(VIDEO_TRACK_ID, VIDEO_TIMESCALE)),
+            audio: has_audio.then(|| TrackState::new(AUDIO_TRACK_ID, AUDIO_TIMESCALE)),
+            origin: None,
+            sequence: 1,
+            segment_duration: 0.0,
+        }
+    }
+
+    pub async fn run(mut self, mut frames: mpsc::UnboundedReceiver<Frame>) {
+        while let Some(frame) = frames.recv().await {
+            self.handle(frame);
+        }
+        self.flush_part();
+        self.stream.finish_segment();
+        self.stream.end();
+        tracing::debug!("HLS muxer finished");
+    }
+
+    fn handle(&mut self, frame: Frame) {
+        let origin = match self.origin {
+            Some(origin) => origin,
+            None => match self.start(&frame) {
+                Some(origin) => origin,
+                None => return,
+            },
+        };
+
+        let driver = match frame.kind {
+            Kind::Video => self.video.is_some(),
+            Kind::Audio => self.video.is_none(),
+        };
+        let track = match frame.kind {
+            Kind::Video => self.video.as_mut(),
+            Kind::Audio => self.audio.as_mut(),
+        };
+        let Some(track) = track else {
+            return;
+        };
+        let decode_time = track.decode_time(frame.timestamp, frame.arrival, origin);
+        let keyframe = frame.keyframe || frame.kind == Kind::Audio;
+        track.push(decode_time, keyframe, frame.data);
+
+        if !driver {
+            return;
+        }
+        let buffered = track.seconds(track.buffered());
+        let next = track.seconds(track.buffered() + track.last_duration);
+        if keyframe && self.segment_duration + buffered >= self.segment_target.as_secs_f64() {
+            self.flush_part();
+            self.stream.finish_segment();
+            self.segment_duration = 0.0;
+        } else if next > self.part_target.as_secs_f64() {
+            self.flush_part();
+        }
+    }
+
+    // Writes the init segment once every codec parameter is known.
+    fn start(&mut self, frame: &Frame) -> Option<Instant> {
+        let mut tracks = Vec::new();
+        if self.video.is_some() {
+            if frame.kind != Kind::Video || !frame.keyframe {
+                return None;
+            }
+            let nalus = h264::split_avc(&frame.data);
+            let sps = nalus
+                .iter()
+                .find(|n| h264::nalu_type(n) == h264::NALU_TYPE_SPS)?;
+            let pps = nalus
+                .iter()
+                .find(|n| h264::nalu_type(n) == h264::NALU_TYPE_PPS)?;
+            let resolution = h264::parse_sps(sps)?;
+            tracks.push(TrackInfo {
+                id: VIDEO_TRACK_ID,
+                timescale: VIDEO_TIMESCALE,
+                codec: Codec::H264 {
+                    sps: sps.to_vec(),
+                    pps: pps.to_vec(),
+                    width: resolution.width,
+                    height: resolution.height,
+                },
+            });
+        }
+        if self.audio.is_some() {
+            tracks.push(TrackInfo {
+                id: AUDIO_TRACK_ID,
+                timescale: AUDIO_TIMESCALE,
+                codec: Codec::Opus { channels: 2 },
+            });
+        }
+        tracing::info!(
+            "HLS stream started: {}",
+            tracks
+                .iter()
+                .map(|t| t.codecs_parameter())
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        self.stream.set_init(fmp4::init_segment(&tracks));
+        self.origin = Some(frame.arrival);
+        self.origin
+    }
+
+    fn flush_part(&mut self) {
+        let driver = match (&self.video, &self.audio) {
+            (Some(video), _) => video,
+            (None, Some(audio)) => audio,
+            (None, None) => return,
+        };
+        let duration = driver.seconds(driver.buffered());
+        if duration <= 0.0 {
+            return;
+        }
+        let independent = match &self.video {
+            Some(video) => video
+                .buffer
+                .as_ref()
+                .and_then(|b| b.samples.first())
+                .map(|s| s.keyframe)
+                .unwrap_or(false),
+            None => true,
+        };
+        let fragments: Vec<TrackFragment> = [self.video.as_mut(), self.audio.as_mut()]
+            .into_iter()
+            .flatten()
+            .filter_map(|t| t.buffer.take())
+            .collect();
+        let data = fmp4::media_fragment(self.sequence, &fragments);
+        self.sequence += 1;
+        self.segment_duration += duration;
+        self.stream.push_part(Part {
+            duration,
+            independent,
+            data: Arc::new(data),
+        });
+    }
+}
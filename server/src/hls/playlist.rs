@@ -0,0 +1,280 @@
+use std::{collections::VecDeque, fmt::Write, sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+// Number of completed segments kept in the playlist.
+const WINDOW: usize = 6;
+// Parts are only advertised for the latest segments, as recommended by the LL-HLS spec.
+const PART_WINDOW: usize = 2;
+// Blocking reloads may ask for segments up to this many ahead of the last one.
+const MAX_MSN_AHEAD: u64 = 2;
+
+pub struct Part {
+    pub duration: f64,
+    pub independent: bool,
+    pub data: Arc<Vec<u8>>,
+}
+
+pub struct Segment {
+    pub msn: u64,
+    pub parts: Vec<Part>,
+    pub complete: bool,
+}
+
+impl Segment {
+    fn duration(&self) -> f64 {
+        self.parts.iter().map(|p| p.duration).sum()
+    }
+
+    fn data(&self) -> Vec<u8> {
+        self.parts
+            .iter()
+            .flat_map(|p| p.data.iter().copied())
+            .collect()
+    }
+}
+
+struct State {
+    init: Option<Arc<Vec<u8>>>,
+    segments: VecDeque<Segment>,
+    next_msn: u64,
+    // No more parts are added after the egress is stopped.
+    ended: bool,
+}
+
+/// Segments produced by an egress, shared with the HTTP handlers.
+pub struct Stream {
+    part_target: Duration,
+    segment_target: Duration,
+    state: std::sync::Mutex<State>,
+    updated: watch::Sender<u64>,
+}
+
+impl Stream {
+    pub fn new(part_target: Duration, segment_target: Duration) -> Self {
+        let (updated, _) = watch::channel(0);
+        Self {
+            part_target,
+            segment_target,
+            state: std::sync::Mutex::new(State {
+                init: None,
+                segments: VecDeque::new(),
+                next_msn: 0,
+                ended: false,
+            }),
+            updated,
+        }
+    }
+
+    fn notify(&self) {
+        self.updated.send_modify(|v| *v += 1);
+    }
+
+    pub fn set_init(&self, init: Vec<u8>) {
+        self.state.lock().unwrap().init = Some(Arc::new(init));
+        self.notify();
+    }
+
+    pub fn push_part(&self, part: Part) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let open = state.segments.back().map(|s| !s.complete).unwrap_or(false);
+            if !open {
+                let msn = state.next_msn;
+                state.next_msn += 1;
+                state.segments.push_back(Segment {
+                    msn,
+                    parts: Vec::new(),
+                    complete: false,
+                });
+            }
+            if let Some(segment) = state.segments.back_mut() {
+                segment.parts.push(part);
+            }
+        }
+        self.notify();
+    }
+
+    pub fn finish_segment(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(segment) = state.segments.back_mut() {
+                segment.complete = true;
+            }
+            while state.segments.iter().filter(|s| s.complete).count() > WINDOW {
+                state.segments.pop_front();
+            }
+        }
+        self.notify();
+    }
+
+    /// Ends the playlist, and wakes up requests which wait for parts.
+    pub fn end(&self) {
+        self.state.lock().unwrap().ended = true;
+        self.notify();
+    }
+
+    pub fn init(&self) -> Option<Arc<Vec<u8>>> {
+        self.state.lock().unwrap().init.clone()
+    }
+
+    pub fn segment(&self, msn: u64) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .segments
+            .iter()
+            .find(|s| s.msn == msn && s.complete)
+            .map(|s| s.data())
+    }
+
+    pub fn part(&self, msn: u64, index: usize) -> Option<Arc<Vec<u8>>> {
+        let state = self.state.lock().unwrap();
+        state
+            .segments
+            .iter()
+            .find(|s| s.msn == msn)
+            .and_then(|s| s.parts.get(index))
+            .map(|p| p.data.clone())
+    }
+
+    // Whether the playlist already contains the part, or a later one.
+    fn has_part(&self, msn: u64, index: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        state.ended
+            || state
+                .segments
+                .iter()
+                .any(|s| s.msn > msn || (s.msn == msn && (s.complete || s.parts.len() > index)))
+    }
+
+    /// Whether a blocking reload asks for a segment more than MAX_MSN_AHEAD after the last one.
+    pub fn is_too_far(&self, msn: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        msn > state.next_msn.saturating_sub(1) + MAX_MSN_AHEAD
+    }
+
+    /// Waits until the part is available, for blocking playlist reloads and preload hints.
+    pub async fn wait_part(&self, msn: u64, index: usize) -> bool {
+        let mut updated = self.updated.subscribe();
+        let timeout = self.segment_target * 3;
+        let wait = async {
+            while !self.has_part(msn, index) {
+                if updated.changed().await.is_err() {
+                    return false;
+                }
+            }
+            true
+        };
+        tokio::time::timeout(timeout, wait).await.unwrap_or(false)
+    }
+
+    pub fn playlist(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let part_target = self.part_target.as_secs_f64();
+        let target_duration = state
+            .segments
+            .iter()
+            .map(|s| s.duration())
+            .fold(self.segment_target.as_secs_f64(), f64::max)
+            .ceil() as u64;
+        let first_msn = state.segments.front().map(|s| s.msn).unwrap_or(0);
+        let complete = state.segments.iter().filter(|s| s.complete).count();
+
+        let mut playlist = String::new();
+        let _ = writeln!(playlist, "#EXTM3U");
+        let _ = writeln!(playlist, "#EXT-X-VERSION:9");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration);
+        let _ = writeln!(
+            playlist,
+            "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+            part_target * 3.0
+        );
+        let _ = writeln!(playlist, "#EXT-X-PART-INF:PART-TARGET={:.3}", part_target);
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first_msn);
+        let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"init.mp4\"");
+
+        for (i, segment) in state.segments.iter().enumerate() {
+            if i + PART_WINDOW >= complete {
+                for (index, part) in segment.parts.iter().enumerate() {
+                    let _ = write!(
+                        playlist,
+                        "#EXT-X-PART:DURATION={:.3},URI=\"part{}.{}.m4s\"",
+                        part.duration, segment.msn, index
+                    );
+                    if part.independent {
+                        let _ = write!(playlist, ",INDEPENDENT=YES");
+                    }
+                    let _ = writeln!(playlist);
+                }
+            }
+            if segment.complete {
+                let _ = writeln!(playlist, "#EXTINF:{:.3},", segment.duration());
+                let _ = writeln!(playlist, "segment{}.m4s", segment.msn);
+            }
+        }
+
+        if state.ended {
+            let _ = writeln!(playlist, "#EXT-X-ENDLIST");
+            return playlist;
+        }
+        let (hint_msn, hint_part) = match state.segments.back() {
+            Some(s) if !s.complete => (s.msn, s.parts.len()),
+            _ => (state.next_msn, 0),
+        };
+        let _ = writeln!(
+            playlist,
+            "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"part{}.{}.m4s\"",
+            hint_msn, hint_part
+        );
+        playlist
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(independent: bool) -> Part {
+        Part {
+            duration: 0.5,
+            independent,
+            data: Arc::new(vec![1, 2]),
+        }
+    }
+
+    #[test]
+    fn renders_parts_of_latest_segments() {
+        let stream = Stream::new(Duration::from_millis(500), Duration::from_secs(2));
+        for _ in 0..3 {
+            stream.push_part(part(true));
+            stream.push_part(part(false));
+            stream.finish_segment();
+        }
+        stream.push_part(part(true));
+
+        let playlist = stream.playlist();
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:2\n"));
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(playlist.contains("#EXTINF:1.000,\nsegment0.m4s\n"));
+        assert!(!playlist.contains("part0.0.m4s"));
+        assert!(playlist.contains(
+            "#EXT-X-PART:DURATION=0.500,URI=\"part1.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.500,URI=\"part1.1.m4s\"\n"
+        ));
+        assert!(playlist.ends_with(
+            "#EXT-X-PART:DURATION=0.500,URI=\"part3.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"part3.1.m4s\"\n"
+        ));
+        assert_eq!(stream.segment(2).unwrap(), vec![1, 2, 1, 2]);
+        assert!(stream.segment(3).is_none());
+
+        assert!(stream.has_part(3, 0));
+        assert!(!stream.has_part(3, 1));
+        assert!(!stream.is_too_far(5));
+        assert!(stream.is_too_far(6));
+
+        stream.end();
+        assert!(stream.has_part(3, 1));
+        assert!(stream.playlist().ends_with("#EXT-X-ENDLIST\n"));
+    }
+}
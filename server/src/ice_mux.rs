@@ -0,0 +1,420 @@
+// Single-port ICE. Transports still bind their own ports, but only locally: clients are given the public IP and the
+// shared port in host candidates, and packets on it are forwarded to the local address of the transport whose ICE
+// username fragment is in the first STUN binding request of the client. ICE-TCP (RFC 6544) connections are forwarded to the transports over UDP.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::web::Data;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinHandle,
+};
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::sdp::session_description::RTCSessionDescription,
+    stun::{
+        attributes::ATTR_USERNAME,
+        message::{is_message, Message, BINDING_REQUEST},
+        textattrs::TextAttribute,
+    },
+};
+
+// Clients send consent checks every few seconds, so a client which is silent for this long has gone.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+const RECEIVE_MTU: usize = 8192;
+
+// Local addresses of transports by their username fragments.
+type Routes = Arc<Mutex<HashMap<String, Vec<SocketAddr>>>>;
+
+pub struct Mux {
+    udp_port: u16,
+    tcp_port: Option<u16>,
+    routes: Routes,
+    // Addresses which clients are given, since transports behind the mux do not announce them.
+    public_ips: Vec<IpAddr>,
+}
+
+impl Mux {
+    pub async fn bind(
+        udp_port: u16,
+        tcp_port: Option<u16>,
+        public_ips: Vec<IpAddr>,
+    ) -> std::io::Result<Self> {
+        let routes = Routes::default();
+        // Dual-stack sockets receive IPv4 too, where IPv6 is available.
+        let socket = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, udp_port)).await {
+            Ok(socket) => socket,
+            Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, udp_port)).await?,
+        };
+        tracing::info!("ICE is multiplexed on UDP port {}", udp_port);
+        actix::spawn(serve_udp(Arc::new(socket), routes.clone()));
+        if let Some(tcp_port) = tcp_port {
+            let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, tcp_port)).await {
+                Ok(listener) => listener,
+                Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, tcp_port)).await?,
+            };
+            tracing::info!("ICE-TCP is multiplexed on TCP port {}", tcp_port);
+            actix::spawn(serve_tcp(listener, routes.clone()));
+        }
+        Ok(Self {
+            udp_port,
+            tcp_port,
+            routes,
+            public_ips,
+        })
+    }
+
+    // Host candidates over UDP are moved to the public IP and the shared port, and ICE-TCP is offered on the same
+    // address. The local address in the original candidate is kept to forward packets to the transport.
+    // Transports bind a port on each interface, and one of them is enough for each IP version.
+    fn candidates(&self, username_fragment: &str, candidate: &str) -> Vec<String> {
+        let fields: Vec<&str> = candidate.split_whitespace().collect();
+        let [foundation, component, transport, priority, ip, port, "typ", "host", ..] =
+            fields.as_slice()
+        else {
+            return vec![candidate.to_owned()];
+        };
+        let address = match (ip.parse(), port.parse()) {
+            (Ok(ip), Ok(port)) if transport.eq_ignore_ascii_case("udp") => {
+                SocketAddr::new(ip, port)
+            }
+            _ => return vec![candidate.to_owned()],
+        };
+        let mut routes = self.routes.lock().unwrap();
+        let addresses = routes.entry(username_fragment.to_owned()).or_default();
+        if !addresses.contains(&address) {
+            if addresses.iter().any(|a| a.is_ipv4() == address.is_ipv4()) {
+                return Vec::new();
+            }
+            addresses.push(address);
+        }
+        let public_ip = self
+            .public_ips
+            .iter()
+            .find(|public_ip| public_ip.is_ipv4() == address.is_ipv4())
+            .map(|public_ip| public_ip.to_string())
+            .unwrap_or_else(|| ip.to_string());
+
+        let mut udp = fields.clone();
+        let udp_port = self.udp_port.to_string();
+        udp[4] = &public_ip;
+        udp[5] = &udp_port;
+        let mut candidates = vec![udp.join(" ")];
+        if let (Some(tcp_port), Ok(priority)) = (self.tcp_port, priority.parse::<u32>()) {
+            // One type preference lower than UDP.
+            candidates.push(format!(
+                "{}1 {} tcp {} {} {} typ host tcptype passive",
+                foundation,
+                component,
+                priority.saturating_sub(1 << 24),
+                public_ip,
+                tcp_port
+            ));
+        }
+        candidates
+    }
+}
+
+/// Credentials of a transport behind the mux. The transport is unreachable from clients once this is dropped.
+pub struct Route {
+    mux: Data<Mux>,
+    username_fragment: String,
+    password: String,
+}
+
+impl Route {
+    /// The route of a new transport. Its credentials have to be given to the transport.
+    pub fn new(mux: Data<Mux>) -> Self {
+        Self {
+            mux,
+            username_fragment: uuid::Uuid::new_v4().simple().to_string(),
+            password: uuid::Uuid::new_v4().simple().to_string(),
+        }
+    }
+
+    // Host candidates keep the local addresses of the transport, which the mux forwards to.
+    pub fn configure(&self, config: &mut rheomesh::config::WebRTCTransportConfig) {
+        config.announced_ips.clear();
+        config.ice_username_fragment = Some(self.username_fragment.clone());
+        config.ice_password = Some(self.password.clone());
+    }
+
+    /// Candidates which are sent to the client instead of the trickled one.
+    pub fn candidates(&self, candidate: RTCIceCandidateInit) -> Vec<RTCIceCandidateInit> {
+        self.mux
+            .candidates(&self.username_fragment, &candidate.candidate)
+            .into_iter()
+            .map(|c| RTCIceCandidateInit {
+                candidate: c,
+                ..candidate.clone()
+            })
+            .collect()
+    }
+
+    /// Rewrites the candidates in the SDP of an offer or an answer.
+    pub fn session_description(&self, mut sdp: RTCSessionDescription) -> RTCSessionDescription {
+        let mut lines = Vec::new();
+        for line in sdp.sdp.lines() {
+            match line.strip_prefix("a=") {
+                Some(candidate) if candidate.starts_with("candidate:") => lines.extend(
+                    self.mux
+                        .candidates(&self.username_fragment, candidate)
+                        .into_iter()
+                        .map(|c| format!("a={}", c)),
+                ),
+                _ => lines.push(line.to_owned()),
+            }
+        }
+        sdp.sdp = lines.join("\r\n") + "\r\n";
+        sdp
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        self.mux
+            .routes
+            .lock()
+            .unwrap()
+            .remove(&self.username_fragment);
+    }
+}
+
+// The local address of the transport which a STUN binding request is sent to.
+fn resolve(routes: &Routes, packet: &[u8], source: SocketAddr) -> Option<(String, SocketAddr)> {
+    if !is_message(packet) {
+        return None;
+    }
+    let mut message = Message::new();
+    message.raw = packet.to_vec();
+    message.decode().ok()?;
+    if message.typ != BINDING_REQUEST {
+        return None;
+    }
+    let username = TextAttribute::get_from_as(&message, ATTR_USERNAME).ok()?;
+    let (username_fragment, _) = username.text.split_once(':')?;
+    let routes = routes.lock().unwrap();
+    let addresses = routes.get(username_fragment)?;
+    let address = addresses
+        .iter()
+        .find(|a| a.is_ipv4() == source.ip().to_canonical().is_ipv4())
+        .or(addresses.first())?;
+    Some((username_fragment.to_owned(), *address))
+}
+
+// Packets are forwarded to the transport from a socket of each client, so the transport sees clients apart.
+async fn upstream(target: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = match target {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+    };
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+struct Session {
+    username_fragment: String,
+    upstream: Arc<UdpSocket>,
+    received_at: Instant,
+    downstream: JoinHandle<()>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.downstream.abort();
+    }
+}
+
+async fn serve_udp(socket: Arc<UdpSocket>, routes: Routes) {
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+    let mut swept_at = Instant::now();
+    let mut buf = vec![0u8; RECEIVE_MTU];
+    loop {
+        let (n, source) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                tracing::warn!("Failed to receive on the ICE mux: {}", err);
+                continue;
+            }
+        };
+        let packet = &buf[..n];
+        if swept_at.elapsed() > SESSION_TIMEOUT {
+            let routes = routes.lock().unwrap();
+            sessions.retain(|_, s| {
+                s.received_at.elapsed() < SESSION_TIMEOUT
+                    && routes.contains_key(&s.username_fragment)
+            });
+            swept_at = Instant::now();
+        }
+        // A new binding request decides the transport again, for clients which reuse their port.
+        if let Some((username_fragment, target)) = resolve(&routes, packet, source) {
+            let known = sessions
+                .get(&source)
+                .is_some_and(|s| s.username_fragment == username_fragment);
+            if !known {
+                match upstream(target).await {
+                    Ok(upstream) => {
+                        tracing::debug!("ICE mux forwards {} to {}", source, target);
+                        let upstream = Arc::new(upstream);
+                        let downstream =
+                            actix::spawn(forward_udp(upstream.clone(), socket.clone(), source));
+                        sessions.insert(
+                            source,
+                            Session {
+                                username_fragment,
+                                upstream,
+                                received_at: Instant::now(),
+                                downstream,
+                            },
+                        );
+                    }
+                    Err(err) => {
+                        tracing::error!("Failed to forward ICE to {}: {}", target, err);
+                        continue;
+                    }
+                }
+            }
+        }
+        let Some(session) = sessions.get_mut(&source) else {
+            tracing::trace!("Ignored a packet from {} on the ICE mux", source);
+            continue;
+        };
+        session.received_at = Instant::now();
+        if let Err(err) = session.upstream.send(packet).await {
+            tracing::trace!("Failed to forward a packet from {}: {}", source, err);
+        }
+    }
+}
+
+async fn forward_udp(upstream: Arc<UdpSocket>, socket: Arc<UdpSocket>, client: SocketAddr) {
+    let mut buf = vec![0u8; RECEIVE_MTU];
+    while let Ok(n) = upstream.recv(&mut buf).await {
+        if let Err(err) = socket.send_to(&buf[..n], client).await {
+            tracing::trace!("Failed to forward a packet to {}: {}", client, err);
+        }
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, routes: Routes) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, source)) => {
+                let routes = routes.clone();
+                actix::spawn(async move {
+                    if let Err(err) = forward_tcp(stream, source, routes).await {
+                        tracing::debug!("ICE-TCP connection from {} is closed: {}", source, err);
+                    }
+                });
+            }
+            Err(err) => tracing::warn!("Failed to accept ICE-TCP: {}", err),
+        }
+    }
+}
+
+// Packets are framed with their length on TCP (RFC 4571).
+async fn read_frame(stream: &mut (impl AsyncReadExt + Unpin)) -> std::io::Result<Vec<u8>> {
+    let length = stream.read_u16().await?;
+    let mut frame = vec![0u8; length as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn forward_tcp(stream: TcpStream, source: SocketAddr, routes: Routes) -> std::io::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let first = read_frame(&mut reader).await?;
+    let Some((_, target)) = resolve(&routes, &first, source) else {
+        return Err(std::io::Error::other(
+            "the first packet is not a known binding request",
+        ));
+    };
+    tracing::debug!("ICE mux forwards TCP {} to {}", source, target);
+    let upstream = Arc::new(upstream(target).await?);
+    upstream.send(&first).await?;
+
+    let receiver = upstream.clone();
+    let downstream = actix::spawn(async move {
+        let mut buf = vec![0u8; RECEIVE_MTU];
+        while let Ok(n) = receiver.recv(&mut buf).await {
+            let mut frame = (n as u16).to_be_bytes().to_vec();
+            frame.extend_from_slice(&buf[..n]);
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let result = loop {
+        match read_frame(&mut reader).await {
+            Ok(frame) => {
+                if let Err(err) = upstream.send(&frame).await {
+                    break Err(err);
+                }
+            }
+            Err(err) => break Err(err),
+        }
+    };
+    downstream.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_candidates_are_moved_to_the_shared_port() {
+        let mux = Data::new(Mux {
+            udp_port: 3478,
+            tcp_port: Some(3478),
+            routes: Routes::default(),
+            public_ips: vec!["203.0.113.1".parse().unwrap()],
+        });
+        let route = Route::new(mux.clone());
+        let candidate = |candidate: &str| {
+            route
+                .candidates(RTCIceCandidateInit {
+                    candidate: candidate.to_owned(),
+                    sdp_mid: Some("0".to_owned()),
+                    sdp_mline_index: Some(0),
+                    username_fragment: None,
+                })
+                .into_iter()
+                .map(|c| c.candidate)
+                .collect::<Vec<String>>()
+        };
+        let expected = vec![
+            "candidate:1 1 udp 2130706431 203.0.113.1 3478 typ host",
+            "candidate:11 1 tcp 2113929215 203.0.113.1 3478 typ host tcptype passive",
+        ];
+        assert_eq!(
+            candidate("candidate:1 1 udp 2130706431 10.0.0.5 31300 typ host"),
+            expected
+        );
+        // The same candidate in the SDP is rewritten again, and the other interfaces are left out.
+        assert_eq!(
+            candidate("candidate:1 1 udp 2130706431 10.0.0.5 31300 typ host"),
+            expected
+        );
+        assert!(candidate("candidate:2 1 udp 2130706431 172.17.0.1 31301 typ host").is_empty());
+        // There is no public IPv6 address, so the local one is given.
+        assert_eq!(
+            candidate("candidate:3 1 udp 2130706431 2001:db8::5 31302 typ host")[0],
+            "candidate:3 1 udp 2130706431 2001:db8::5 3478 typ host"
+        );
+        let relay = "candidate:4 1 udp 16777215 198.51.100.1 3478 typ relay raddr 0.0.0.0 rport 0";
+        assert_eq!(route.mux.candidates("x", relay), vec![relay]);
+        assert_eq!(
+            mux.routes.lock().unwrap()[&route.username_fragment],
+            vec![
+                "10.0.0.5:31300".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::5]:31302".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+        drop(route);
+        assert!(mux.routes.lock().unwrap().is_empty());
+    }
+}
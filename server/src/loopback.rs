@@ -0,0 +1,391 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use rheomesh::{
+    error::{Error, PublisherErrorKind, SubscriberErrorKind, TransportErrorKind},
+    publish_transport::PublishTransport,
+    publisher::Publisher,
+    subscribe_transport::SubscribeTransport,
+    subscriber::Subscriber,
+};
+use tokio::sync::{oneshot, Mutex};
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors,
+        media_engine::{MediaEngine, MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU},
+        APIBuilder,
+    },
+    interceptor::registry::Registry,
+    peer_connection::{
+        configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription,
+        RTCPeerConnection,
+    },
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+    rtp_transceiver::{
+        rtp_codec::{
+            RTCRtpCodecCapability, RTCRtpCodecParameters, RTCRtpHeaderExtensionCapability,
+            RTPCodecType,
+        },
+        rtp_sender::RTCRtpSender,
+    },
+    sdp::extmap::AUDIO_LEVEL_URI,
+    track::{track_local::TrackLocal, track_remote::TrackRemote},
+};
+
+use crate::{config, room::Room};
+
+const TRACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Loopback peers are WebRTC clients living inside this process. They connect to a room's router through
+// regular rheomesh transports, so server-side features can receive and send media like any other participant.
+async fn new_peer_connection(
+    mut media_engine: MediaEngine,
+) -> Result<Arc<RTCPeerConnection>, Error> {
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+    let api = APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .build();
+    let peer_connection = api.new_peer_connection(RTCConfiguration::default()).await?;
+    Ok(Arc::new(peer_connection))
+}
+
+async fn local_description(
+    peer_connection: &RTCPeerConnection,
+) -> Result<RTCSessionDescription, Error> {
+    peer_connection.local_description().await.ok_or_else(|| {
+        Error::new_transport(
+            "Failed to get local description".to_owned(),
+            TransportErrorKind::LocalDescriptionError,
+        )
+    })
+}
+
+/// A peer which receives published tracks from a room.
+pub struct LoopbackSubscriber {
+    transport: Arc<SubscribeTransport>,
+    peer_connection: Arc<RTCPeerConnection>,
+    signaling: Arc<Mutex<()>>,
+    waiting_tracks: Arc<std::sync::Mutex<HashMap<String, oneshot::Sender<Arc<TrackRemote>>>>>,
+    subscribers: Mutex<HashMap<String, Arc<Mutex<Subscriber>>>>,
+}
+
+impl LoopbackSubscriber {
+    pub async fn new(room: &Room) -> Result<Self, Error> {
+        let transport = {
+            let router = room.router.lock().await;
+            router
+                .create_subscribe_transport(config::transport_config())
+                .await
+        };
+        let transport = Arc::new(transport);
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs()?;
+        // Audio levels of publishers are forwarded by the router.
+        media_engine.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: AUDIO_LEVEL_URI.to_owned(),
+            },
+            RTPCodecType::Audio,
+            None,
+        )?;
+        let peer_connection = new_peer_connection(media_engine).await?;
+        let signaling = Arc::new(Mutex::new(()));
+        let waiting_tracks = Arc::new(std::sync::Mutex::new(HashMap::<
+            String,
+            oneshot::Sender<Arc<TrackRemote>>,
+        >::new()));
+
+        {
+            let waiting_tracks = waiting_tracks.clone();
+            peer_connection.on_track(Box::new(move |track, _receiver, _transceiver| {
+                let waiting = waiting_tracks.lock().unwrap().remove(&track.id());
+                Box::pin(async move {
+                    if let Some(sender) = waiting {
+                        let _ = sender.send(track);
+                    }
+                })
+            }));
+        }
+
+        {
+            let pc = Arc::downgrade(&peer_connection);
+            let t = Arc::downgrade(&transport);
+            let signaling = signaling.clone();
+            transport
+                .on_negotiation_needed(Box::new(move |offer| {
+                    let pc = pc.clone();
+                    let t = t.clone();
+                    let signaling = signaling.clone();
+                    tokio::spawn(async move {
+                        if let (Some(pc), Some(t)) = (pc.upgrade(), t.upgrade()) {
+                            if let Err(err) = Self::answer(&pc, &t, &signaling, offer).await {
+                                tracing::error!("Failed to answer loopback offer: {}", err);
+                            }
+                        }
+                    });
+                }))
+                .await;
+        }
+
+        Ok(Self {
+            transport,
+            peer_connection,
+            signaling,
+            waiting_tracks,
+            subscribers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn answer(
+        peer_connection: &RTCPeerConnection,
+        transport: &SubscribeTransport,
+        signaling: &Mutex<()>,
+        offer: RTCSessionDescription,
+    ) -> Result<(), Error> {
+        let _guard = signaling.lock().await;
+        // Offers from SubscribeTransport already contain every candidate, so the answer is sent after gathering.
+        peer_connection.set_remote_description(offer).await?;
+        let answer = peer_connection.create_answer(None).await?;
+        let mut gathering_complete = peer_connection.gathering_complete_promise().await;
+        peer_connection.set_local_description(answer).await?;
+        let _ = gathering_complete.recv().await;
+        let answer = local_description(peer_connection).await?;
+        transport.set_answer(answer).await
+    }
+
+    /// Subscribes the publisher and returns the received track once media starts flowing.
+    pub async fn subscribe(&self, publisher_id: &str) -> Result<Arc<TrackRemote>, Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.waiting_tracks
+            .lock()
+            .unwrap()
+            .insert(publisher_id.to_owned(), sender);
+
+        let (subscriber, offer) = self.transport.subscribe(publisher_id.to_owned()).await?;
+        self.subscribers
+            .lock()
+            .await
+            .insert(publisher_id.to_owned(), subscriber);
+        Self::answer(
+            &self.peer_connection,
+            &self.transport,
+            &self.signaling,
+            offer,
+        )
+        .await?;
+
+        match tokio::time::timeout(TRACK_TIMEOUT, receiver).await {
+            Ok(Ok(track)) => Ok(track),
+            _ => {
+                self.waiting_tracks.lock().unwrap().remove(publisher_id);
+                Err(Error::new_subscriber(
+                    format!("Track for {} is not received", publisher_id),
+                    SubscriberErrorKind::TrackNotFoundError,
+                ))
+            }
+        }
+    }
+
+    /// Asks the publisher of the track to send a keyframe.
+    pub async fn request_keyframe(&self, track: &TrackRemote) {
+        let pli = PictureLossIndication {
+            sender_ssrc: 0,
+            media_ssrc: track.ssrc(),
+        };
+        if let Err(err) = self.peer_connection.write_rtcp(&[Box::new(pli)]).await {
+            tracing::error!("Failed to send PLI: {}", err);
+        }
+    }
+
+    pub async fn unsubscribe(&self, publisher_id: &str) {
+        let removed = self.subscribers.lock().await.remove(publisher_id);
+        if let Some(subscriber) = removed {
+            subscriber.lock().await.close().await;
+        }
+    }
+
+    pub async fn close(&self) {
+        let mut subscribers = self.subscribers.lock().await;
+        for (_, subscriber) in subscribers.drain() {
+            let subscriber = subscriber.lock().await;
+            subscriber.close().await;
+        }
+        if let Err(err) = self.transport.close().await {
+            tracing::error!("Failed to close loopback subscribe transport: {}", err);
+        }
+        if let Err(err) = self.peer_connection.close().await {
+            tracing::error!("Failed to close loopback peer connection: {}", err);
+        }
+    }
+}
+
+/// The mime type of a received track. webrtc-rs leaves the codec empty for static payload types of RFC 3551.
+pub fn mime_type(track: &TrackRemote) -> String {
+    match (track.codec().capability.mime_type, track.payload_type()) {
+        (mime_type, _) if !mime_type.is_empty() => mime_type,
+        (_, 0) => MIME_TYPE_PCMU.to_owned(),
+        (_, 8) => MIME_TYPE_PCMA.to_owned(),
+        (mime_type, _) => mime_type,
+    }
+}
+
+// webrtc-rs treats payload type 0 as unknown, so the router can not forward PCMU with its static payload type.
+// Locally published audio uses dynamic payload types instead.
+fn publisher_media_engine() -> Result<MediaEngine, Error> {
+    let mut media_engine = MediaEngine::default();
+    for (mime_type, clock_rate, channels, sdp_fmtp_line, payload_type) in [
+        (MIME_TYPE_OPUS, 48000, 2, "minptime=10;useinbandfec=1", 111),
+        (MIME_TYPE_PCMU, 8000, 0, "", 110),
+        (MIME_TYPE_PCMA, 8000, 0, "", 119),
+    ] {
+        media_engine.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: mime_type.to_owned(),
+                    clock_rate,
+                    channels,
+                    sdp_fmtp_line: sdp_fmtp_line.to_owned(),
+                    rtcp_feedback: vec![],
+                },
+                payload_type,
+                ..Default::default()
+            },
+            RTPCodecType::Audio,
+        )?;
+    }
+    Ok(media_engine)
+}
+
+type PublishedTrack = (Arc<RTCRtpSender>, Arc<Mutex<Publisher>>);
+
+/// A peer which publishes locally generated tracks to a room.
+pub struct LoopbackPublisher {
+    transport: Arc<PublishTransport>,
+    peer_connection: Arc<RTCPeerConnection>,
+    signaling: Mutex<()>,
+    published: Mutex<HashMap<String, PublishedTrack>>,
+}
+
+impl LoopbackPublisher {
+    pub async fn new(room: &Arc<Room>) -> Result<Self, Error> {
+        let mut transport = {
+            let router = room.router.lock().await;
+            router
+                .create_publish_transport(config::transport_config())
+                .await
+        };
+        let r = Arc::downgrade(room);
+        transport
+            .on_track(Box::new(move |track, _, _| {
+                if let Some(room) = r.upgrade() {
+                    room.set_track_kind(track.id(), track.kind());
+                }
+            }))
+            .await;
+        let transport = Arc::new(transport);
+        let peer_connection = new_peer_connection(publisher_media_engine()?).await?;
+
+        // PublishTransport answers before gathering, so its candidates have to be trickled.
+        let pc: Weak<RTCPeerConnection> = Arc::downgrade(&peer_connection);
+        transport
+            .on_ice_candidate(Box::new(move |candidate| {
+                let pc = pc.clone();
+                tokio::spawn(async move {
+                    let Ok(init) = candidate.to_json() else {
+                        return;
+                    };
+                    while let Some(pc) = pc.upgrade() {
+                        if pc.remote_description().await.is_some() {
+                            if let Err(err) = pc.add_ice_candidate(init).await {
+                                tracing::error!("Failed to add loopback ICE candidate: {}", err);
+                            }
+                            return;
+                        }
+                        drop(pc);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                });
+            }))
+            .await;
+
+        Ok(Self {
+            transport,
+            peer_connection,
+            signaling: Mutex::new(()),
+            published: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Publishes the track to the room. RTP packets should already be written to the track, because
+    /// the publisher is created only after the router receives media.
+    pub async fn publish(
+        &self,
+        track: Arc<dyn TrackLocal + Send + Sync>,
+    ) -> Result<Arc<Mutex<Publisher>>, Error> {
+        let track_id = track.id().to_owned();
+        let sender = {
+            let _guard = self.signaling.lock().await;
+            let sender = self.peer_connection.add_track(track).await?;
+            {
+                // Interceptors only process RTCP while somebody reads it.
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 1500];
+                    while sender.read(&mut buf).await.is_ok() {}
+                });
+            }
+            let offer = self.peer_connection.create_offer(None).await?;
+            let mut gathering_complete = self.peer_connection.gathering_complete_promise().await;
+            self.peer_connection.set_local_description(offer).await?;
+            let _ = gathering_complete.recv().await;
+            let offer = local_description(&self.peer_connection).await?;
+            let answer = self.transport.get_answer(offer).await?;
+            self.peer_connection.set_remote_description(answer).await?;
+            sender
+        };
+
+        let publisher =
+            tokio::time::timeout(TRACK_TIMEOUT, self.transport.publish(track_id.clone()))
+                .await
+                .map_err(|_| {
+                    Error::new_publisher(
+                        format!("Track {} is not published", track_id),
+                        PublisherErrorKind::TrackNotPublishedError,
+                    )
+                })??;
+        self.published
+            .lock()
+            .await
+            .insert(track_id, (sender, publisher.clone()));
+        Ok(publisher)
+    }
+
+    // Only the mixer removes single tracks.
+    #[cfg(feature = "opus")]
+    pub async fn unpublish(&self, track_id: &str) {
+        let removed = self.published.lock().await.remove(track_id);
+        if let Some((sender, publisher)) = removed {
+            publisher.lock().await.close().await;
+            if let Err(err) = self.peer_connection.remove_track(&sender).await {
+                tracing::error!("Failed to remove loopback track: {}", err);
+            }
+        }
+    }
+
+    pub async fn close(&self) {
+        let mut published = self.published.lock().await;
+        for (_, (_, publisher)) in published.drain() {
+            publisher.lock().await.close().await;
+        }
+        if let Err(err) = self.transport.close().await {
+            tracing::error!("Failed to close loopback publish transport: {}", err);
+        }
+        if let Err(err) = self.peer_connection.close().await {
+            tracing::error!("Failed to close loopback peer connection: {}", err);
+        }
+    }
+}
@@ -1,14 +1,39 @@
 use std::collections::HashMap;
 
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
 use actix_web::web::{Data, Query};
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use actix_web_actors::ws;
 use tokio::sync::Mutex;
 use tracing_actix_web::TracingLogger;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+mod access_log;
+mod admin;
+#[cfg(feature = "opus")]
+mod audio;
+mod audit;
+mod chat;
+mod cluster;
+mod config;
+mod congestion;
+mod encoding;
+mod hls;
+mod ice_mux;
+mod loopback;
+mod media;
+#[cfg(feature = "opus")]
+mod mixer;
+mod protocol;
 mod room;
+mod settings;
+mod sip;
+mod speaker;
+mod stats;
+mod subscription;
+mod turn;
+mod webhook;
 mod websocket;
 
 #[actix_web::main]
@@ -21,19 +46,93 @@ async fn main() -> std::io::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let room_owner = room::RoomOwner::new().await;
+    if config::admin_token().is_none() {
+        tracing::warn!("ADMIN_TOKEN is not given, so control endpoints are disabled");
+    }
+
+    let store = settings::Store::open(&config::database_path()).expect("failed to open database");
+    let store = Data::new(store);
+    let room_owner = room::RoomOwner::new(store.clone().into_inner()).await;
     let room_data = Data::new(Mutex::new(room_owner));
 
+    let mux = match config::ice_udp_port() {
+        Some(port) => Some(Data::new(
+            ice_mux::Mux::bind(port, config::ice_tcp_port(), config::public_ips()).await?,
+        )),
+        None => None,
+    };
+
+    let turn = match config::turn_port() {
+        Some(port) => Some(Data::new(turn::Turn::start(port).await?)),
+        None => None,
+    };
+
+    if let Some(port) = config::sip_port() {
+        let ip = config::public_ip().ok_or_else(|| {
+            std::io::Error::other("SIP_PORT requires an IPv4 address in PUBLIC_IP")
+        })?;
+        let owner = room_data.clone();
+        actix::spawn(async move {
+            if let Err(err) = sip::serve(port, ip, owner).await {
+                tracing::error!("SIP gateway is stopped: {}", err);
+            }
+        });
+    }
+
+    let ice_servers = turn.clone();
     HttpServer::new(move || {
-        App::new()
-            .wrap(TracingLogger::default())
+        let app = App::new()
+            .wrap(TracingLogger::<access_log::RedactedSpan>::new())
             .service(index)
             .app_data(room_data.clone())
+            .app_data(store.clone())
             .route("/socket", web::get().to(socket))
+            .route("/cluster", web::post().to(cluster::relay::receive))
+            .service(
+                web::scope("/rooms")
+                    .wrap(from_fn(admin::authorize))
+                    .route("", web::get().to(settings::list))
+                    .route("/{room}", web::get().to(settings::get))
+                    .route("/{room}", web::put().to(settings::put))
+                    .route("/{room}", web::delete().to(settings::delete)),
+            )
+            .service(
+                web::resource("/stats/{room}")
+                    .wrap(from_fn(admin::authorize))
+                    .route(web::get().to(stats::get)),
+            )
+            .service(
+                web::resource("/hls/{room}")
+                    .wrap(from_fn(admin::authorize))
+                    .route(web::post().to(hls::start))
+                    .route(web::delete().to(hls::stop)),
+            )
+            .route("/hls/{room}/{file}", web::get().to(hls::file));
+        let app = match mux.clone() {
+            Some(mux) => app.app_data(mux),
+            None => app,
+        };
+        let app = match ice_servers.clone() {
+            Some(turn) => app.app_data(turn),
+            None => app,
+        };
+        #[cfg(feature = "opus")]
+        let app = app.service(
+            web::resource("/mixer/{room}")
+                .wrap(from_fn(admin::authorize))
+                .route(web::post().to(mixer::start))
+                .route(web::delete().to(mixer::stop)),
+        );
+        app
     })
-    .bind("0.0.0.0:4000")?
+    .bind(("0.0.0.0", config::port()))?
     .run()
-    .await
+    .await?;
+
+    if let Some(turn) = turn {
+        turn.close().await;
+    }
+    Ok(())
 }
 
 #[actix_web::get("/")]
@@ -44,6 +143,8 @@ async fn index() -> impl Responder {
 async fn socket(
     req: HttpRequest,
     room_owner: Data<Mutex<room::RoomOwner>>,
+    mux: Option<Data<ice_mux::Mux>>,
+    turn: Option<Data<turn::Turn>>,
     stream: web::Payload,
 ) -> impl Responder {
     let query = req.query_string();
@@ -51,26 +152,81 @@ async fn socket(
     let parameters =
         Query::<HashMap<String, String>>::from_query(query).expect("Failed to parse query");
     let room_id = parameters.get("room").expect("room is required");
-    let find = room_owner
-        .as_ref()
-        .lock()
-        .await
-        .find_by_id(room_id.to_string());
+    let protocols = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok());
+    let (encoding, protocol) =
+        encoding::Encoding::negotiate(parameters.get("encoding").map(String::as_str), protocols);
+    let profile = match parameters.get("profile") {
+        Some(profile) => match media::Profile::parse(profile) {
+            Some(profile) => profile,
+            None => return Ok(HttpResponse::BadRequest().body("unknown profile")),
+        },
+        None => media::Profile::Default,
+    };
+    let password = parameters.get("password");
+    // A room which does not exist is created with these options.
+    let options = room::RoomOptions {
+        profile,
+        password: password.cloned(),
+        lobby: parameters.get("lobby").is_some_and(|lobby| lobby == "true"),
+    };
+    let placement = room::find_or_create_room(&room_owner, room_id.to_string(), options).await;
+    let cluster = room_owner.lock().await.cluster.clone();
 
-    let config = rheomesh::config::MediaConfig::default();
-
-    match find {
-        Some(room) => {
-            tracing::info!("Room found, so joining it: {}", room_id);
-            let server = websocket::WebSocket::new(room, room_owner.clone()).await;
-            ws::start(server, &req, stream)
+    match placement {
+        Ok(room::Placement::Local(room)) => {
+            // Passwords are hashed with many iterations, so they are not checked on the worker of the server.
+            let admission = {
+                let room = room.clone();
+                let password = password.cloned();
+                let moderator = parameters.get("moderator").cloned();
+                web::block(move || room.admit(password.as_deref(), moderator.as_deref())).await?
+            };
+            let reservation = match admission {
+                Ok(reservation) => reservation,
+                Err(rejection) => {
+                    tracing::warn!("Rejected from room {}: {}", room_id, rejection);
+                    if room.is_empty() {
+                        room::close(&room_owner, &room).await;
+                    }
+                    let mut response = match rejection {
+                        settings::Rejection::WrongPassword => HttpResponse::Unauthorized(),
+                        settings::Rejection::Full | settings::Rejection::Lobby => {
+                            HttpResponse::Forbidden()
+                        }
+                    };
+                    return Ok(response.body(rejection.to_string()));
+                }
+            };
+            let moderator = reservation.moderator;
+            // Participants in the lobby are counted when they are admitted.
+            let reservation = (!room.settings.lobby || moderator).then_some(reservation);
+            tracing::info!("Joining room: {}", room_id);
+            let server = websocket::WebSocket::new(
+                room,
+                room_owner.clone(),
+                mux,
+                turn,
+                encoding,
+                moderator,
+                reservation,
+            );
+            websocket::start_socket(server, &req, stream, protocol)
+        }
+        Ok(room::Placement::Remote(node_url)) => {
+            // The home node may not know yet that it has the room, so requests are not routed twice.
+            let cluster = cluster.expect("remote room without cluster");
+            if req.headers().contains_key(cluster::route::PROXIED_HEADER) {
+                tracing::warn!("Room {} is on {}, not on this node", room_id, node_url);
+                return Ok(HttpResponse::ServiceUnavailable().finish());
+            }
+            cluster::route::start(cluster.routing, &node_url, &req, stream, encoding, protocol)
         }
-        None => {
-            let owner = room_owner.clone();
-            let mut owner = owner.lock().await;
-            let room = owner.create_new_room(room_id.to_string(), config).await;
-            let server = websocket::WebSocket::new(room, room_owner.clone()).await;
-            ws::start(server, &req, stream)
+        Err(err) => {
+            tracing::error!("Failed to place room {}: {}", room_id, err);
+            Ok(HttpResponse::ServiceUnavailable().finish())
         }
     }
 }
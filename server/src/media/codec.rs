@@ -0,0 +1,123 @@
+// Codecs which rooms can choose from. They are the default codecs of webrtc-rs, which the router registers when a
+// room does not restrict codecs.
+use rheomesh::config::{CodecConfig, MediaConfig};
+use webrtc::{
+    api::media_engine::{
+        MIME_TYPE_AV1, MIME_TYPE_G722, MIME_TYPE_H264, MIME_TYPE_HEVC, MIME_TYPE_OPUS,
+        MIME_TYPE_PCMA, MIME_TYPE_PCMU, MIME_TYPE_VP8, MIME_TYPE_VP9,
+    },
+    rtp_transceiver::{
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters},
+        RTCPFeedback,
+    },
+};
+
+const AUDIO_CODECS: [(&str, u32, u16, &str, u8); 4] = [
+    (MIME_TYPE_OPUS, 48000, 2, "minptime=10;useinbandfec=1", 111),
+    (MIME_TYPE_G722, 8000, 0, "", 9),
+    (MIME_TYPE_PCMU, 8000, 0, "", 0),
+    (MIME_TYPE_PCMA, 8000, 0, "", 8),
+];
+
+const VIDEO_CODECS: [(&str, &str, u8); 11] = [
+    (MIME_TYPE_VP8, "", 96),
+    (MIME_TYPE_VP9, "profile-id=0", 98),
+    (MIME_TYPE_VP9, "profile-id=1", 100),
+    (
+        MIME_TYPE_H264,
+        "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f",
+        102,
+    ),
+    (
+        MIME_TYPE_H264,
+        "level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=42001f",
+        127,
+    ),
+    (
+        MIME_TYPE_H264,
+        "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f",
+        125,
+    ),
+    (
+        MIME_TYPE_H264,
+        "level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=42e01f",
+        108,
+    ),
+    (
+        MIME_TYPE_H264,
+        "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640032",
+        123,
+    ),
+    (MIME_TYPE_AV1, "profile-id=0", 41),
+    (MIME_TYPE_HEVC, "", 126),
+    ("video/ulpfec", "", 116),
+];
+
+pub fn is_known(mime_type: &str) -> bool {
+    AUDIO_CODECS
+        .iter()
+        .map(|(m, ..)| m)
+        .chain(VIDEO_CODECS.iter().map(|(m, ..)| m))
+        .any(|m| m.eq_ignore_ascii_case(mime_type))
+}
+
+/// Media configuration which only has the given codecs. All codecs are registered when the list is empty.
+pub fn media_config(codecs: &[String]) -> MediaConfig {
+    let mut config = MediaConfig::default();
+    if codecs.is_empty() {
+        return config;
+    }
+    let included = |mime_type: &str| codecs.iter().any(|c| c.eq_ignore_ascii_case(mime_type));
+    let audio = AUDIO_CODECS
+        .iter()
+        .filter(|(mime_type, ..)| included(mime_type))
+        .map(|&(mime_type, clock_rate, channels, fmtp, payload_type)| {
+            parameters(mime_type, clock_rate, channels, fmtp, vec![], payload_type)
+        })
+        .collect();
+    let video = VIDEO_CODECS
+        .iter()
+        .filter(|(mime_type, ..)| included(mime_type))
+        .map(|&(mime_type, fmtp, payload_type)| {
+            parameters(mime_type, 90000, 0, fmtp, video_feedback(), payload_type)
+        })
+        .collect();
+    config.codec = CodecConfig { audio, video };
+    config
+}
+
+fn parameters(
+    mime_type: &str,
+    clock_rate: u32,
+    channels: u16,
+    sdp_fmtp_line: &str,
+    rtcp_feedback: Vec<RTCPFeedback>,
+    payload_type: u8,
+) -> RTCRtpCodecParameters {
+    RTCRtpCodecParameters {
+        capability: RTCRtpCodecCapability {
+            mime_type: mime_type.to_owned(),
+            clock_rate,
+            channels,
+            sdp_fmtp_line: sdp_fmtp_line.to_owned(),
+            rtcp_feedback,
+        },
+        payload_type,
+        ..Default::default()
+    }
+}
+
+fn video_feedback() -> Vec<RTCPFeedback> {
+    [
+        ("goog-remb", ""),
+        ("ccm", "fir"),
+        ("nack", ""),
+        ("nack", "pli"),
+    ]
+    .into_iter()
+    .map(|(typ, parameter)| RTCPFeedback {
+        typ: typ.to_owned(),
+        parameter: parameter.to_owned(),
+    })
+    .collect()
+}
@@ -0,0 +1,159 @@
+// Media profiles of rooms. A profile fixes the codecs of the router when the room is created, and tells publishers
+// how to encode video.
+use serde::{Deserialize, Serialize};
+use webrtc::api::media_engine::{
+    MIME_TYPE_AV1, MIME_TYPE_G722, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU,
+    MIME_TYPE_VP9,
+};
+
+use crate::settings::RoomSettings;
+
+pub mod codec;
+
+/// A set of codecs and encodings which suits some clients.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Profile {
+    #[default]
+    Default,
+    AudioOnly,
+    // Safari decodes H.264 in hardware.
+    H264,
+    Vp9Svc,
+    Av1,
+}
+
+impl Profile {
+    pub fn parse(profile: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(profile.to_owned())).ok()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Default => "default",
+            Profile::AudioOnly => "audioOnly",
+            Profile::H264 => "h264",
+            Profile::Vp9Svc => "vp9Svc",
+            Profile::Av1 => "av1",
+        }
+    }
+
+    // All codecs are available when it is empty.
+    fn codecs(&self) -> &'static [&'static str] {
+        match self {
+            Profile::Default => &[],
+            Profile::AudioOnly => &[
+                MIME_TYPE_OPUS,
+                MIME_TYPE_G722,
+                MIME_TYPE_PCMU,
+                MIME_TYPE_PCMA,
+            ],
+            Profile::H264 => &[MIME_TYPE_OPUS, MIME_TYPE_H264],
+            Profile::Vp9Svc => &[MIME_TYPE_OPUS, MIME_TYPE_VP9],
+            Profile::Av1 => &[MIME_TYPE_OPUS, MIME_TYPE_AV1],
+        }
+    }
+
+    // SVC codecs send the layers in one stream, so they do not need simulcast.
+    fn simulcast(&self) -> bool {
+        !matches!(self, Profile::AudioOnly | Profile::Vp9Svc)
+    }
+
+    fn scalability_mode(&self) -> Option<&'static str> {
+        match self {
+            Profile::Vp9Svc => Some("L3T3_KEY"),
+            _ => None,
+        }
+    }
+}
+
+/// Media configuration of a room, which is sent to participants when they join.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Media {
+    pub profile: Profile,
+    // MIME types which the room accepts. All codecs are accepted when it is empty.
+    pub codecs: Vec<String>,
+    // Publishers send video in simulcast encodings when it is true, otherwise in a single encoding.
+    pub simulcast: bool,
+    // Upper limit of each video publisher in bits per second.
+    pub max_bitrate: Option<u32>,
+    // Scalability mode of the encoding, like L3T3_KEY, when the codec is scalable.
+    pub scalability_mode: Option<String>,
+}
+
+impl Media {
+    /// Settings of a persistent room override the profile which is requested by the participant creating the room.
+    pub fn new(requested: Profile, settings: &RoomSettings) -> Self {
+        let profile = settings.profile.unwrap_or(requested);
+        let codecs = match (profile.codecs(), settings.allowed_codecs.as_slice()) {
+            (codecs, []) => codecs.iter().map(|c| c.to_string()).collect(),
+            ([], allowed) => allowed.to_vec(),
+            (codecs, allowed) => codecs
+                .iter()
+                .filter(|c| allowed.iter().any(|a| a.eq_ignore_ascii_case(c)))
+                .map(|c| c.to_string())
+                .collect(),
+        };
+        Self {
+            profile,
+            codecs,
+            simulcast: settings.simulcast.unwrap_or(profile.simulcast()),
+            max_bitrate: settings.max_bitrate,
+            scalability_mode: profile.scalability_mode().map(str::to_owned),
+        }
+    }
+
+    pub fn media_config(&self) -> rheomesh::config::MediaConfig {
+        codec::media_config(&self.codecs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(value: serde_json::Value) -> RoomSettings {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn profiles_are_selected() {
+        let media = Media::new(Profile::H264, &RoomSettings::default());
+        assert_eq!(media.codecs, vec![MIME_TYPE_OPUS, MIME_TYPE_H264]);
+        assert!(media.simulcast);
+        let config = media.media_config();
+        assert_eq!(config.codec.audio.len(), 1);
+        assert_eq!(config.codec.video.len(), 5);
+
+        let media = Media::new(Profile::Default, &RoomSettings::default());
+        assert!(media.codecs.is_empty());
+        assert!(media.media_config().codec.video.is_empty());
+
+        let media = Media::new(Profile::Vp9Svc, &RoomSettings::default());
+        assert!(!media.simulcast);
+        assert_eq!(media.scalability_mode.as_deref(), Some("L3T3_KEY"));
+        assert_eq!(Profile::parse("vp9Svc"), Some(Profile::Vp9Svc));
+        assert_eq!(Profile::parse("vp10"), None);
+    }
+
+    #[test]
+    fn room_settings_override_profiles() {
+        let room = settings(serde_json::json!({
+            "profile": "av1",
+            "allowedCodecs": ["audio/opus"],
+            "simulcast": false,
+            "maxBitrate": 1_000_000,
+        }));
+        let media = Media::new(Profile::H264, &room);
+        assert_eq!(media.profile, Profile::Av1);
+        assert_eq!(media.codecs, vec![MIME_TYPE_OPUS]);
+        assert!(!media.simulcast);
+        assert_eq!(media.max_bitrate, Some(1_000_000));
+
+        let room = settings(serde_json::json!({ "allowedCodecs": ["video/VP8"] }));
+        let media = Media::new(Profile::Default, &room);
+        assert_eq!(media.codecs, vec!["video/VP8"]);
+        assert!(media.simulcast);
+    }
+}
@@ -0,0 +1,509 @@
+// Server-side audio mixing, so participants can receive one audio track instead of one per publisher.
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{web, HttpResponse, Responder};
+use tokio::{sync::Mutex, task::JoinHandle};
+use webrtc::{
+    api::media_engine::{MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU},
+    media::Sample,
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::{
+        track_local::track_local_static_sample::TrackLocalStaticSample, track_remote::TrackRemote,
+    },
+};
+
+use crate::{
+    audio::{self, OpusDecoder, OpusEncoder},
+    loopback::{self, LoopbackPublisher, LoopbackSubscriber},
+    room::{self, Room},
+};
+
+/// Publisher ID of the mix of every audio publisher.
+pub const MIX_ID: &str = "mix";
+const SELECT_INTERVAL: Duration = Duration::from_secs(1);
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+const FRAME_SAMPLES: usize = audio::SAMPLE_RATE as usize / 50;
+// A source starts playing after this much audio is buffered, to absorb jitter.
+const PLAYOUT_DELAY: usize = FRAME_SAMPLES * 2;
+// Audio over this is dropped, so clock drift of a source does not add latency.
+const MAX_BUFFER: usize = FRAME_SAMPLES * 10;
+
+#[derive(Debug)]
+pub enum Error {
+    RoomNotFound,
+    AlreadyStarted,
+    Opus(audiopus::Error),
+    Rheomesh(rheomesh::error::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RoomNotFound => write!(f, "room is not found"),
+            Error::AlreadyStarted => write!(f, "mixer is already started"),
+            Error::Opus(err) => write!(f, "{}", err),
+            Error::Rheomesh(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<audiopus::Error> for Error {
+    fn from(err: audiopus::Error) -> Self {
+        Error::Opus(err)
+    }
+}
+
+impl From<rheomesh::error::Error> for Error {
+    fn from(err: rheomesh::error::Error) -> Self {
+        Error::Rheomesh(err)
+    }
+}
+
+/// Publisher ID of the mix without the publisher, for the participant who publishes it.
+pub fn mix_id_without(publisher_id: &str) -> String {
+    format!("{}-{}", MIX_ID, publisher_id)
+}
+
+pub fn is_mix(publisher_id: &str) -> bool {
+    publisher_id == MIX_ID || publisher_id.starts_with(&format!("{}-", MIX_ID))
+}
+
+#[derive(Default)]
+struct Source {
+    samples: VecDeque<i16>,
+    playing: bool,
+}
+
+impl Source {
+    fn push(&mut self, samples: &[i16]) {
+        self.samples.extend(samples);
+        if self.samples.len() > MAX_BUFFER {
+            let excess = self.samples.len() - MAX_BUFFER;
+            self.samples.drain(..excess);
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<i16>> {
+        if !self.playing {
+            if self.samples.len() < PLAYOUT_DELAY {
+                return None;
+            }
+            self.playing = true;
+        }
+        let available = FRAME_SAMPLES.min(self.samples.len());
+        let mut frame: Vec<i16> = self.samples.drain(..available).collect();
+        if available < FRAME_SAMPLES {
+            // Buffer again after an underrun.
+            frame.resize(FRAME_SAMPLES, 0);
+            self.playing = false;
+        }
+        Some(frame)
+    }
+}
+
+struct Output {
+    excluded: Option<String>,
+    track: Arc<TrackLocalStaticSample>,
+    encoder: OpusEncoder,
+}
+
+type Sources = Arc<std::sync::Mutex<HashMap<String, Source>>>;
+type Outputs = Arc<Mutex<Vec<Output>>>;
+
+/// Decodes every audio publisher of a room and publishes the mix as [`MIX_ID`]. Each audio publisher also
+/// gets a mix without itself, published as `mix-<publisher ID>`, so participants do not hear themselves.
+pub struct Mixer {
+    publisher: Arc<LoopbackPublisher>,
+    subscriber: Arc<LoopbackSubscriber>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Mixer {
+    pub async fn start(room: &Arc<Room>) -> Result<Self, Error> {
+        let publisher = Arc::new(LoopbackPublisher::new(room).await?);
+        let subscriber = match LoopbackSubscriber::new(room).await {
+            Ok(subscriber) => Arc::new(subscriber),
+            Err(err) => {
+                publisher.close().await;
+                return Err(err.into());
+            }
+        };
+        let output = match new_output(&publisher, None) {
+            Ok(output) => output,
+            Err(err) => {
+                subscriber.close().await;
+                publisher.close().await;
+                return Err(err.into());
+            }
+        };
+
+        let sources = Sources::default();
+        let outputs = Arc::new(Mutex::new(vec![output]));
+        let tasks = vec![
+            tokio::spawn(mix(sources.clone(), outputs.clone())),
+            actix::spawn(select(
+                room.clone(),
+                publisher.clone(),
+                subscriber.clone(),
+                sources,
+                outputs,
+            )),
+        ];
+        tracing::info!("Audio mixer started in room {}", room.id);
+
+        Ok(Self {
+            publisher,
+            subscriber,
+            tasks,
+        })
+    }
+
+    pub async fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.subscriber.close().await;
+        self.publisher.close().await;
+    }
+}
+
+fn new_output(
+    publisher: &Arc<LoopbackPublisher>,
+    excluded: Option<String>,
+) -> Result<Output, audiopus::Error> {
+    let encoder = OpusEncoder::new()?;
+    let track_id = match &excluded {
+        Some(publisher_id) => mix_id_without(publisher_id),
+        None => MIX_ID.to_owned(),
+    };
+    let track = Arc::new(TrackLocalStaticSample::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_owned(),
+            clock_rate: audio::SAMPLE_RATE,
+            channels: 2,
+            sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+            rtcp_feedback: vec![],
+        },
+        track_id.clone(),
+        track_id.clone(),
+    ));
+
+    // Publishing waits for media, which the mix task writes in the meantime.
+    let publisher = publisher.clone();
+    let t = track.clone();
+    tokio::spawn(async move {
+        match publisher.publish(t).await {
+            Ok(_) => tracing::debug!("Mix is published: {}", track_id),
+            Err(err) => tracing::error!("Failed to publish mix {}: {}", track_id, err),
+        }
+    });
+
+    Ok(Output {
+        excluded,
+        track,
+        encoder,
+    })
+}
+
+// Follows audio publishers of the room, and adds or removes sources and outputs.
+async fn select(
+    room: Arc<Room>,
+    publisher: Arc<LoopbackPublisher>,
+    subscriber: Arc<LoopbackSubscriber>,
+    sources: Sources,
+    outputs: Outputs,
+) {
+    let mut readers: HashMap<String, JoinHandle<()>> = HashMap::new();
+    loop {
+        let publisher_ids: Vec<String> = room
+            .audio_publisher_ids()
+            .await
+            .into_iter()
+            .filter(|id| !is_mix(id))
+            .collect();
+
+        let removed: Vec<String> = readers
+            .keys()
+            .filter(|id| !publisher_ids.contains(id))
+            .cloned()
+            .collect();
+        for publisher_id in removed {
+            if let Some(reader) = readers.remove(&publisher_id) {
+                reader.abort();
+            }
+            subscriber.unsubscribe(&publisher_id).await;
+            sources.lock().unwrap().remove(&publisher_id);
+            outputs
+                .lock()
+                .await
+                .retain(|o| o.excluded.as_ref() != Some(&publisher_id));
+            publisher.unpublish(&mix_id_without(&publisher_id)).await;
+            tracing::info!("{} is removed from the mix", publisher_id);
+        }
+
+        for publisher_id in publisher_ids {
+            if readers.contains_key(&publisher_id) {
+                continue;
+            }
+            let track = match subscriber.subscribe(&publisher_id).await {
+                Ok(track) => track,
+                Err(err) => {
+                    tracing::error!("Failed to subscribe {} for the mix: {}", publisher_id, err);
+                    continue;
+                }
+            };
+            let output = match new_output(&publisher, Some(publisher_id.clone())) {
+                Ok(output) => output,
+                Err(err) => {
+                    tracing::error!("Failed to create Opus encoder: {}", err);
+                    subscriber.unsubscribe(&publisher_id).await;
+                    continue;
+                }
+            };
+            sources
+                .lock()
+                .unwrap()
+                .insert(publisher_id.clone(), Source::default());
+            outputs.lock().await.push(output);
+            readers.insert(
+                publisher_id.clone(),
+                tokio::spawn(read(track, publisher_id.clone(), sources.clone())),
+            );
+            tracing::info!("{} is added to the mix", publisher_id);
+        }
+
+        tokio::time::sleep(SELECT_INTERVAL).await;
+    }
+}
+
+enum Decoder {
+    Opus(OpusDecoder),
+    Pcmu,
+    Pcma,
+}
+
+// Decodes a source into 48kHz mono samples.
+async fn read(track: Arc<TrackRemote>, publisher_id: String, sources: Sources) {
+    let mime_type = loopback::mime_type(&track);
+    let mut decoder = if mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+        match OpusDecoder::new() {
+            Ok(decoder) => Decoder::Opus(decoder),
+            Err(err) => {
+                tracing::error!("Failed to create Opus decoder: {}", err);
+                return;
+            }
+        }
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_PCMU) {
+        Decoder::Pcmu
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_PCMA) {
+        Decoder::Pcma
+    } else {
+        tracing::warn!("{} of {} can not be mixed", mime_type, publisher_id);
+        return;
+    };
+    let factor = (audio::SAMPLE_RATE / 8000) as usize;
+
+    while let Ok((packet, _)) = track.read_rtp().await {
+        if packet.payload.is_empty() {
+            continue;
+        }
+        let samples = match &mut decoder {
+            Decoder::Opus(decoder) => match decoder.decode(&packet.payload) {
+                Ok(samples) => samples,
+                Err(err) => {
+                    tracing::trace!("Failed to decode Opus: {}", err);
+                    continue;
+                }
+            },
+            Decoder::Pcmu => {
+                let samples: Vec<i16> = packet
+                    .payload
+                    .iter()
+                    .map(|v| audio::decode_ulaw(*v))
+                    .collect();
+                audio::upsample(&samples, factor)
+            }
+            Decoder::Pcma => {
+                let samples: Vec<i16> = packet
+                    .payload
+                    .iter()
+                    .map(|v| audio::decode_alaw(*v))
+                    .collect();
+                audio::upsample(&samples, factor)
+            }
+        };
+        if let Some(source) = sources.lock().unwrap().get_mut(&publisher_id) {
+            source.push(&samples);
+        }
+    }
+    tracing::debug!("Mix source {} finished", publisher_id);
+}
+
+fn sum(frames: &[(String, Vec<i16>)]) -> Vec<i32> {
+    let mut total = vec![0i32; FRAME_SAMPLES];
+    for (_, frame) in frames {
+        for (sum, sample) in total.iter_mut().zip(frame) {
+            *sum += *sample as i32;
+        }
+    }
+    total
+}
+
+// Takes the frame of the excluded publisher out of the sum, and clips it to 16 bits.
+fn mix_without(total: &[i32], frames: &[(String, Vec<i16>)], excluded: Option<&str>) -> Vec<i16> {
+    let excluded = excluded
+        .and_then(|id| frames.iter().find(|(i, _)| i == id))
+        .map(|(_, frame)| frame);
+    total
+        .iter()
+        .enumerate()
+        .map(|(i, sum)| {
+            let sum = sum - excluded.map(|f| f[i] as i32).unwrap_or(0);
+            sum.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        })
+        .collect()
+}
+
+async fn mix(sources: Sources, outputs: Outputs) {
+    let mut interval = tokio::time::interval(FRAME_DURATION);
+    loop {
+        interval.tick().await;
+        let frames: Vec<(String, Vec<i16>)> = sources
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter_map(|(id, source)| Some((id.clone(), source.next_frame()?)))
+            .collect();
+        let total = sum(&frames);
+
+        let mut outputs = outputs.lock().await;
+        for output in outputs.iter_mut() {
+            let samples = mix_without(&total, &frames, output.excluded.as_deref());
+            let payload = match output.encoder.encode(&samples) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    tracing::trace!("Failed to encode Opus: {}", err);
+                    continue;
+                }
+            };
+            let sample = Sample {
+                data: payload.into(),
+                duration: FRAME_DURATION,
+                ..Default::default()
+            };
+            if let Err(err) = output.track.write_sample(&sample).await {
+                tracing::trace!("Failed to write mix: {}", err);
+            }
+        }
+    }
+}
+
+async fn find_room(room_owner: &Mutex<room::RoomOwner>, room_id: &str) -> Result<Arc<Room>, Error> {
+    room_owner
+        .lock()
+        .await
+        .find_by_id(room_id.to_string())
+        .ok_or(Error::RoomNotFound)
+}
+
+pub async fn start(
+    path: web::Path<String>,
+    room_owner: web::Data<Mutex<room::RoomOwner>>,
+) -> impl Responder {
+    let room_id = path.into_inner();
+    let result = async {
+        let room = find_room(&room_owner, &room_id).await?;
+        let mut mixer = room.mixer.lock().await;
+        if mixer.is_some() {
+            return Err(Error::AlreadyStarted);
+        }
+        *mixer = Some(Mixer::start(&room).await?);
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => HttpResponse::Created().finish(),
+        Err(Error::RoomNotFound) => HttpResponse::NotFound().body(Error::RoomNotFound.to_string()),
+        Err(Error::AlreadyStarted) => {
+            HttpResponse::Conflict().body(Error::AlreadyStarted.to_string())
+        }
+        Err(err) => {
+            tracing::error!("Failed to start audio mixer: {}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+pub async fn stop(
+    path: web::Path<String>,
+    room_owner: web::Data<Mutex<room::RoomOwner>>,
+) -> impl Responder {
+    let Ok(room) = find_room(&room_owner, &path.into_inner()).await else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mixer = room.mixer.lock().await.take();
+    match mixer {
+        Some(mixer) => {
+            mixer.stop().await;
+            HttpResponse::NoContent().finish()
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant_frames(sources: &[(&str, i16)]) -> Vec<(String, Vec<i16>)> {
+        sources
+            .iter()
+            .map(|(id, sample)| (id.to_string(), vec![*sample; FRAME_SAMPLES]))
+            .collect()
+    }
+
+    #[test]
+    fn publishers_do_not_hear_themselves() {
+        let frames = constant_frames(&[("alice", 1000), ("bob", -300)]);
+        let total = sum(&frames);
+        assert_eq!(mix_without(&total, &frames, None), vec![700; FRAME_SAMPLES]);
+        assert_eq!(
+            mix_without(&total, &frames, Some("alice")),
+            vec![-300; FRAME_SAMPLES]
+        );
+        assert_eq!(
+            mix_without(&total, &frames, Some("bob")),
+            vec![1000; FRAME_SAMPLES]
+        );
+        // A publisher without a frame in this interval, like while it buffers, hears everyone.
+        assert_eq!(
+            mix_without(&total, &frames, Some("carol")),
+            vec![700; FRAME_SAMPLES]
+        );
+    }
+
+    #[test]
+    fn mixes_are_clipped() {
+        let frames = constant_frames(&[("alice", 30000), ("bob", 30000), ("carol", -30000)]);
+        let total = sum(&frames);
+        assert_eq!(
+            mix_without(&total, &frames, None),
+            vec![30000; FRAME_SAMPLES]
+        );
+        assert_eq!(
+            mix_without(&total, &frames, Some("carol")),
+            vec![i16::MAX; FRAME_SAMPLES]
+        );
+        let frames = constant_frames(&[("alice", -30000), ("bob", -30000)]);
+        assert_eq!(
+            mix_without(&sum(&frames), &frames, None),
+            vec![i16::MIN; FRAME_SAMPLES]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+// Version and capabilities of the signaling protocol.
+// Version 1 is the protocol before the handshake. Clients which do not send Hello are treated as version 1,
+// and receive every message as before.
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features. The server only sends notifications of the capabilities which the client declares.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum Capability {
+    ActiveSpeakers,
+    LayerChanged,
+    Chat,
+    DataChannel,
+    ConnectionQuality,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    // The text is not a message of this protocol.
+    InvalidMessage,
+    UnknownAction,
+    UnsupportedVersion,
+    // The participant waits in the lobby, and only Ping and Hello are handled.
+    NotAdmitted,
+    // The action is only allowed for moderators.
+    NotModerator,
+    // The transport which the action needs is not created by PublisherInit or SubscriberInit.
+    NoTransport,
+}
+
+/// Result of a Hello.
+pub struct Session {
+    pub protocol_version: u32,
+    pub capabilities: HashSet<Capability>,
+}
+
+impl Session {
+    /// Returns None if the client is older than the server supports.
+    /// Newer clients are expected to fall back to the version of the server, and capabilities which this
+    /// server does not know are ignored.
+    pub fn negotiate(protocol_version: u32, capabilities: Vec<String>) -> Option<Self> {
+        if protocol_version < MIN_PROTOCOL_VERSION {
+            return None;
+        }
+        Some(Self {
+            protocol_version: protocol_version.min(PROTOCOL_VERSION),
+            capabilities: capabilities
+                .into_iter()
+                .filter_map(|c| serde_json::from_value(serde_json::Value::String(c)).ok())
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    // build.rs generates these files from the message enums.
+    const GENERATED: [(&str, &str); 2] = [
+        (
+            include_str!(concat!(env!("OUT_DIR"), "/signaling.schema.json")),
+            "schema/signaling.schema.json",
+        ),
+        (
+            include_str!(concat!(env!("OUT_DIR"), "/signaling.ts")),
+            "../frontend/src/signaling.ts",
+        ),
+    ];
+
+    #[test]
+    fn generated_definitions_are_up_to_date() {
+        for (generated, path) in GENERATED {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+            if env::var_os("UPDATE_SCHEMA").is_some() {
+                fs::write(&path, generated).expect("failed to update generated definitions");
+                continue;
+            }
+            let checked_in = fs::read_to_string(&path).unwrap_or_default();
+            assert!(
+                checked_in == generated,
+                "{} is stale, run `UPDATE_SCHEMA=1 cargo test` in server",
+                path.display()
+            );
+        }
+    }
+}
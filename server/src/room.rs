@@ -1,27 +1,87 @@
-use rheomesh;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use tokio::sync::Mutex;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
 
-use crate::websocket::WebSocket;
+#[cfg(feature = "opus")]
+use crate::mixer;
+use crate::{
+    audit, chat,
+    cluster::{self, memory::MemoryRegistry, redis::RedisRegistry},
+    config, hls, media, settings, speaker, webhook,
+    websocket::{SendingMessage, WebSocket},
+};
 use actix::Addr;
 
 pub struct RoomOwner {
     pub rooms: HashMap<String, Arc<Room>>,
     worker: Arc<Mutex<rheomesh::worker::Worker>>,
+    pub cluster: Option<Arc<cluster::Cluster>>,
+    store: Arc<settings::Store>,
+    pub audit: audit::Log,
+}
+
+/// What the participant who creates a room asks for. Persistent rooms use their settings instead, and only take
+/// the profile when their settings do not fix it.
+#[derive(Default)]
+pub struct RoomOptions {
+    pub profile: media::Profile,
+    pub password: Option<String>,
+    pub lobby: bool,
+}
+
+pub enum Placement {
+    Local(Arc<Room>),
+    // URL of the node which has the room.
+    Remote(String),
 }
 
 impl RoomOwner {
-    pub async fn new() -> Self {
-        let worker = rheomesh::worker::Worker::new(rheomesh::config::WorkerConfig {
-            relay_sender_port: 9441,
-            relay_server_udp_port: 9442,
-            relay_server_tcp_port: 9443,
-        })
-        .await
-        .unwrap();
+    pub async fn new(store: Arc<settings::Store>) -> Self {
+        let worker = rheomesh::worker::Worker::new(config::worker_config())
+            .await
+            .unwrap();
+        let cluster = config::cluster_node_url().map(|node_url| {
+            let registry: Box<dyn cluster::Registry> = match config::cluster_registry().as_str() {
+                "memory" => Box::new(MemoryRegistry::default()),
+                url => Box::new(RedisRegistry::new(url).expect("failed to configure registry")),
+            };
+            let routing = config::cluster_routing();
+            if routing == cluster::Routing::Relay && config::relay_ip().is_none() {
+                panic!("RELAY_IP is required for relay routing when PUBLIC_IP has no IPv4 address");
+            }
+            if routing == cluster::Routing::Relay && config::cluster_secret().is_none() {
+                panic!("CLUSTER_SECRET is required for relay routing");
+            }
+            tracing::info!("Starting cluster node {}", node_url);
+            cluster::Cluster::start(node_url, routing, registry)
+        });
+        let mut sinks = Vec::new();
+        if let Some(sink) = config::audit_sink() {
+            sinks.push(audit::open(&sink).await.expect("failed to open audit sink"));
+        }
+        let webhook_urls = config::webhook_urls();
+        if !webhook_urls.is_empty() {
+            let secret = config::webhook_secret().expect("WEBHOOK_SECRET is required for webhooks");
+            for url in webhook_urls {
+                let endpoint = webhook::Endpoint::parse(&url)
+                    .unwrap_or_else(|| panic!("webhook URL must be http:// or https://: {}", url));
+                sinks.push(Box::new(webhook::Webhook::new(
+                    endpoint,
+                    secret.clone(),
+                    config::webhook_max_attempts(),
+                )));
+            }
+        }
+        let audit = audit::Log::start(sinks);
         RoomOwner {
             rooms: HashMap::<String, Arc<Room>>::new(),
             worker,
+            cluster,
+            store,
+            audit,
         }
     }
 
@@ -29,52 +89,379 @@ impl RoomOwner {
         self.rooms.get(&id).cloned()
     }
 
-    pub async fn create_new_room(
-        &mut self,
-        id: String,
-        config: rheomesh::config::MediaConfig,
-    ) -> Arc<Room> {
+    // Settings of a persistent room are applied here, so they are fixed while the room is open.
+    pub async fn create_new_room(&mut self, id: String, options: RoomOptions) -> Arc<Room> {
+        let settings = self.store.get(&id).unwrap_or_else(|err| {
+            tracing::error!("Failed to load settings of room {}: {}", id, err);
+            None
+        });
+        let settings = settings.unwrap_or_else(|| {
+            settings::RoomSettings::ad_hoc(id.clone(), options.password.as_deref(), options.lobby)
+        });
+        let media = media::Media::new(options.profile, &settings);
+        tracing::info!("Creating room {} with {:?}", id, media);
         let mut worker = self.worker.lock().await;
-        let router = worker.new_router(config);
-        let room = Room::new(id.clone(), router);
+        let router = worker.new_router(media.media_config());
+        let room = Room::new(id.clone(), router, settings, media, self.audit.clone());
+        self.audit.emit(audit::Event::RoomCreated {
+            room_id: id.clone(),
+        });
         let a = Arc::new(room);
+        if let Some(interval) = config::active_speaker_interval() {
+            *a.speakers.lock().await = Some(speaker::Detector::start(&a, interval));
+        }
         self.rooms.insert(id.clone(), a.clone());
         a
     }
 
     pub fn remove_room(&mut self, room_id: String) {
-        self.rooms.remove(&room_id);
+        if self.rooms.remove(&room_id).is_some() {
+            self.audit.emit(audit::Event::RoomClosed {
+                room_id: room_id.clone(),
+            });
+        }
+        if let Some(cluster) = self.cluster.clone() {
+            actix::spawn(async move { cluster.release(&room_id).await });
+        }
+    }
+}
+
+// In cluster mode, a room is created only when this node becomes its home. The home is resolved without holding the
+// owner, so joins of other rooms do not wait for the registry.
+// The options are only used when the room is created.
+pub async fn find_or_create_room(
+    owner: &Mutex<RoomOwner>,
+    id: String,
+    options: RoomOptions,
+) -> Result<Placement, cluster::Error> {
+    let cluster = {
+        let owner = owner.lock().await;
+        if let Some(room) = owner.find_by_id(id.clone()) {
+            return Ok(Placement::Local(room));
+        }
+        owner.cluster.clone()
+    };
+    let mut relayed_home = None;
+    if let Some(cluster) = cluster {
+        if let cluster::Home::Remote(node_url) = cluster.home(&id).await? {
+            if cluster.routing != cluster::Routing::Relay {
+                return Ok(Placement::Remote(node_url));
+            }
+            relayed_home = Some((cluster, node_url));
+        }
+    }
+    let mut owner = owner.lock().await;
+    // Another join may have created the room while the home was resolved.
+    if let Some(room) = owner.find_by_id(id.clone()) {
+        return Ok(Placement::Local(room));
+    }
+    let room = owner.create_new_room(id, options).await;
+    if let Some((cluster, node_url)) = relayed_home {
+        cluster::relay::join(cluster, room.clone(), node_url);
+    }
+    Ok(Placement::Local(room))
+}
+
+// This is called when the last participant leaves the room.
+pub async fn close(owner: &Mutex<RoomOwner>, room: &Room) {
+    if room.links.keeps_room().await {
+        return;
+    }
+    if let Some(detector) = room.speakers.lock().await.take() {
+        detector.stop().await;
+    }
+    if let Some(egress) = room.hls.lock().await.take() {
+        egress.stop().await;
+        room.audit.emit(audit::Event::RecordingStopped {
+            room_id: room.id.clone(),
+        });
+    }
+    #[cfg(feature = "opus")]
+    if let Some(mixer) = room.mixer.lock().await.take() {
+        mixer.stop().await;
+    }
+    let cluster = {
+        let mut owner = owner.lock().await;
+        owner.remove_room(room.id.clone());
+        owner.cluster.clone()
+    };
+    let router = room.router.lock().await;
+    router.close();
+    if let Some(cluster) = cluster {
+        room.links.leave(&room.id, &cluster.node_url).await;
+    }
+}
+
+/// A place in a room, which is taken when a participant is admitted. It is released when it is dropped before
+/// the participant joins, like when the WebSocket handshake fails.
+pub struct Reservation {
+    room: Arc<Room>,
+    pub moderator: bool,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.room.reserved.lock().unwrap() -= 1;
     }
 }
 
 pub struct Room {
     pub id: String,
     pub router: Arc<Mutex<rheomesh::router::Router>>,
-    users: std::sync::Mutex<Vec<Addr<WebSocket>>>,
+    // Stored settings of a persistent room, or the options which the first participant gave.
+    pub settings: settings::RoomSettings,
+    pub media: media::Media,
+    // Participant IDs and their WebSockets.
+    users: std::sync::Mutex<Vec<(String, Addr<WebSocket>)>>,
+    // Participant IDs of the users who are moderators.
+    moderators: std::sync::Mutex<HashSet<String>>,
+    // Participants who wait to be admitted. They are not counted as participants.
+    lobby: std::sync::Mutex<Vec<(String, Addr<WebSocket>)>>,
+    // Participants who are admitted, but have not joined yet.
+    reserved: std::sync::Mutex<usize>,
+    // Participants which are not connected with WebSocket, like phone calls.
+    calls: std::sync::Mutex<usize>,
+    // rheomesh does not expose the kind of publishers, so it is recorded when tracks arrive.
+    track_kinds: std::sync::Mutex<Vec<(String, RTPCodecType)>>,
+    speakers: Mutex<Option<speaker::Detector>>,
+    chat: std::sync::Mutex<chat::History>,
+    pub links: cluster::relay::Links,
+    pub hls: Mutex<Option<hls::Egress>>,
+    #[cfg(feature = "opus")]
+    pub mixer: Mutex<Option<mixer::Mixer>>,
+    pub audit: audit::Log,
 }
 
 impl Room {
-    pub fn new(id: String, router: Arc<Mutex<rheomesh::router::Router>>) -> Self {
+    pub fn new(
+        id: String,
+        router: Arc<Mutex<rheomesh::router::Router>>,
+        settings: settings::RoomSettings,
+        media: media::Media,
+        audit: audit::Log,
+    ) -> Self {
         Self {
             id,
             router,
+            settings,
+            media,
             users: std::sync::Mutex::new(Vec::new()),
+            moderators: std::sync::Mutex::new(HashSet::new()),
+            lobby: std::sync::Mutex::new(Vec::new()),
+            reserved: std::sync::Mutex::new(0),
+            calls: std::sync::Mutex::new(0),
+            track_kinds: std::sync::Mutex::new(Vec::new()),
+            speakers: Mutex::new(None),
+            chat: std::sync::Mutex::new(chat::History::new(config::chat_history_size())),
+            links: cluster::relay::Links::default(),
+            hls: Mutex::new(None),
+            #[cfg(feature = "opus")]
+            mixer: Mutex::new(None),
+            audit,
         }
     }
 
-    pub fn add_user(&self, user: Addr<WebSocket>) {
+    // Participants admitted from the lobby do not have a reservation, because moderators let them in.
+    pub fn add_user(
+        &self,
+        reservation: Option<Reservation>,
+        participant_id: String,
+        user: Addr<WebSocket>,
+        moderator: bool,
+    ) {
         let mut users = self.users.lock().unwrap();
-        users.push(user);
+        if moderator {
+            self.moderators
+                .lock()
+                .unwrap()
+                .insert(participant_id.clone());
+        }
+        users.push((participant_id, user));
+        drop(reservation);
     }
 
-    pub fn remove_user(&self, user: Addr<WebSocket>) -> usize {
+    // Returns the participant who becomes a moderator, when the last moderator of a room without moderator tokens
+    // left. They are the one who has been in the room for the longest. Rooms with tokens wait for a moderator to join.
+    pub fn remove_user(&self, user: Addr<WebSocket>) -> Option<Addr<WebSocket>> {
         let mut users = self.users.lock().unwrap();
-        users.retain(|u| u != &user);
-        users.len()
+        let mut moderators = self.moderators.lock().unwrap();
+        if let Some((id, _)) = users.iter().find(|(_, u)| u == &user) {
+            moderators.remove(id);
+        }
+        users.retain(|(_, u)| u != &user);
+        if !moderators.is_empty() || !self.settings.moderators.is_empty() {
+            return None;
+        }
+        let (id, user) = users.first()?;
+        moderators.insert(id.clone());
+        Some(user.clone())
+    }
+
+    pub fn participants(&self) -> usize {
+        self.count(&self.users.lock().unwrap())
+    }
+
+    // The lock of users is taken first, so counting is consistent with adding users.
+    fn count(&self, users: &[(String, Addr<WebSocket>)]) -> usize {
+        users.len() + *self.reserved.lock().unwrap() + *self.calls.lock().unwrap()
+    }
+
+    /// Checks a participant who joins the room, and reserves their place until they join. Participants are
+    /// counted and the place is taken under the lock of users, so concurrent participants can not exceed
+    /// max_participants, and only one of them becomes the moderator of an empty room.
+    pub fn admit(
+        self: &Arc<Self>,
+        password: Option<&str>,
+        moderator: Option<&str>,
+    ) -> Result<Reservation, settings::Rejection> {
+        let token = self.settings.authenticate(password, moderator)?;
+        let users = self.users.lock().unwrap();
+        let moderator = self.settings.admit(self.count(&users), token)?;
+        *self.reserved.lock().unwrap() += 1;
+        Ok(Reservation {
+            room: self.clone(),
+            moderator,
+        })
+    }
+
+    pub fn notify_moderators(&self, message: SendingMessage) {
+        let users = self.users.lock().unwrap();
+        let moderators = self.moderators.lock().unwrap();
+        users
+            .iter()
+            .filter(|(id, _)| moderators.contains(id))
+            .for_each(|(_, u)| u.do_send(message.clone()));
+    }
+
+    pub fn wait(&self, participant_id: String, user: Addr<WebSocket>) {
+        self.lobby
+            .lock()
+            .unwrap()
+            .push((participant_id.clone(), user));
+        self.notify_moderators(SendingMessage::LobbyJoined { participant_id });
+    }
+
+    pub fn waiting_ids(&self) -> Vec<String> {
+        let lobby = self.lobby.lock().unwrap();
+        lobby.iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    // Removes the participant from the lobby, when they are admitted or denied.
+    pub fn take_waiting(&self, participant_id: &str) -> Option<Addr<WebSocket>> {
+        let mut lobby = self.lobby.lock().unwrap();
+        let index = lobby.iter().position(|(id, _)| id == participant_id)?;
+        Some(lobby.remove(index).1)
+    }
+
+    // This is called when a participant leaves the lobby without being admitted.
+    pub fn leave_lobby(&self, user: &Addr<WebSocket>) {
+        let mut lobby = self.lobby.lock().unwrap();
+        let Some(index) = lobby.iter().position(|(_, u)| u == user) else {
+            return;
+        };
+        let (participant_id, _) = lobby.remove(index);
+        drop(lobby);
+        self.notify_moderators(SendingMessage::LobbyLeft { participant_id });
+    }
+
+    // Participants in the lobby of a room without moderator tokens are sent away when nobody is left to admit them.
+    pub fn abandon_lobby(&self) -> Vec<Addr<WebSocket>> {
+        if !self.settings.moderators.is_empty() || !self.users.lock().unwrap().is_empty() {
+            return Vec::new();
+        }
+        let mut lobby = self.lobby.lock().unwrap();
+        lobby.drain(..).map(|(_, user)| user).collect()
+    }
+
+    // The room is kept while participants wait in the lobby, even if nobody is in the room.
+    pub fn is_empty(&self) -> bool {
+        self.participants() == 0 && self.lobby.lock().unwrap().is_empty()
+    }
+
+    pub fn add_call(&self, reservation: Reservation) {
+        let _users = self.users.lock().unwrap();
+        *self.calls.lock().unwrap() += 1;
+        drop(reservation);
+    }
+
+    // Returns the number of remaining participants.
+    pub fn remove_call(&self) -> usize {
+        let users = self.users.lock().unwrap();
+        {
+            let mut calls = self.calls.lock().unwrap();
+            *calls = calls.saturating_sub(1);
+        }
+        self.count(&users)
+    }
+
+    pub fn addresses(&self) -> Vec<Addr<WebSocket>> {
+        let users = self.users.lock().unwrap();
+        users.iter().map(|(_, u)| u.clone()).collect()
     }
 
     pub fn get_peers(&self, user: &Addr<WebSocket>) -> Vec<Addr<WebSocket>> {
         let users = self.users.lock().unwrap();
-        users.iter().filter(|u| u != &user).cloned().collect()
+        users
+            .iter()
+            .filter(|(_, u)| u != user)
+            .map(|(_, u)| u.clone())
+            .collect()
+    }
+
+    pub fn broadcast(&self, message: SendingMessage) {
+        let users = self.users.lock().unwrap();
+        users.iter().for_each(|(_, u)| u.do_send(message.clone()));
+    }
+
+    // Messages to everyone are kept for participants who join later, and sent to the room.
+    // A direct message is sent to the recipient and the sender. Returns false if the recipient is not in the room.
+    pub fn send_chat(&self, message: chat::ChatMessage) -> bool {
+        let Some(to) = &message.to else {
+            self.chat.lock().unwrap().push(message.clone());
+            self.broadcast(SendingMessage::Chat { message });
+            return true;
+        };
+        let users = self.users.lock().unwrap();
+        if !users.iter().any(|(id, _)| id == to) {
+            return false;
+        }
+        users
+            .iter()
+            .filter(|(id, _)| id == to || id == &message.from)
+            .for_each(|(_, u)| {
+                u.do_send(SendingMessage::Chat {
+                    message: message.clone(),
+                })
+            });
+        true
+    }
+
+    pub fn chat_history(&self) -> Vec<chat::ChatMessage> {
+        self.chat.lock().unwrap().messages()
+    }
+
+    pub fn set_track_kind(&self, track_id: String, kind: RTPCodecType) {
+        let mut track_kinds = self.track_kinds.lock().unwrap();
+        track_kinds.retain(|(id, _)| id != &track_id);
+        track_kinds.push((track_id, kind));
+    }
+
+    pub fn track_kind(&self, track_id: &str) -> Option<RTPCodecType> {
+        let track_kinds = self.track_kinds.lock().unwrap();
+        track_kinds
+            .iter()
+            .find(|(id, _)| id == track_id)
+            .map(|(_, kind)| *kind)
+    }
+
+    /// Audio publishers in the room, ordered from the oldest to the newest.
+    pub async fn audio_publisher_ids(&self) -> Vec<String> {
+        let publisher_ids = self.router.lock().await.publisher_ids();
+        let track_kinds = self.track_kinds.lock().unwrap();
+        track_kinds
+            .iter()
+            .filter(|(id, kind)| *kind == RTPCodecType::Audio && publisher_ids.contains(id))
+            .map(|(id, _)| id.clone())
+            .collect()
     }
 }
@@ -0,0 +1,351 @@
+// Persistent rooms. Their settings are applied when the room is created, so changes take effect the next time the
+// room is opened.
+use std::fmt;
+
+use actix_web::{web, HttpResponse, Responder};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::media::{self, codec};
+
+mod store;
+
+pub use store::Store;
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownCodec(String),
+    // None of the allowed codecs is in the profile.
+    NoCodec,
+    UnknownRecordingPolicy(String),
+    UnknownProfile(String),
+    Sqlite(rusqlite::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownCodec(mime_type) => write!(f, "unknown codec: {}", mime_type),
+            Error::NoCodec => write!(f, "no allowed codec is in the profile"),
+            Error::UnknownRecordingPolicy(policy) => {
+                write!(f, "unknown recording policy: {}", policy)
+            }
+            Error::UnknownProfile(profile) => write!(f, "unknown profile: {}", profile),
+            Error::Sqlite(err) => write!(f, "{}", err),
+            Error::Json(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+/// Why a participant can not join a room.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    WrongPassword,
+    Full,
+    // The room has a lobby, which the participant can not wait in.
+    Lobby,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::WrongPassword => write!(f, "password is wrong"),
+            Rejection::Full => write!(f, "room is full"),
+            Rejection::Lobby => write!(f, "room has a lobby"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordingPolicy {
+    #[default]
+    Allowed,
+    // HLS egress can not be started.
+    Disabled,
+}
+
+impl RecordingPolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RecordingPolicy::Allowed => "allowed",
+            RecordingPolicy::Disabled => "disabled",
+        }
+    }
+
+    fn parse(policy: &str) -> Result<Self, Error> {
+        serde_json::from_value(serde_json::Value::String(policy.to_owned()))
+            .map_err(|_| Error::UnknownRecordingPolicy(policy.to_owned()))
+    }
+}
+
+// Iterations of PBKDF2-HMAC-SHA256 which OWASP recommends. They are stored with hashes, so they can be raised.
+#[cfg(not(test))]
+const PASSWORD_ITERATIONS: u32 = 600_000;
+// Hashing takes seconds in unoptimized builds.
+#[cfg(test)]
+const PASSWORD_ITERATIONS: u32 = 1_000;
+
+/// PBKDF2 hash of a room password, as "<iterations>$<salt>$<hash>". Passwords are given in plain text, but only the
+/// hash is stored.
+#[derive(Debug, Clone)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: &str) -> Self {
+        let salt = uuid::Uuid::new_v4().simple().to_string();
+        let hash = digest(password, &salt, PASSWORD_ITERATIONS);
+        Self(format!("{}${}${}", PASSWORD_ITERATIONS, salt, hash))
+    }
+
+    fn from_hash(hash: String) -> Self {
+        Self(hash)
+    }
+
+    fn hash(&self) -> &str {
+        &self.0
+    }
+
+    // Hashes are compared in constant time, so they are not guessed from response times.
+    pub fn verify(&self, password: &str) -> bool {
+        let mut parts = self.0.splitn(3, '$');
+        let iterations = parts.next().and_then(|iterations| iterations.parse().ok());
+        let (Some(iterations), Some(salt), Some(hash)) = (iterations, parts.next(), parts.next())
+        else {
+            return false;
+        };
+        digest(password, salt, iterations)
+            .as_bytes()
+            .ct_eq(hash.as_bytes())
+            .into()
+    }
+}
+
+impl<'de> Deserialize<'de> for Password {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|password| Password::new(&password))
+    }
+}
+
+fn digest(password: &str, salt: &str, iterations: u32) -> String {
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), iterations, &mut hash);
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Settings of a persistent room. Other rooms have the settings which the participant creating them gives.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSettings {
+    // The ID is given by the path.
+    #[serde(skip_deserializing)]
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    // Participants give it with the `password` query parameter. It is never returned.
+    #[serde(default, skip_serializing)]
+    pub password: Option<Password>,
+    #[serde(default)]
+    pub max_participants: Option<usize>,
+    // MIME types, like audio/opus. All codecs are allowed when it is empty.
+    #[serde(default)]
+    pub allowed_codecs: Vec<String>,
+    // The profile which participants request is used when it is None.
+    #[serde(default)]
+    pub profile: Option<media::Profile>,
+    // The default of the profile is used when it is None.
+    #[serde(default)]
+    pub simulcast: Option<bool>,
+    // Bits per second of each video publisher.
+    #[serde(default)]
+    pub max_bitrate: Option<u32>,
+    #[serde(default)]
+    pub recording: RecordingPolicy,
+    // Tokens which participants give with the `moderator` query parameter to join as moderators. They are never
+    // returned.
+    #[serde(default, skip_serializing)]
+    pub moderators: Vec<String>,
+    // Participants wait in the lobby until a moderator admits them.
+    #[serde(default)]
+    pub lobby: bool,
+}
+
+impl RoomSettings {
+    pub fn ad_hoc(id: String, password: Option<&str>, lobby: bool) -> Self {
+        Self {
+            id,
+            password: password.map(Password::new),
+            lobby,
+            ..Default::default()
+        }
+    }
+
+    /// Checks the password of a participant who joins the room, and returns whether they give a moderator token.
+    /// Moderators are not limited by the password.
+    pub fn authenticate(
+        &self,
+        password: Option<&str>,
+        moderator: Option<&str>,
+    ) -> Result<bool, Rejection> {
+        if moderator.is_some_and(|token| self.moderators.iter().any(|m| m == token)) {
+            return Ok(true);
+        }
+        if let Some(expected) = &self.password {
+            if !password.is_some_and(|password| expected.verify(password)) {
+                return Err(Rejection::WrongPassword);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Checks whether an authenticated participant fits in the room, and returns whether they are a moderator.
+    /// Moderators are not limited by the number of participants. In rooms without moderator tokens, the
+    /// participant who joins the empty room is a moderator.
+    pub fn admit(&self, participants: usize, token: bool) -> Result<bool, Rejection> {
+        if token {
+            return Ok(true);
+        }
+        if self.max_participants.is_some_and(|max| participants >= max) {
+            return Err(Rejection::Full);
+        }
+        Ok(self.moderators.is_empty() && participants == 0)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(mime_type) = self.allowed_codecs.iter().find(|c| !codec::is_known(c)) {
+            return Err(Error::UnknownCodec(mime_type.clone()));
+        }
+        let media = media::Media::new(media::Profile::Default, self);
+        if !self.allowed_codecs.is_empty() && media.codecs.is_empty() {
+            return Err(Error::NoCodec);
+        }
+        Ok(())
+    }
+}
+
+fn internal_error(err: Error) -> HttpResponse {
+    tracing::error!("Failed to access rooms: {}", err);
+    HttpResponse::InternalServerError().finish()
+}
+
+pub async fn list(store: web::Data<Store>) -> impl Responder {
+    match store.list() {
+        Ok(rooms) => HttpResponse::Ok().json(rooms),
+        Err(err) => internal_error(err),
+    }
+}
+
+pub async fn get(path: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    match store.get(&path.into_inner()) {
+        Ok(Some(room)) => HttpResponse::Ok().json(room),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(err) => internal_error(err),
+    }
+}
+
+pub async fn put(
+    path: web::Path<String>,
+    body: web::Json<RoomSettings>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let mut settings = body.into_inner();
+    settings.id = path.into_inner();
+    if let Err(err) = settings.validate() {
+        return HttpResponse::BadRequest().body(err.to_string());
+    }
+    match store.put(&settings) {
+        Ok(()) => HttpResponse::Ok().json(settings),
+        Err(err) => internal_error(err),
+    }
+}
+
+pub async fn delete(path: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    match store.delete(&path.into_inner()) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => internal_error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn participants_are_admitted_by_settings() {
+        let settings: RoomSettings = serde_json::from_value(serde_json::json!({
+            "password": "secret",
+            "maxParticipants": 2,
+            "moderators": ["token"],
+        }))
+        .unwrap();
+        assert_eq!(settings.authenticate(Some("secret"), None), Ok(false));
+        assert_eq!(
+            settings.authenticate(Some("wrong"), None),
+            Err(Rejection::WrongPassword)
+        );
+        assert_eq!(
+            settings.authenticate(None, None),
+            Err(Rejection::WrongPassword)
+        );
+        assert_eq!(settings.authenticate(None, Some("token")), Ok(true));
+        assert_eq!(settings.admit(1, false), Ok(false));
+        assert_eq!(settings.admit(2, false), Err(Rejection::Full));
+        assert_eq!(settings.admit(2, true), Ok(true));
+
+        let settings = RoomSettings::ad_hoc("room".to_owned(), Some("secret"), true);
+        assert_eq!(settings.authenticate(Some("secret"), None), Ok(false));
+        assert_eq!(settings.admit(0, false), Ok(true));
+        assert_eq!(settings.admit(1, false), Ok(false));
+    }
+
+    #[test]
+    fn passwords_are_hashed_with_pbkdf2() {
+        // RFC 7914 section 11, truncated to 32 bytes.
+        assert_eq!(
+            digest("passwd", "salt", 1),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        let password = Password::new("secret");
+        assert!(password
+            .hash()
+            .starts_with(&format!("{}$", PASSWORD_ITERATIONS)));
+        assert!(!password.hash().contains("secret"));
+        assert!(password.verify("secret"));
+        assert!(!password.verify("Secret"));
+        assert!(!password.verify(""));
+        assert_ne!(password.hash(), Password::new("secret").hash());
+        assert!(!Password::from_hash("salt$hash".to_owned()).verify("secret"));
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        let settings = |value| serde_json::from_value::<RoomSettings>(value).unwrap();
+        let valid = settings(serde_json::json!({
+            "allowedCodecs": ["audio/OPUS", "video/H264"],
+            "profile": "h264",
+        }));
+        assert!(valid.validate().is_ok());
+        let unknown = settings(serde_json::json!({ "allowedCodecs": ["video/theora"] }));
+        assert!(matches!(unknown.validate(), Err(Error::UnknownCodec(_))));
+        let disjoint = settings(serde_json::json!({
+            "allowedCodecs": ["video/VP8"],
+            "profile": "audioOnly",
+        }));
+        assert!(matches!(disjoint.validate(), Err(Error::NoCodec)));
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::{Error, Password, RecordingPolicy, RoomSettings};
+use crate::media::Profile;
+
+// The database is migrated from its user_version, which is the number of applied migrations.
+const MIGRATIONS: [&str; 3] = [
+    "CREATE TABLE IF NOT EXISTS rooms (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        password TEXT,
+        max_participants INTEGER,
+        allowed_codecs TEXT NOT NULL,
+        recording TEXT NOT NULL,
+        moderators TEXT NOT NULL
+    )",
+    "ALTER TABLE rooms ADD COLUMN profile TEXT;
+    ALTER TABLE rooms ADD COLUMN simulcast INTEGER;
+    ALTER TABLE rooms ADD COLUMN max_bitrate INTEGER;",
+    "ALTER TABLE rooms ADD COLUMN lobby INTEGER NOT NULL DEFAULT 0",
+];
+
+const COLUMNS: &str =
+    "id, name, password, max_participants, allowed_codecs, recording, moderators, \
+                       profile, simulcast, max_bitrate, lobby";
+
+/// Persistent rooms in a SQLite file. Lists are stored as JSON.
+pub struct Store {
+    connection: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Self::new(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(connection: Connection) -> Result<Self, Error> {
+        let version: usize =
+            connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            connection.execute_batch(migration)?;
+            connection.pragma_update(None, "user_version", i + 1)?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<RoomSettings>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare(&format!("SELECT {} FROM rooms ORDER BY id", COLUMNS))?;
+        let rows = statement.query_map([], columns)?;
+        rows.map(|row| row?.try_into()).collect()
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<RoomSettings>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let row = connection
+            .query_row(
+                &format!("SELECT {} FROM rooms WHERE id = ?1", COLUMNS),
+                [id],
+                columns,
+            )
+            .optional()?;
+        row.map(TryInto::try_into).transpose()
+    }
+
+    // Creates the room, or replaces all of its settings.
+    pub fn put(&self, settings: &RoomSettings) -> Result<(), Error> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            &format!(
+                "INSERT OR REPLACE INTO rooms ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                COLUMNS
+            ),
+            params![
+                settings.id,
+                settings.name,
+                settings.password.as_ref().map(Password::hash),
+                settings.max_participants,
+                serde_json::to_string(&settings.allowed_codecs)?,
+                settings.recording.as_str(),
+                serde_json::to_string(&settings.moderators)?,
+                settings.profile.map(|p| p.as_str()),
+                settings.simulcast,
+                settings.max_bitrate,
+                settings.lobby,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Returns false if the room does not exist.
+    pub fn delete(&self, id: &str) -> Result<bool, Error> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection.execute("DELETE FROM rooms WHERE id = ?1", [id])? > 0)
+    }
+}
+
+// Columns of a row, before JSON columns are parsed.
+struct Columns {
+    id: String,
+    name: String,
+    password: Option<String>,
+    max_participants: Option<usize>,
+    allowed_codecs: String,
+    recording: String,
+    moderators: String,
+    profile: Option<String>,
+    simulcast: Option<bool>,
+    max_bitrate: Option<u32>,
+    lobby: bool,
+}
+
+fn columns(row: &Row) -> rusqlite::Result<Columns> {
+    Ok(Columns {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        password: row.get(2)?,
+        max_participants: row.get(3)?,
+        allowed_codecs: row.get(4)?,
+        recording: row.get(5)?,
+        moderators: row.get(6)?,
+        profile: row.get(7)?,
+        simulcast: row.get(8)?,
+        max_bitrate: row.get(9)?,
+        lobby: row.get(10)?,
+    })
+}
+
+impl TryFrom<Columns> for RoomSettings {
+    type Error = Error;
+
+    fn try_from(columns: Columns) -> Result<Self, Error> {
+        Ok(Self {
+            id: columns.id,
+            name: columns.name,
+            password: columns.password.map(Password::from_hash),
+            max_participants: columns.max_participants,
+            allowed_codecs: serde_json::from_str(&columns.allowed_codecs)?,
+            recording: RecordingPolicy::parse(&columns.recording)?,
+            moderators: serde_json::from_str(&columns.moderators)?,
+            profile: columns
+                .profile
+                .map(|p| Profile::parse(&p).ok_or(Error::UnknownProfile(p)))
+                .transpose()?,
+            simulcast: columns.simulcast,
+            max_bitrate: columns.max_bitrate,
+            lobby: columns.lobby,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(id: &str) -> RoomSettings {
+        RoomSettings {
+            id: id.to_owned(),
+            name: "Weekly meeting".to_owned(),
+            password: Some(Password::new("secret")),
+            max_participants: Some(10),
+            allowed_codecs: vec!["audio/opus".to_owned(), "video/VP8".to_owned()],
+            recording: RecordingPolicy::Disabled,
+            moderators: vec!["token".to_owned()],
+            profile: Some(Profile::Vp9Svc),
+            simulcast: Some(false),
+            max_bitrate: Some(500_000),
+            lobby: true,
+        }
+    }
+
+    #[test]
+    fn rooms_are_stored() {
+        let store = Store::in_memory().unwrap();
+        store.put(&settings("b")).unwrap();
+        store.put(&settings("a")).unwrap();
+
+        let room = store.get("a").unwrap().unwrap();
+        assert_eq!(room.name, "Weekly meeting");
+        assert!(room.password.as_ref().unwrap().verify("secret"));
+        assert!(!room.password.as_ref().unwrap().verify("wrong"));
+        assert_eq!(room.max_participants, Some(10));
+        assert_eq!(room.allowed_codecs, vec!["audio/opus", "video/VP8"]);
+        assert_eq!(room.recording, RecordingPolicy::Disabled);
+        assert_eq!(room.moderators, vec!["token"]);
+        assert_eq!(room.profile, Some(Profile::Vp9Svc));
+        assert_eq!(room.simulcast, Some(false));
+        assert_eq!(room.max_bitrate, Some(500_000));
+        assert!(room.lobby);
+        let ids: Vec<_> = store.list().unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn rooms_are_replaced_and_deleted() {
+        let store = Store::in_memory().unwrap();
+        store.put(&settings("a")).unwrap();
+        let mut room = settings("a");
+        room.password = None;
+        room.max_participants = None;
+        store.put(&room).unwrap();
+
+        let room = store.get("a").unwrap().unwrap();
+        assert!(room.password.is_none());
+        assert_eq!(room.max_participants, None);
+        assert!(store.delete("a").unwrap());
+        assert!(!store.delete("a").unwrap());
+        assert!(store.get("a").unwrap().is_none());
+    }
+}
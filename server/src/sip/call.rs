@@ -0,0 +1,390 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(feature = "opus")]
+use crate::mixer;
+use rheomesh::error::Error;
+use tokio::{
+    net::UdpSocket,
+    sync::{oneshot, Mutex, Notify},
+    task::JoinHandle,
+};
+#[cfg(feature = "opus")]
+use webrtc::api::media_engine::MIME_TYPE_OPUS;
+use webrtc::{
+    rtp::{header::Header, packet::Packet},
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::{
+        track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocal, TrackLocalWriter},
+        track_remote::TrackRemote,
+    },
+    util::{Marshal, Unmarshal},
+};
+
+use super::sdp::Format;
+use crate::{
+    loopback::{self, LoopbackPublisher, LoopbackSubscriber},
+    room::{self, Reservation, Room, RoomOwner},
+    websocket::SendingMessage,
+};
+
+// Phones which disappear without BYE are hung up after this.
+const RTP_TIMEOUT: Duration = Duration::from_secs(30);
+const SELECT_INTERVAL: Duration = Duration::from_secs(1);
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// Media of a phone call. Caller audio is published to the room, and an audio publisher of the room
+/// is played back to the caller.
+pub struct Call {
+    room: Arc<Room>,
+    publisher: Arc<LoopbackPublisher>,
+    subscriber: Arc<LoopbackSubscriber>,
+    track_id: String,
+    tasks: Vec<JoinHandle<()>>,
+    ended: Arc<Notify>,
+}
+
+impl Call {
+    /// `remote` is the media address in the offer, and `peer` is the address which the call is signaled from.
+    pub async fn start(
+        room: Arc<Room>,
+        reservation: Reservation,
+        socket: UdpSocket,
+        remote: SocketAddr,
+        peer: IpAddr,
+        format: Format,
+    ) -> Result<Self, Error> {
+        let publisher = Arc::new(LoopbackPublisher::new(&room).await?);
+        let subscriber = match LoopbackSubscriber::new(&room).await {
+            Ok(subscriber) => Arc::new(subscriber),
+            Err(err) => {
+                publisher.close().await;
+                return Err(err);
+            }
+        };
+        room.add_call(reservation);
+
+        let socket = Arc::new(socket);
+        let peers = [remote.ip(), peer];
+        let remote = Arc::new(std::sync::Mutex::new(remote));
+        let ended = Arc::new(Notify::new());
+        let track_id = format!("sip-{}", uuid::Uuid::new_v4());
+        let track = Arc::new(TrackLocalStaticRTP::new(
+            RTCRtpCodecCapability {
+                mime_type: format.codec.mime_type().to_owned(),
+                clock_rate: format.codec.clock_rate(),
+                channels: format.codec.channels(),
+                sdp_fmtp_line: format.codec.fmtp().to_owned(),
+                rtcp_feedback: vec![],
+            },
+            track_id.clone(),
+            track_id.clone(),
+        ));
+
+        let (received, first_packet) = oneshot::channel();
+        let tasks = vec![
+            tokio::spawn(receive(
+                socket.clone(),
+                remote.clone(),
+                peers,
+                format,
+                track.clone(),
+                received,
+                ended.clone(),
+            )),
+            actix::spawn(publish(
+                room.clone(),
+                publisher.clone(),
+                track,
+                first_packet,
+            )),
+            actix::spawn(play(
+                room.clone(),
+                subscriber.clone(),
+                track_id.clone(),
+                RtpSender::new(socket, remote, format),
+            )),
+        ];
+
+        Ok(Self {
+            room,
+            publisher,
+            subscriber,
+            track_id,
+            tasks,
+            ended,
+        })
+    }
+
+    /// Notified when the caller stops sending media.
+    pub fn ended(&self) -> Arc<Notify> {
+        self.ended.clone()
+    }
+
+    pub async fn stop(&self, owner: &Mutex<RoomOwner>) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.subscriber.close().await;
+        self.publisher.close().await;
+        self.room
+            .links
+            .unpublish(&self.room.id, &self.track_id)
+            .await;
+        if self.room.remove_call() == 0 {
+            room::close(owner, &self.room).await;
+        }
+    }
+}
+
+async fn receive(
+    socket: Arc<UdpSocket>,
+    remote: Arc<std::sync::Mutex<SocketAddr>>,
+    peers: [IpAddr; 2],
+    format: Format,
+    track: Arc<TrackLocalStaticRTP>,
+    received: oneshot::Sender<()>,
+    ended: Arc<Notify>,
+) {
+    let mut received = Some(received);
+    let mut buf = vec![0u8; 1500];
+    let mut deadline = tokio::time::Instant::now() + RTP_TIMEOUT;
+    loop {
+        let (n, source) = match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        {
+            Ok(Ok(received)) => received,
+            Ok(Err(err)) => {
+                tracing::error!("Failed to receive RTP from the caller: {}", err);
+                break;
+            }
+            Err(_) => {
+                tracing::info!("No RTP from the caller for {:?}", RTP_TIMEOUT);
+                break;
+            }
+        };
+        // Anybody can send to the port, so only the caller is accepted, from the address in the offer or the one
+        // which signals the call.
+        if !peers.contains(&source.ip().to_canonical()) {
+            tracing::debug!("Ignored RTP from {}, which is not the caller", source);
+            continue;
+        }
+        deadline = tokio::time::Instant::now() + RTP_TIMEOUT;
+        // Callers behind NAT can only receive media on the address they send from.
+        *remote.lock().unwrap() = source;
+        let Ok(packet) = Packet::unmarshal(&mut &buf[..n]) else {
+            continue;
+        };
+        // Other payload types, like telephone-event, are not forwarded.
+        if packet.header.payload_type != format.payload_type {
+            continue;
+        }
+        if let Err(err) = track.write_rtp(&packet).await {
+            tracing::trace!("Failed to write caller RTP: {}", err);
+        }
+        if let Some(received) = received.take() {
+            let _ = received.send(());
+        }
+    }
+    ended.notify_one();
+}
+
+// The router creates a publisher only after media arrives, so it waits for the first packet.
+async fn publish(
+    room: Arc<Room>,
+    publisher: Arc<LoopbackPublisher>,
+    track: Arc<TrackLocalStaticRTP>,
+    first_packet: oneshot::Receiver<()>,
+) {
+    if first_packet.await.is_err() {
+        return;
+    }
+    let track_id = track.id().to_owned();
+    match publisher.publish(track).await {
+        Ok(published) => {
+            tracing::info!("Caller is published to room {}: {}", room.id, track_id);
+            room.broadcast(SendingMessage::Published {
+                publisher_ids: vec![track_id.clone()],
+            });
+            room.links.publish(&room.id, track_id, published).await;
+        }
+        Err(err) => tracing::error!("Failed to publish caller audio: {}", err),
+    }
+}
+
+// Plays the room mix if the mixer is running, otherwise the newest audio publisher in the room,
+// until it is unpublished.
+async fn play(
+    room: Arc<Room>,
+    subscriber: Arc<LoopbackSubscriber>,
+    own_track_id: String,
+    mut sender: RtpSender,
+) {
+    loop {
+        let publisher_ids = room.audio_publisher_ids().await;
+        #[cfg(feature = "opus")]
+        let publisher_ids = {
+            let mix_id = mixer::mix_id_without(&own_track_id);
+            if publisher_ids.contains(&mix_id) {
+                vec![mix_id]
+            } else {
+                publisher_ids
+                    .into_iter()
+                    .filter(|id| !mixer::is_mix(id))
+                    .collect()
+            }
+        };
+        let publisher_id = publisher_ids.into_iter().rfind(|id| id != &own_track_id);
+        let Some(publisher_id) = publisher_id else {
+            tokio::time::sleep(SELECT_INTERVAL).await;
+            continue;
+        };
+        let track = match subscriber.subscribe(&publisher_id).await {
+            Ok(track) => track,
+            Err(err) => {
+                tracing::error!(
+                    "Failed to subscribe {} for the caller: {}",
+                    publisher_id,
+                    err
+                );
+                tokio::time::sleep(SELECT_INTERVAL).await;
+                continue;
+            }
+        };
+        tracing::info!("Playing {} to the caller", publisher_id);
+        sender.switch_source();
+        forward(&track, &mut sender).await;
+        subscriber.unsubscribe(&publisher_id).await;
+    }
+}
+
+async fn forward(track: &TrackRemote, sender: &mut RtpSender) {
+    let mime_type = loopback::mime_type(track);
+    if mime_type.eq_ignore_ascii_case(sender.format.codec.mime_type()) {
+        while let Ok((packet, _)) = track.read_rtp().await {
+            sender
+                .send(packet.header.timestamp, 1, packet.payload.to_vec())
+                .await;
+        }
+        return;
+    }
+
+    #[cfg(feature = "opus")]
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+        transcode(track, sender).await;
+        return;
+    }
+
+    tracing::warn!(
+        "{} can not be played to the caller which uses {:?}",
+        mime_type,
+        sender.format.codec
+    );
+    // Keep reading, so the next publisher is selected when this one is unpublished.
+    while track.read_rtp().await.is_ok() {}
+}
+
+#[cfg(feature = "opus")]
+async fn transcode(track: &TrackRemote, sender: &mut RtpSender) {
+    use super::sdp::Codec;
+    use crate::audio;
+
+    let mut decoder = match audio::OpusDecoder::new() {
+        Ok(decoder) => decoder,
+        Err(err) => {
+            tracing::error!("Failed to create Opus decoder: {}", err);
+            return;
+        }
+    };
+    let factor = (audio::SAMPLE_RATE / sender.format.codec.clock_rate()) as usize;
+    let encode = match sender.format.codec {
+        Codec::Pcma => audio::encode_alaw,
+        _ => audio::encode_ulaw,
+    };
+    while let Ok((packet, _)) = track.read_rtp().await {
+        let samples = match decoder.decode(&packet.payload) {
+            Ok(samples) => samples,
+            Err(err) => {
+                tracing::trace!("Failed to decode Opus: {}", err);
+                continue;
+            }
+        };
+        let payload = audio::downsample(&samples, factor)
+            .into_iter()
+            .map(encode)
+            .collect();
+        sender
+            .send(packet.header.timestamp, factor as u32, payload)
+            .await;
+    }
+}
+
+/// Sends RTP to the caller as a single stream, even when the played publisher changes.
+struct RtpSender {
+    socket: Arc<UdpSocket>,
+    remote: Arc<std::sync::Mutex<SocketAddr>>,
+    format: Format,
+    ssrc: u32,
+    sequence_number: u16,
+    timestamp: u32,
+    source_timestamp: Option<u32>,
+    marker: bool,
+}
+
+impl RtpSender {
+    fn new(
+        socket: Arc<UdpSocket>,
+        remote: Arc<std::sync::Mutex<SocketAddr>>,
+        format: Format,
+    ) -> Self {
+        let random = uuid::Uuid::new_v4().as_u128();
+        Self {
+            socket,
+            remote,
+            format,
+            ssrc: random as u32,
+            sequence_number: (random >> 32) as u16,
+            timestamp: (random >> 64) as u32,
+            source_timestamp: None,
+            marker: true,
+        }
+    }
+
+    fn switch_source(&mut self) {
+        self.source_timestamp = None;
+        self.marker = true;
+    }
+
+    // `scale` converts source timestamps into the clock rate of the caller.
+    async fn send(&mut self, source_timestamp: u32, scale: u32, payload: Vec<u8>) {
+        let elapsed = match self.source_timestamp {
+            Some(previous) => source_timestamp.wrapping_sub(previous) / scale,
+            None => self.format.codec.clock_rate() * FRAME_DURATION.as_millis() as u32 / 1000,
+        };
+        self.source_timestamp = Some(source_timestamp);
+        self.timestamp = self.timestamp.wrapping_add(elapsed);
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        let packet = Packet {
+            header: Header {
+                version: 2,
+                marker: std::mem::take(&mut self.marker),
+                payload_type: self.format.payload_type,
+                sequence_number: self.sequence_number,
+                timestamp: self.timestamp,
+                ssrc: self.ssrc,
+                ..Default::default()
+            },
+            payload: payload.into(),
+        };
+        let Ok(data) = packet.marshal() else {
+            return;
+        };
+        let remote = *self.remote.lock().unwrap();
+        if let Err(err) = self.socket.send_to(&data, remote).await {
+            tracing::trace!("Failed to send RTP to the caller: {}", err);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Minimal SIP message parser and builder (RFC 3261), enough for a UAS over UDP.
+use std::fmt::Write;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// Compact forms defined in RFC 3261 section 7.3.3.
+fn canonical_name(name: &str) -> &str {
+    match name {
+        "v" => "Via",
+        "f" => "From",
+        "t" => "To",
+        "i" => "Call-ID",
+        "m" => "Contact",
+        "l" => "Content-Length",
+        "c" => "Content-Type",
+        _ => name,
+    }
+}
+
+impl Request {
+    /// Parses a request. Responses and malformed messages are ignored.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(data).ok()?;
+        let (head, body) = text
+            .split_once("\r\n\r\n")
+            .or_else(|| text.split_once("\n\n"))
+            .unwrap_or((text, ""));
+        let mut lines = head.lines();
+        let mut request_line = lines.next()?.split_whitespace();
+        let method = request_line.next()?.to_owned();
+        let uri = request_line.next()?.to_owned();
+        if request_line.next() != Some("SIP/2.0") {
+            return None;
+        }
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in lines {
+            // Folded header lines continue the previous value.
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if let Some((_, value)) = headers.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+                continue;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            headers.push((
+                canonical_name(name.trim()).to_owned(),
+                value.trim().to_owned(),
+            ));
+        }
+
+        let mut request = Self {
+            method,
+            uri,
+            headers,
+            body: body.as_bytes().to_vec(),
+        };
+        if let Some(length) = request
+            .header("Content-Length")
+            .and_then(|l| l.parse::<usize>().ok())
+        {
+            request.body.truncate(length);
+        }
+        Some(request)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn headers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn call_id(&self) -> Option<&str> {
+        self.header("Call-ID")
+    }
+
+    /// The user part of the Request-URI, like `room` in `sip:room@example.com`.
+    pub fn user(&self) -> Option<&str> {
+        let uri = self
+            .uri
+            .strip_prefix("sip:")
+            .or_else(|| self.uri.strip_prefix("sips:"))?;
+        let (user, _) = uri.split_once('@')?;
+        let user = user.split(';').next()?;
+        (!user.is_empty()).then_some(user)
+    }
+
+    pub fn to_tag(&self) -> Option<&str> {
+        let to = self.header("To")?;
+        to.split(';')
+            .skip(1)
+            .find_map(|p| p.trim().strip_prefix("tag="))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(String, String)>,
+    body: Option<(&'static str, String)>,
+}
+
+impl Response {
+    /// Builds a response which copies the headers required by RFC 3261 section 8.2.6.2.
+    /// `tag` is added to the To header if the request does not have one yet.
+    pub fn new(request: &Request, status: u16, reason: &'static str, tag: Option<&str>) -> Self {
+        let mut headers: Vec<(String, String)> = request
+            .headers("Via")
+            .map(|v| ("Via".to_owned(), v.to_owned()))
+            .collect();
+        if let Some(from) = request.header("From") {
+            headers.push(("From".to_owned(), from.to_owned()));
+        }
+        if let Some(to) = request.header("To") {
+            let to = match tag {
+                Some(tag) if request.to_tag().is_none() => format!("{};tag={}", to, tag),
+                _ => to.to_owned(),
+            };
+            headers.push(("To".to_owned(), to));
+        }
+        for name in ["Call-ID", "CSeq"] {
+            if let Some(value) = request.header(name) {
+                headers.push((name.to_owned(), value.to_owned()));
+            }
+        }
+        Self {
+            status,
+            reason,
+            headers,
+            body: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: String) -> Self {
+        self.headers.push((name.to_owned(), value));
+        self
+    }
+
+    pub fn body(mut self, content_type: &'static str, body: String) -> Self {
+        self.body = Some((content_type, body));
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = String::new();
+        let _ = write!(message, "SIP/2.0 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            let _ = write!(message, "{}: {}\r\n", name, value);
+        }
+        let _ = write!(message, "Server: livecamera\r\n");
+        match &self.body {
+            Some((content_type, body)) => {
+                let _ = write!(message, "Content-Type: {}\r\n", content_type);
+                let _ = write!(message, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+            }
+            None => {
+                let _ = write!(message, "Content-Length: 0\r\n\r\n");
+            }
+        }
+        message.into_bytes()
+    }
+}
+
+/// Builds BYE for a dialog established by `invite`, to hang up from the server side.
+pub fn bye(invite: &Request, tag: &str, host: &str, branch: &str) -> Option<Vec<u8>> {
+    let contact = invite.header("Contact")?;
+    let uri = match contact.split_once('<') {
+        Some((_, rest)) => rest.split('>').next()?,
+        None => contact.split(';').next()?,
+    };
+    let to = invite.header("To")?;
+    let to = match invite.to_tag() {
+        Some(_) => to.to_owned(),
+        None => format!("{};tag={}", to, tag),
+    };
+    let mut message = String::new();
+    let _ = write!(message, "BYE {} SIP/2.0\r\n", uri.trim());
+    let _ = write!(
+        message,
+        "Via: SIP/2.0/UDP {};branch=z9hG4bK{};rport\r\n",
+        host, branch
+    );
+    let _ = write!(message, "Max-Forwards: 70\r\n");
+    let _ = write!(message, "From: {}\r\n", to);
+    let _ = write!(message, "To: {}\r\n", invite.header("From")?);
+    let _ = write!(message, "Call-ID: {}\r\n", invite.call_id()?);
+    let _ = write!(message, "CSeq: 1 BYE\r\n");
+    let _ = write!(message, "Content-Length: 0\r\n\r\n");
+    Some(message.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVITE: &str = "INVITE sip:lobby@example.com;transport=udp SIP/2.0\r\n\
+        v: SIP/2.0/UDP 192.0.2.10:5060;branch=z9hG4bK1\r\n\
+        Via: SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bK2\r\n\
+        f: <sip:alice@example.com>;tag=a1\r\n\
+        t: <sip:lobby@example.com>\r\n\
+        i: call-1\r\n\
+        CSeq: 1 INVITE\r\n\
+        m: <sip:alice@192.0.2.10:5060>\r\n\
+        Subject: a long\r\n \t subject\r\n\
+        l: 4\r\n\
+        \r\n\
+        v=0\r\nignored";
+
+    #[test]
+    fn parses_requests() {
+        let request = Request::parse(INVITE.as_bytes()).unwrap();
+        assert_eq!(request.method, "INVITE");
+        assert_eq!(request.user(), Some("lobby"));
+        assert_eq!(request.call_id(), Some("call-1"));
+        assert_eq!(request.header("content-length"), Some("4"));
+        assert_eq!(request.header("Subject"), Some("a long subject"));
+        assert_eq!(request.headers("Via").count(), 2);
+        assert_eq!(request.to_tag(), None);
+        assert_eq!(request.body, b"v=0\r");
+        assert!(Request::parse(b"SIP/2.0 200 OK\r\nCSeq: 1 INVITE\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn builds_responses() {
+        let request = Request::parse(INVITE.as_bytes()).unwrap();
+        let response = String::from_utf8(
+            Response::new(&request, 200, "OK", Some("b2"))
+                .body("application/sdp", "v=0\r\n".to_owned())
+                .to_bytes(),
+        )
+        .unwrap();
+        assert!(response.starts_with("SIP/2.0 200 OK\r\n"));
+        assert!(response.contains("Via: SIP/2.0/UDP 192.0.2.10:5060;branch=z9hG4bK1\r\nVia: SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bK2\r\n"));
+        assert!(response.contains("From: <sip:alice@example.com>;tag=a1\r\n"));
+        assert!(response.contains("To: <sip:lobby@example.com>;tag=b2\r\n"));
+        assert!(response.contains("Call-ID: call-1\r\nCSeq: 1 INVITE\r\n"));
+        assert!(response.ends_with("Content-Length: 5\r\n\r\nv=0\r\n"));
+
+        let bye = String::from_utf8(bye(&request, "b2", "192.0.2.2:5060", "3").unwrap()).unwrap();
+        assert!(bye.starts_with("BYE sip:alice@192.0.2.10:5060 SIP/2.0\r\n"));
+        assert!(bye.contains("From: <sip:lobby@example.com>;tag=b2\r\n"));
+        assert!(bye.contains("To: <sip:alice@example.com>;tag=a1\r\n"));
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix_web::web::Data;
+use tokio::{net::UdpSocket, sync::Mutex};
+
+use crate::{room, settings};
+
+mod call;
+mod message;
+mod sdp;
+
+use call::Call;
+use message::{Request, Response};
+
+// Retransmission timers of RFC 3261 section 17.2.1.
+const T1: Duration = Duration::from_millis(500);
+const T2: Duration = Duration::from_secs(4);
+const TIMER_H: Duration = Duration::from_secs(32);
+const ALLOW: &str = "INVITE, ACK, BYE, CANCEL, OPTIONS";
+
+struct Dialog {
+    tag: String,
+    contact: String,
+    answer: String,
+    acked: Arc<AtomicBool>,
+    bye: Option<(Vec<u8>, SocketAddr)>,
+    call: Arc<Call>,
+}
+
+/// A minimal SIP UAS. The user part of the dialed URI is used as the room ID, like `sip:myroom@example.com`.
+pub struct Gateway {
+    socket: UdpSocket,
+    ip: Ipv4Addr,
+    port: u16,
+    owner: Data<Mutex<room::RoomOwner>>,
+    dialogs: Mutex<HashMap<String, Dialog>>,
+    // Call-IDs of INVITEs whose calls are being started, and whether they are cancelled meanwhile.
+    starting: std::sync::Mutex<HashMap<String, bool>>,
+}
+
+pub async fn serve(
+    port: u16,
+    ip: Ipv4Addr,
+    owner: Data<Mutex<room::RoomOwner>>,
+) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+    tracing::info!("SIP gateway is listening on {}", port);
+    let gateway = Arc::new(Gateway {
+        socket,
+        ip,
+        port,
+        owner,
+        dialogs: Mutex::new(HashMap::new()),
+        starting: std::sync::Mutex::new(HashMap::new()),
+    });
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (n, source) = gateway.socket.recv_from(&mut buf).await?;
+        match Request::parse(&buf[..n]) {
+            Some(request) => gateway.handle(request, source).await,
+            // Keep-alives and responses to BYE are ignored.
+            None => tracing::trace!("Ignored SIP message from {}", source),
+        }
+    }
+}
+
+impl Gateway {
+    async fn send(&self, data: &[u8], target: SocketAddr) {
+        if let Err(err) = self.socket.send_to(data, target).await {
+            tracing::error!("Failed to send SIP message to {}: {}", target, err);
+        }
+    }
+
+    async fn respond(&self, response: Response, target: SocketAddr) {
+        self.send(&response.to_bytes(), target).await;
+    }
+
+    async fn handle(self: &Arc<Self>, request: Request, source: SocketAddr) {
+        tracing::debug!("SIP {} {} from {}", request.method, request.uri, source);
+        let Some(call_id) = request.call_id().map(str::to_owned) else {
+            return;
+        };
+        match request.method.as_str() {
+            // Starting a call negotiates with the room, so other messages are not kept waiting.
+            "INVITE" => {
+                let gateway = self.clone();
+                actix::spawn(async move { gateway.invite(request, call_id, source).await });
+            }
+            "ACK" => {
+                if let Some(dialog) = self.dialogs.lock().await.get(&call_id) {
+                    dialog.acked.store(true, Ordering::Relaxed);
+                }
+            }
+            "BYE" => {
+                let dialog = self.dialogs.lock().await.remove(&call_id);
+                match dialog {
+                    Some(dialog) => {
+                        self.respond(Response::new(&request, 200, "OK", None), source)
+                            .await;
+                        dialog.call.stop(&self.owner).await;
+                        tracing::info!("SIP call {} is finished", call_id);
+                    }
+                    None => {
+                        let response = Response::new(&request, 481, "Call Does Not Exist", None);
+                        self.respond(response, source).await;
+                    }
+                }
+            }
+            // An INVITE can only be cancelled while its call is being started. Established calls are left to BYE.
+            "CANCEL" => {
+                let cancelled = match self.starting.lock().unwrap().get_mut(&call_id) {
+                    Some(cancelled) => {
+                        *cancelled = true;
+                        true
+                    }
+                    None => false,
+                };
+                let exists = cancelled || self.dialogs.lock().await.contains_key(&call_id);
+                let response = if exists {
+                    Response::new(&request, 200, "OK", None)
+                } else {
+                    Response::new(&request, 481, "Call Does Not Exist", None)
+                };
+                self.respond(response, source).await;
+            }
+            "OPTIONS" => {
+                let response =
+                    Response::new(&request, 200, "OK", None).header("Allow", ALLOW.to_owned());
+                self.respond(response, source).await;
+            }
+            _ => {
+                let response = Response::new(&request, 405, "Method Not Allowed", None)
+                    .header("Allow", ALLOW.to_owned());
+                self.respond(response, source).await;
+            }
+        }
+    }
+
+    async fn invite(self: &Arc<Self>, request: Request, call_id: String, source: SocketAddr) {
+        // Retransmitted INVITE and re-INVITE get the same answer.
+        if let Some(dialog) = self.dialogs.lock().await.get(&call_id) {
+            let response = Response::new(&request, 200, "OK", Some(&dialog.tag))
+                .header("Contact", dialog.contact.clone())
+                .body("application/sdp", dialog.answer.clone());
+            self.respond(response, source).await;
+            return;
+        }
+        // Retransmissions while the call is being started are only acknowledged.
+        let started = self
+            .starting
+            .lock()
+            .unwrap()
+            .insert(call_id.clone(), false)
+            .is_none();
+        self.respond(Response::new(&request, 100, "Trying", None), source)
+            .await;
+        if !started {
+            return;
+        }
+        let call = self.start_call(&request, source).await;
+        let cancelled = self.starting.lock().unwrap().get(&call_id) == Some(&true);
+        let Some((room_id, offer, call, rtp_port)) = call else {
+            self.starting.lock().unwrap().remove(&call_id);
+            return;
+        };
+        if cancelled {
+            self.starting.lock().unwrap().remove(&call_id);
+            call.stop(&self.owner).await;
+            let response = Response::new(&request, 487, "Request Terminated", None);
+            self.respond(response, source).await;
+            return;
+        }
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let tag = id[..16].to_owned();
+        let contact = format!("<sip:{}@{}:{}>", room_id, self.ip, self.port);
+        let answer = sdp::answer(
+            self.ip,
+            rtp_port,
+            u64::from_str_radix(&id[16..24], 16).unwrap_or(0),
+            &offer.format,
+        );
+        let bye = message::bye(
+            &request,
+            &tag,
+            &format!("{}:{}", self.ip, self.port),
+            &id[16..],
+        )
+        .map(|bye| (bye, source));
+        let response = Response::new(&request, 200, "OK", Some(&tag))
+            .header("Contact", contact.clone())
+            .body("application/sdp", answer.clone());
+        let acked = Arc::new(AtomicBool::new(false));
+        let call = Arc::new(call);
+        self.dialogs.lock().await.insert(
+            call_id.clone(),
+            Dialog {
+                tag,
+                contact,
+                answer,
+                acked: acked.clone(),
+                bye,
+                call: call.clone(),
+            },
+        );
+        // Retransmissions are answered from the dialog from now on.
+        self.starting.lock().unwrap().remove(&call_id);
+        tracing::info!(
+            "SIP call {} joined room {} with {:?}",
+            call_id,
+            room_id,
+            offer.format.codec
+        );
+
+        // 200 OK is retransmitted until ACK arrives.
+        let response = response.to_bytes();
+        self.send(&response, source).await;
+        let gateway = Arc::downgrade(self);
+        let id = call_id.clone();
+        actix::spawn(async move {
+            let mut interval = T1;
+            let mut elapsed = Duration::ZERO;
+            while elapsed < TIMER_H {
+                tokio::time::sleep(interval).await;
+                elapsed += interval;
+                interval = (interval * 2).min(T2);
+                let Some(gateway) = gateway.upgrade() else {
+                    return;
+                };
+                if acked.load(Ordering::Relaxed) {
+                    return;
+                }
+                gateway.send(&response, source).await;
+            }
+            if let Some(gateway) = gateway.upgrade() {
+                tracing::info!("ACK is not received for SIP call {}", id);
+                gateway.hang_up(&id).await;
+            }
+        });
+
+        let gateway = Arc::downgrade(self);
+        let ended = call.ended();
+        actix::spawn(async move {
+            ended.notified().await;
+            if let Some(gateway) = gateway.upgrade() {
+                gateway.hang_up(&call_id).await;
+            }
+        });
+    }
+
+    // Places the room of the dialed user and starts the call. Failures are responded to here.
+    async fn start_call(
+        &self,
+        request: &Request,
+        source: SocketAddr,
+    ) -> Option<(String, sdp::Offer, Call, u16)> {
+        let Some(room_id) = request.user().map(str::to_owned) else {
+            self.respond(Response::new(request, 404, "Not Found", None), source)
+                .await;
+            return None;
+        };
+        let Some(offer) = sdp::parse_offer(&request.body) else {
+            let response = Response::new(request, 488, "Not Acceptable Here", None);
+            self.respond(response, source).await;
+            return None;
+        };
+
+        let placement =
+            room::find_or_create_room(&self.owner, room_id.clone(), room::RoomOptions::default())
+                .await;
+        // Calls are not routed between nodes, so phones have to call the home node of the room.
+        let room = match placement {
+            Ok(room::Placement::Local(room)) => room,
+            Ok(room::Placement::Remote(node_url)) => {
+                tracing::warn!("Room {} of SIP call is on {}", room_id, node_url);
+                let response = Response::new(request, 503, "Service Unavailable", None);
+                self.respond(response, source).await;
+                return None;
+            }
+            Err(err) => {
+                tracing::error!("Failed to place room {}: {}", room_id, err);
+                let response = Response::new(request, 503, "Service Unavailable", None);
+                self.respond(response, source).await;
+                return None;
+            }
+        };
+        // Phones can not give a password or wait in the lobby, so they only join rooms without them.
+        let admission = match room.admit(None, None) {
+            Ok(reservation) if room.settings.lobby && !reservation.moderator => {
+                Err(settings::Rejection::Lobby)
+            }
+            admission => admission,
+        };
+        let reservation = match admission {
+            Ok(reservation) => reservation,
+            Err(rejection) => {
+                tracing::warn!("SIP call is rejected from room {}: {}", room_id, rejection);
+                if room.is_empty() {
+                    room::close(&self.owner, &room).await;
+                }
+                let response = match rejection {
+                    settings::Rejection::WrongPassword | settings::Rejection::Lobby => {
+                        Response::new(request, 403, "Forbidden", None)
+                    }
+                    settings::Rejection::Full => Response::new(request, 486, "Busy Here", None),
+                };
+                self.respond(response, source).await;
+                return None;
+            }
+        };
+        let call = async {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.ok()?;
+            let port = socket.local_addr().ok()?.port();
+            let call = Call::start(
+                room,
+                reservation,
+                socket,
+                offer.address,
+                source.ip(),
+                offer.format,
+            );
+            match call.await {
+                Ok(call) => Some((call, port)),
+                Err(err) => {
+                    tracing::error!("Failed to start SIP call: {}", err);
+                    None
+                }
+            }
+        }
+        .await;
+        let Some((call, rtp_port)) = call else {
+            let response = Response::new(request, 500, "Server Internal Error", None);
+            self.respond(response, source).await;
+            return None;
+        };
+        Some((room_id, offer, call, rtp_port))
+    }
+
+    async fn hang_up(&self, call_id: &str) {
+        let Some(dialog) = self.dialogs.lock().await.remove(call_id) else {
+            return;
+        };
+        if let Some((bye, target)) = &dialog.bye {
+            self.send(bye, *target).await;
+        }
+        dialog.call.stop(&self.owner).await;
+        tracing::info!("SIP call {} is hung up", call_id);
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use webrtc::{
+    api::media_engine::{MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU},
+    sdp::SessionDescription,
+};
+
+// Codecs which browsers decode natively, so caller audio is published without transcoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Opus,
+    Pcmu,
+    Pcma,
+}
+
+impl Codec {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Codec::Opus => MIME_TYPE_OPUS,
+            Codec::Pcmu => MIME_TYPE_PCMU,
+            Codec::Pcma => MIME_TYPE_PCMA,
+        }
+    }
+
+    pub fn clock_rate(&self) -> u32 {
+        match self {
+            Codec::Opus => 48000,
+            Codec::Pcmu | Codec::Pcma => 8000,
+        }
+    }
+
+    pub fn channels(&self) -> u16 {
+        match self {
+            Codec::Opus => 2,
+            Codec::Pcmu | Codec::Pcma => 1,
+        }
+    }
+
+    pub fn fmtp(&self) -> &'static str {
+        match self {
+            Codec::Opus => "minptime=10;useinbandfec=1",
+            Codec::Pcmu | Codec::Pcma => "",
+        }
+    }
+
+    fn rtpmap(&self) -> &'static str {
+        match self {
+            Codec::Opus => "opus/48000/2",
+            Codec::Pcmu => "PCMU/8000",
+            Codec::Pcma => "PCMA/8000",
+        }
+    }
+
+    fn from_rtpmap(rtpmap: &str) -> Option<Self> {
+        let name = rtpmap.split('/').next()?;
+        match name.to_ascii_lowercase().as_str() {
+            "opus" => Some(Codec::Opus),
+            "pcmu" => Some(Codec::Pcmu),
+            "pcma" => Some(Codec::Pcma),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    pub codec: Codec,
+    pub payload_type: u8,
+}
+
+#[derive(Debug)]
+pub struct Offer {
+    pub address: SocketAddr,
+    pub format: Format,
+}
+
+/// Picks the audio stream and the codec from an SDP offer. Opus is preferred, because rooms use it.
+pub fn parse_offer(body: &[u8]) -> Option<Offer> {
+    let session = SessionDescription::unmarshal(&mut Cursor::new(body)).ok()?;
+    let media = session
+        .media_descriptions
+        .iter()
+        .find(|m| m.media_name.media == "audio" && m.media_name.port.value != 0)?;
+    let address = media
+        .connection_information
+        .as_ref()
+        .or(session.connection_information.as_ref())
+        .and_then(|c| c.address.as_ref())
+        .and_then(|a| a.address.parse::<IpAddr>().ok())?;
+    let port = u16::try_from(media.media_name.port.value).ok()?;
+
+    let formats: Vec<Format> = media
+        .media_name
+        .formats
+        .iter()
+        .filter_map(|format| {
+            let payload_type = format.parse::<u8>().ok()?;
+            let rtpmap = media.attributes.iter().find_map(|a| {
+                let value = a.value.as_deref().filter(|_| a.key == "rtpmap")?;
+                let (pt, rtpmap) = value.split_once(' ')?;
+                (pt == format).then_some(rtpmap)
+            });
+            // Static payload types may be used without rtpmap.
+            let codec = match (payload_type, rtpmap) {
+                (_, Some(rtpmap)) => Codec::from_rtpmap(rtpmap)?,
+                (0, None) => Codec::Pcmu,
+                (8, None) => Codec::Pcma,
+                _ => return None,
+            };
+            Some(Format {
+                codec,
+                payload_type,
+            })
+        })
+        .collect();
+    let format = [Codec::Opus, Codec::Pcmu, Codec::Pcma]
+        .iter()
+        .find_map(|codec| formats.iter().find(|f| f.codec == *codec))?;
+
+    Some(Offer {
+        address: SocketAddr::new(address, port),
+        format: *format,
+    })
+}
+
+pub fn answer(ip: Ipv4Addr, port: u16, session_id: u64, format: &Format) -> String {
+    let pt = format.payload_type;
+    let mut sdp = format!(
+        "v=0\r\n\
+         o=livecamera {session_id} {session_id} IN IP4 {ip}\r\n\
+         s=livecamera\r\n\
+         c=IN IP4 {ip}\r\n\
+         t=0 0\r\n\
+         m=audio {port} RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} {}\r\n",
+        format.codec.rtpmap()
+    );
+    if !format.codec.fmtp().is_empty() {
+        sdp.push_str(&format!("a=fmtp:{} {}\r\n", pt, format.codec.fmtp()));
+    }
+    sdp.push_str("a=ptime:20\r\na=sendrecv\r\n");
+    sdp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_opus() {
+        let offer = parse_offer(
+            b"v=0\r\n\
+              o=- 1 1 IN IP4 192.0.2.10\r\n\
+              s=-\r\n\
+              c=IN IP4 192.0.2.10\r\n\
+              t=0 0\r\n\
+              m=video 0 RTP/AVP 96\r\n\
+              m=audio 4000 RTP/AVP 0 8 111 101\r\n\
+              a=rtpmap:111 opus/48000/2\r\n\
+              a=rtpmap:101 telephone-event/8000\r\n",
+        )
+        .unwrap();
+        assert_eq!(offer.address, "192.0.2.10:4000".parse().unwrap());
+        assert_eq!(offer.format.codec, Codec::Opus);
+        assert_eq!(offer.format.payload_type, 111);
+
+        let offer = parse_offer(
+            b"v=0\r\n\
+              o=- 1 1 IN IP4 192.0.2.10\r\n\
+              s=-\r\n\
+              t=0 0\r\n\
+              m=audio 0 RTP/AVP 0\r\n\
+              m=audio 4002 RTP/AVP 18 8\r\n\
+              c=IN IP4 192.0.2.11\r\n",
+        )
+        .unwrap();
+        assert_eq!(offer.address, "192.0.2.11:4002".parse().unwrap());
+        assert_eq!(offer.format.codec, Codec::Pcma);
+    }
+
+    #[test]
+    fn answers() {
+        let opus = answer(
+            Ipv4Addr::new(192, 0, 2, 2),
+            31000,
+            7,
+            &Format {
+                codec: Codec::Opus,
+                payload_type: 111,
+            },
+        );
+        assert!(opus.contains("c=IN IP4 192.0.2.2\r\n"));
+        assert!(opus.contains("m=audio 31000 RTP/AVP 111\r\na=rtpmap:111 opus/48000/2\r\n"));
+        assert!(opus.contains("a=fmtp:111 minptime=10;useinbandfec=1\r\n"));
+
+        let pcmu = answer(
+            Ipv4Addr::new(192, 0, 2, 2),
+            31000,
+            7,
+            &Format {
+                codec: Codec::Pcmu,
+                payload_type: 0,
+            },
+        );
+        assert!(pcmu.contains("a=rtpmap:0 PCMU/8000\r\n"));
+        assert!(!pcmu.contains("a=fmtp"));
+        assert!(parse_offer(pcmu.as_bytes()).is_some());
+    }
+}
@@ -0,0 +1,282 @@
+// Active speaker detection from audio levels which clients put in RTP header extensions (RFC 6464).
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::task::JoinHandle;
+use webrtc::{sdp::extmap::AUDIO_LEVEL_URI, track::track_remote::TrackRemote};
+
+use crate::{loopback::LoopbackSubscriber, room::Room, websocket::SendingMessage};
+
+const SELECT_INTERVAL: Duration = Duration::from_secs(1);
+// Browsers stop sending audio while muted or with DTX, so an old level means silence.
+const SILENCE_TIMEOUT: Duration = Duration::from_millis(500);
+// Loudness is 127 minus the level in -dBov, so this is -50dBov.
+const SPEECH_LOUDNESS: f32 = 77.0;
+// Another speaker becomes dominant after being louder by this margin for SWITCH_DELAY.
+const HYSTERESIS: f32 = 6.0;
+const SWITCH_DELAY: Duration = Duration::from_millis(1000);
+const SMOOTHING: f32 = 0.2;
+// A single publisher is always the active speaker, so nothing is received until there are two.
+const MIN_PUBLISHERS: usize = 2;
+
+struct Level {
+    loudness: f32,
+    updated: Instant,
+}
+
+type Levels = Arc<std::sync::Mutex<HashMap<String, Level>>>;
+type Subscriber = Arc<std::sync::Mutex<Option<Arc<LoopbackSubscriber>>>>;
+
+/// Follows audio levels of every audio publisher in a room, and sends active speakers to the room.
+pub struct Detector {
+    subscriber: Subscriber,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Detector {
+    pub fn start(room: &Arc<Room>, interval: Duration) -> Self {
+        let subscriber = Subscriber::default();
+        let levels = Levels::default();
+        let tasks = vec![
+            actix::spawn(select(room.clone(), subscriber.clone(), levels.clone())),
+            tokio::spawn(report(room.clone(), levels, interval)),
+        ];
+        Self { subscriber, tasks }
+    }
+
+    pub async fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        let subscriber = self.subscriber.lock().unwrap().take();
+        if let Some(subscriber) = subscriber {
+            subscriber.close().await;
+        }
+    }
+}
+
+async fn select(room: Arc<Room>, slot: Subscriber, levels: Levels) {
+    let mut readers: HashMap<String, JoinHandle<()>> = HashMap::new();
+    loop {
+        let publisher_ids = room.audio_publisher_ids().await;
+        // Mixes repeat the levels of their sources.
+        #[cfg(feature = "opus")]
+        let publisher_ids: Vec<String> = publisher_ids
+            .into_iter()
+            .filter(|id| !crate::mixer::is_mix(id))
+            .collect();
+
+        let current = slot.lock().unwrap().clone();
+        let subscriber = match current {
+            Some(subscriber) => subscriber,
+            None if publisher_ids.len() < MIN_PUBLISHERS => {
+                tokio::time::sleep(SELECT_INTERVAL).await;
+                continue;
+            }
+            None => match LoopbackSubscriber::new(&room).await {
+                Ok(subscriber) => {
+                    let subscriber = Arc::new(subscriber);
+                    *slot.lock().unwrap() = Some(subscriber.clone());
+                    subscriber
+                }
+                Err(err) => {
+                    tracing::error!("Failed to start active speaker detection: {}", err);
+                    tokio::time::sleep(SELECT_INTERVAL).await;
+                    continue;
+                }
+            },
+        };
+
+        let removed: Vec<String> = readers
+            .keys()
+            .filter(|id| !publisher_ids.contains(id))
+            .cloned()
+            .collect();
+        for publisher_id in removed {
+            if let Some(reader) = readers.remove(&publisher_id) {
+                reader.abort();
+            }
+            subscriber.unsubscribe(&publisher_id).await;
+            levels.lock().unwrap().remove(&publisher_id);
+        }
+
+        for publisher_id in publisher_ids {
+            if readers.contains_key(&publisher_id) {
+                continue;
+            }
+            match subscriber.subscribe(&publisher_id).await {
+                Ok(track) => {
+                    readers.insert(
+                        publisher_id.clone(),
+                        tokio::spawn(read(track, publisher_id, levels.clone())),
+                    );
+                }
+                Err(err) => {
+                    tracing::error!(
+                        "Failed to subscribe {} for active speakers: {}",
+                        publisher_id,
+                        err
+                    );
+                }
+            }
+        }
+
+        tokio::time::sleep(SELECT_INTERVAL).await;
+    }
+}
+
+async fn read(track: Arc<TrackRemote>, publisher_id: String, levels: Levels) {
+    let Some(extension_id) = track
+        .params()
+        .header_extensions
+        .iter()
+        .find(|e| e.uri == AUDIO_LEVEL_URI)
+        .and_then(|e| u8::try_from(e.id).ok())
+    else {
+        tracing::debug!("{} does not have audio levels", publisher_id);
+        return;
+    };
+
+    while let Ok((packet, _)) = track.read_rtp().await {
+        let Some(level) = packet
+            .header
+            .get_extension(extension_id)
+            .and_then(|e| e.first().copied())
+        else {
+            continue;
+        };
+        // The lower 7 bits are the level in -dBov. The voice activity bit is not reliable across browsers.
+        let loudness = (127 - (level & 0x7f)) as f32;
+        let mut levels = levels.lock().unwrap();
+        let level = levels.entry(publisher_id.clone()).or_insert(Level {
+            loudness,
+            updated: Instant::now(),
+        });
+        level.loudness += (loudness - level.loudness) * SMOOTHING;
+        level.updated = Instant::now();
+    }
+}
+
+#[derive(Default)]
+struct Dominance {
+    dominant: Option<String>,
+    challenger: Option<(String, Instant)>,
+}
+
+impl Dominance {
+    /// Makes the loudest speaker dominant, once it has been louder than the dominant one by HYSTERESIS for SWITCH_DELAY.
+    fn update(&mut self, loudest: Option<&(String, f32)>, dominant_loudness: f32, now: Instant) {
+        match loudest {
+            Some((loudest, loudness))
+                if self.dominant.as_ref() != Some(loudest)
+                    && *loudness > dominant_loudness + HYSTERESIS =>
+            {
+                let since = match &self.challenger {
+                    Some((id, since)) if id == loudest => *since,
+                    _ => now,
+                };
+                if self.dominant.is_none() || now.duration_since(since) >= SWITCH_DELAY {
+                    self.dominant = Some(loudest.clone());
+                    self.challenger = None;
+                } else {
+                    self.challenger = Some((loudest.clone(), since));
+                }
+            }
+            _ => self.challenger = None,
+        }
+    }
+}
+
+async fn report(room: Arc<Room>, levels: Levels, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    let mut dominance = Dominance::default();
+    let mut reported = false;
+    loop {
+        interval.tick().await;
+        let (mut speakers, dominant_loudness) = {
+            let levels = levels.lock().unwrap();
+            if dominance
+                .dominant
+                .as_ref()
+                .is_some_and(|id| !levels.contains_key(id))
+            {
+                dominance.dominant = None;
+            }
+            let loudness = |level: &Level| {
+                if level.updated.elapsed() > SILENCE_TIMEOUT {
+                    0.0
+                } else {
+                    level.loudness
+                }
+            };
+            let speakers: Vec<(String, f32)> = levels
+                .iter()
+                .map(|(id, level)| (id.clone(), loudness(level)))
+                .filter(|(_, loudness)| *loudness >= SPEECH_LOUDNESS)
+                .collect();
+            let dominant_loudness = dominance
+                .dominant
+                .as_ref()
+                .and_then(|id| levels.get(id))
+                .map(loudness)
+                .unwrap_or(0.0);
+            (speakers, dominant_loudness)
+        };
+        speakers.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        dominance.update(speakers.first(), dominant_loudness, Instant::now());
+
+        // The dominant speaker stays first while silent, so clients keep focusing on it.
+        if let Some(id) = &dominance.dominant {
+            let speaker = match speakers.iter().position(|(i, _)| i == id) {
+                Some(index) => speakers.remove(index),
+                None => (id.clone(), dominant_loudness),
+            };
+            speakers.insert(0, speaker);
+        }
+        if speakers.is_empty() && !reported {
+            continue;
+        }
+        reported = !speakers.is_empty();
+        room.broadcast(SendingMessage::ActiveSpeakers {
+            publisher_ids: speakers.iter().map(|(id, _)| id.clone()).collect(),
+            levels: speakers.iter().map(|(_, l)| *l as u8).collect(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_after_hysteresis() {
+        let start = Instant::now();
+        let mut dominance = Dominance::default();
+        let loud = |id: &str, loudness: f32| (id.to_owned(), loudness);
+
+        // The first speaker becomes dominant at once.
+        dominance.update(Some(&loud("a", 80.0)), 0.0, start);
+        assert_eq!(dominance.dominant.as_deref(), Some("a"));
+
+        // A speaker within the hysteresis does not challenge.
+        dominance.update(Some(&loud("b", 85.0)), 80.0, start);
+        assert!(dominance.challenger.is_none());
+
+        // A louder speaker takes over only after SWITCH_DELAY.
+        dominance.update(Some(&loud("b", 90.0)), 80.0, start);
+        dominance.update(Some(&loud("b", 90.0)), 80.0, start + SWITCH_DELAY / 2);
+        assert_eq!(dominance.dominant.as_deref(), Some("a"));
+        dominance.update(Some(&loud("b", 90.0)), 80.0, start + SWITCH_DELAY);
+        assert_eq!(dominance.dominant.as_deref(), Some("b"));
+
+        // Falling back below the margin restarts the delay.
+        dominance.update(Some(&loud("a", 90.0)), 80.0, start + SWITCH_DELAY * 2);
+        dominance.update(None, 80.0, start + SWITCH_DELAY * 3);
+        dominance.update(Some(&loud("a", 90.0)), 80.0, start + SWITCH_DELAY * 3);
+        assert_eq!(dominance.dominant.as_deref(), Some("b"));
+    }
+}
@@ -0,0 +1,359 @@
+// Statistics of the peer connections of participants, for debugging quality. They are gathered from the stats of
+// webrtc-rs, which rheomesh exposes through `Transport::get_stats`.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use actix_web::{web, HttpResponse, Responder};
+use rheomesh::transport::Transport;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use webrtc::{
+    ice::candidate::CandidatePairState,
+    stats::{ICECandidateStats, StatsReport, StatsReportType},
+};
+
+use crate::{room, websocket};
+
+/// Stats of both transports of a participant. A transport is null before the client creates it.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ParticipantStats {
+    pub participant_id: String,
+    pub publish: Option<TransportStats>,
+    pub subscribe: Option<TransportStats>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TransportStats {
+    pub transport_id: String,
+    pub connection_state: String,
+    // Null until ICE succeeds.
+    pub selected_candidate_pair: Option<CandidatePair>,
+    pub tracks: Vec<TrackStats>,
+}
+
+// webrtc-rs does not fill RTT and byte counters of candidate pairs, so only the candidates are given.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CandidatePair {
+    pub local: Candidate,
+    pub remote: Candidate,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    pub address: String,
+    pub port: u16,
+    pub protocol: String,
+    pub candidate_type: String,
+}
+
+/// An RTP stream of a publisher which the server receives, or of a subscriber which the server sends. Simulcast
+/// publishers have a stream for each layer.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackStats {
+    pub publisher_id: String,
+    // Only on subscribe transports.
+    pub subscriber_id: Option<String>,
+    pub kind: String,
+    pub ssrc: u32,
+    pub rid: Option<String>,
+    // Bits per second since the previous stats. Null at the first stats.
+    pub bitrate: Option<f64>,
+    pub packets: u64,
+    // Loss is known from receiver reports of clients, so it is null for streams which the server receives.
+    pub packets_lost: Option<i64>,
+    pub fraction_lost: Option<f64>,
+    // Seconds. webrtc-rs does not measure jitter yet, so it is null until it does.
+    pub jitter: Option<f64>,
+    // Seconds, from RTCP reports.
+    pub round_trip_time: Option<f64>,
+    pub nack_count: u64,
+    pub pli_count: u64,
+    // The simulcast or SVC layer which is forwarded to the subscriber.
+    pub layer: Option<Layer>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Layer {
+    pub sid: u8,
+    pub tid: Option<u8>,
+}
+
+/// Previous byte counters of RTP streams, to calculate bitrates.
+#[derive(Default)]
+pub struct Meter {
+    samples: HashMap<String, (u64, Instant)>,
+}
+
+impl Meter {
+    fn bitrate(&mut self, id: &str, bytes: u64, now: Instant) -> Option<f64> {
+        let previous = self.samples.insert(id.to_owned(), (bytes, now));
+        let (previous_bytes, previous_time) = previous?;
+        let elapsed = now.duration_since(previous_time);
+        if elapsed < Duration::from_millis(1) {
+            return None;
+        }
+        Some(bytes.saturating_sub(previous_bytes) as f64 * 8.0 / elapsed.as_secs_f64())
+    }
+}
+
+/// Layers of subscriptions, keyed by publisher IDs with the subscriber IDs.
+pub type Layers = HashMap<String, (String, Layer)>;
+
+pub async fn collect(
+    transport_id: &str,
+    transport: &impl Transport,
+    meter: &std::sync::Mutex<Meter>,
+    layers: &Layers,
+) -> TransportStats {
+    let report = transport.get_stats().await;
+    let now = Instant::now();
+    let mut meter = meter.lock().unwrap();
+    let mut tracks: Vec<TrackStats> = report
+        .reports
+        .values()
+        .filter_map(|stats| match stats {
+            StatsReportType::InboundRTP(inbound) => Some(TrackStats {
+                publisher_id: inbound.track_identifier.clone(),
+                subscriber_id: None,
+                kind: inbound.kind.clone(),
+                ssrc: inbound.ssrc,
+                rid: None,
+                bitrate: meter.bitrate(&inbound.id, inbound.bytes_received, now),
+                packets: inbound.packets_received,
+                packets_lost: None,
+                fraction_lost: None,
+                jitter: None,
+                round_trip_time: report.reports.values().find_map(|stats| match stats {
+                    StatsReportType::RemoteOutboundRTP(remote) if remote.local_id == inbound.id => {
+                        remote.round_trip_time.map(|ms| ms / 1000.0)
+                    }
+                    _ => None,
+                }),
+                nack_count: inbound.nack_count,
+                pli_count: inbound.pli_count.unwrap_or_default(),
+                layer: None,
+            }),
+            StatsReportType::OutboundRTP(outbound) => {
+                let remote = report.reports.values().find_map(|stats| match stats {
+                    StatsReportType::RemoteInboundRTP(remote) if remote.local_id == outbound.id => {
+                        Some(remote)
+                    }
+                    _ => None,
+                });
+                let subscription = layers.get(&outbound.track_identifier);
+                Some(TrackStats {
+                    publisher_id: outbound.track_identifier.clone(),
+                    subscriber_id: subscription.map(|(id, _)| id.clone()),
+                    kind: outbound.kind.clone(),
+                    ssrc: outbound.ssrc,
+                    rid: outbound.rid.as_ref().map(|rid| rid.to_string()),
+                    bitrate: meter.bitrate(&outbound.id, outbound.bytes_sent, now),
+                    packets: outbound.packets_sent,
+                    packets_lost: remote.map(|remote| remote.packets_lost),
+                    fraction_lost: remote.map(|remote| remote.fraction_lost),
+                    jitter: None,
+                    round_trip_time: remote
+                        .and_then(|remote| remote.round_trip_time)
+                        .map(|ms| ms / 1000.0),
+                    nack_count: outbound.nack_count,
+                    pli_count: outbound.pli_count.unwrap_or_default(),
+                    layer: subscription
+                        .filter(|_| outbound.kind == "video")
+                        .map(|(_, layer)| *layer),
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    tracks.sort_by(|a, b| (&a.publisher_id, a.ssrc).cmp(&(&b.publisher_id, b.ssrc)));
+    TransportStats {
+        transport_id: transport_id.to_owned(),
+        connection_state: transport.connection_state().to_string(),
+        selected_candidate_pair: selected_candidate_pair(&report),
+        tracks,
+    }
+}
+
+// webrtc-rs does not report the selected pair, so it is the nominated pair which succeeded.
+fn selected_candidate_pair(report: &StatsReport) -> Option<CandidatePair> {
+    let candidate = |id: &str| {
+        report.reports.values().find_map(|stats| match stats {
+            StatsReportType::LocalCandidate(candidate)
+            | StatsReportType::RemoteCandidate(candidate)
+                if candidate.id == id =>
+            {
+                Some(Candidate::from(candidate))
+            }
+            _ => None,
+        })
+    };
+    report.reports.values().find_map(|stats| match stats {
+        StatsReportType::CandidatePair(pair)
+            if pair.nominated && pair.state == CandidatePairState::Succeeded =>
+        {
+            Some(CandidatePair {
+                local: candidate(&pair.local_candidate_id)?,
+                remote: candidate(&pair.remote_candidate_id)?,
+            })
+        }
+        _ => None,
+    })
+}
+
+impl From<&ICECandidateStats> for Candidate {
+    fn from(candidate: &ICECandidateStats) -> Self {
+        Self {
+            address: candidate.ip.clone(),
+            port: candidate.port,
+            protocol: candidate.network_type.to_string(),
+            candidate_type: candidate.candidate_type.to_string(),
+        }
+    }
+}
+
+/// Connection quality from 0 (lost) to 5 (excellent), by the worst loss, RTT and jitter of the connected
+/// transports. None until a transport is connected.
+pub fn quality_score(stats: &ParticipantStats) -> Option<u8> {
+    let transports: Vec<&TransportStats> = [&stats.publish, &stats.subscribe]
+        .into_iter()
+        .flatten()
+        .collect();
+    if transports
+        .iter()
+        .any(|t| t.connection_state == "failed" || t.connection_state == "disconnected")
+    {
+        return Some(0);
+    }
+    let connected: Vec<&TrackStats> = transports
+        .iter()
+        .filter(|t| t.connection_state == "connected")
+        .flat_map(|t| &t.tracks)
+        .collect();
+    if !transports.iter().any(|t| t.connection_state == "connected") {
+        return None;
+    }
+    let worst = |value: fn(&TrackStats) -> Option<f64>| {
+        connected
+            .iter()
+            .filter_map(|track| value(track))
+            .fold(0.0, f64::max)
+    };
+    let penalty =
+        |value: f64, thresholds: &[f64]| thresholds.iter().filter(|t| value >= **t).count();
+    let penalties = penalty(worst(|t| t.fraction_lost), &[0.02, 0.05, 0.1])
+        + penalty(worst(|t| t.round_trip_time), &[0.2, 0.4])
+        + penalty(worst(|t| t.jitter), &[0.03, 0.1]);
+    // A connected transport is never reported as lost.
+    Some(5u8.saturating_sub(penalties as u8).max(1))
+}
+
+// Stats of every participant in the room. Participants in the lobby do not have transports.
+pub async fn get(
+    path: web::Path<String>,
+    room_owner: web::Data<Mutex<room::RoomOwner>>,
+) -> impl Responder {
+    let room = room_owner.lock().await.find_by_id(path.into_inner());
+    let Some(room) = room else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mut participants = Vec::new();
+    for address in room.addresses() {
+        // The participant may leave while stats are gathered.
+        if let Ok(stats) = address.send(websocket::CollectStats).await {
+            participants.push(stats);
+        }
+    }
+    HttpResponse::Ok().json(participants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport(
+        connection_state: &str,
+        fraction_lost: f64,
+        round_trip_time: f64,
+    ) -> TransportStats {
+        TransportStats {
+            transport_id: "transport".to_owned(),
+            connection_state: connection_state.to_owned(),
+            selected_candidate_pair: None,
+            tracks: vec![TrackStats {
+                publisher_id: "publisher".to_owned(),
+                subscriber_id: None,
+                kind: "video".to_owned(),
+                ssrc: 1,
+                rid: None,
+                bitrate: None,
+                packets: 100,
+                packets_lost: None,
+                fraction_lost: Some(fraction_lost),
+                jitter: None,
+                round_trip_time: Some(round_trip_time),
+                nack_count: 0,
+                pli_count: 0,
+                layer: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn quality_is_scored_by_the_worst_transport() {
+        let participant = |publish, subscribe| ParticipantStats {
+            participant_id: "alice".to_owned(),
+            publish,
+            subscribe,
+        };
+        assert_eq!(quality_score(&participant(None, None)), None);
+        assert_eq!(
+            quality_score(&participant(Some(transport("connecting", 0.0, 0.0)), None)),
+            None
+        );
+        assert_eq!(
+            quality_score(&participant(Some(transport("connected", 0.0, 0.05)), None)),
+            Some(5)
+        );
+        assert_eq!(
+            quality_score(&participant(
+                Some(transport("connected", 0.0, 0.05)),
+                Some(transport("connected", 0.06, 0.3)),
+            )),
+            Some(2)
+        );
+        assert_eq!(
+            quality_score(&participant(Some(transport("connected", 0.5, 1.0)), None)),
+            Some(1)
+        );
+        assert_eq!(
+            quality_score(&participant(
+                Some(transport("connected", 0.0, 0.05)),
+                Some(transport("disconnected", 0.0, 0.05)),
+            )),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn bitrates_are_measured_between_stats() {
+        let mut meter = Meter::default();
+        let start = Instant::now();
+        assert_eq!(meter.bitrate("stream", 1000, start), None);
+        assert_eq!(
+            meter.bitrate("stream", 126_000, start + Duration::from_secs(1)),
+            Some(1_000_000.0)
+        );
+        assert_eq!(
+            meter.bitrate("stream", 126_000, start + Duration::from_secs(2)),
+            Some(0.0)
+        );
+    }
+}
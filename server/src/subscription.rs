@@ -0,0 +1,151 @@
+use std::{collections::HashMap, sync::Arc};
+
+use actix::Addr;
+use rheomesh::{error::Error, subscribe_transport::SubscribeTransport, subscriber::Subscriber};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+
+use crate::{
+    congestion,
+    room::Room,
+    websocket::{SendingMessage, WebSocket},
+};
+
+/// A participant on the screen of a client, with the size of its tile in CSS pixels.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Tile {
+    pub publisher_id: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    // Simulcast layers are usually a quarter, a half and the full resolution of 720p, in 16:9.
+    fn layer(&self) -> (u8, Option<u8>) {
+        match self.height.max(self.width * 9 / 16) {
+            h if h >= 360 => (2, Some(2)),
+            h if h >= 180 => (1, Some(2)),
+            _ => (0, Some(2)),
+        }
+    }
+}
+
+// Off-screen video is not paused, because rheomesh 0.4 can not hold forwarding of a subscriber without closing it.
+// The lowest layer is forwarded instead, which costs little and recovers without a keyframe request.
+const OFF_SCREEN_LAYER: (u8, Option<u8>) = (0, Some(0));
+
+struct Subscription {
+    publisher_id: String,
+    subscriber: Arc<Mutex<Subscriber>>,
+}
+
+/// Subscribers of a client.
+pub struct Subscriptions {
+    room: Arc<Room>,
+    subscribe_transport: Arc<SubscribeTransport>,
+    congestion: Arc<congestion::Controller>,
+    subscriptions: Mutex<HashMap<String, Subscription>>,
+    // Publishers on the screen, when the client sends its viewport.
+    viewport: Mutex<Option<HashMap<String, Tile>>>,
+}
+
+impl Subscriptions {
+    pub fn new(
+        room: Arc<Room>,
+        subscribe_transport: Arc<SubscribeTransport>,
+        congestion: Arc<congestion::Controller>,
+    ) -> Self {
+        Self {
+            room,
+            subscribe_transport,
+            congestion,
+            subscriptions: Mutex::new(HashMap::new()),
+            viewport: Mutex::new(None),
+        }
+    }
+
+    pub async fn subscribe(
+        &self,
+        publisher_id: String,
+        address: &Addr<WebSocket>,
+    ) -> Result<String, Error> {
+        let (subscriber, offer) = self
+            .subscribe_transport
+            .subscribe(publisher_id.clone())
+            .await?;
+        let id = subscriber.lock().await.id.clone();
+        if self.has_layers(&publisher_id) {
+            self.congestion
+                .add(id.clone(), publisher_id.clone(), subscriber.clone())
+                .await;
+            if let Some(tiles) = self.viewport.lock().await.as_ref() {
+                let (sid, tid) = layer(tiles, &publisher_id);
+                self.congestion.set_ceiling(&id, sid, tid).await;
+            }
+        }
+        address.do_send(SendingMessage::Offer { sdp: offer });
+        address.do_send(SendingMessage::Subscribed {
+            subscriber_id: id.clone(),
+        });
+        self.subscriptions.lock().await.insert(
+            id.clone(),
+            Subscription {
+                publisher_id,
+                subscriber,
+            },
+        );
+        Ok(id)
+    }
+
+    // Returns whether the subscription existed.
+    pub async fn stop(&self, subscriber_id: &str) -> bool {
+        self.congestion.remove(subscriber_id).await;
+        let removed = self.subscriptions.lock().await.remove(subscriber_id);
+        match removed {
+            Some(subscription) => {
+                subscription.subscriber.lock().await.close().await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Selects layers of video by the tile size, and the lowest layer for video which is not on the screen.
+    /// Video without simulcast or SVC is forwarded as it is.
+    pub async fn set_viewport(&self, tiles: Vec<Tile>) {
+        let tiles: HashMap<String, Tile> = tiles
+            .into_iter()
+            .map(|tile| (tile.publisher_id.clone(), tile))
+            .collect();
+        let videos: Vec<(String, String)> = self
+            .subscriptions
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, s)| self.has_layers(&s.publisher_id))
+            .map(|(id, s)| (id.clone(), s.publisher_id.clone()))
+            .collect();
+        for (subscriber_id, publisher_id) in videos {
+            let (sid, tid) = layer(&tiles, &publisher_id);
+            self.congestion.set_ceiling(&subscriber_id, sid, tid).await;
+        }
+        *self.viewport.lock().await = Some(tiles);
+    }
+
+    fn is_video(&self, publisher_id: &str) -> bool {
+        self.room.track_kind(publisher_id) == Some(RTPCodecType::Video)
+    }
+
+    fn has_layers(&self, publisher_id: &str) -> bool {
+        let media = &self.room.media;
+        self.is_video(publisher_id) && (media.simulcast || media.scalability_mode.is_some())
+    }
+}
+
+fn layer(tiles: &HashMap<String, Tile>, publisher_id: &str) -> (u8, Option<u8>) {
+    tiles
+        .get(publisher_id)
+        .map_or(OFF_SCREEN_LAYER, |tile| tile.layer())
+}
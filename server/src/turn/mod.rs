@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use tokio::net::{TcpListener, UdpSocket};
+use webrtc::turn::{
+    auth::{generate_auth_key, AuthHandler},
+    relay::relay_static::RelayAddressGeneratorStatic,
+    server::{
+        config::{ConnConfig, ServerConfig},
+        Server,
+    },
+    Error,
+};
+use webrtc::util::vnet::net::Net;
+
+use crate::config;
+
+mod relay;
+mod tcp;
+
+/// An ICE server given to clients in Joined, in the form of RTCIceServer of browsers.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+
+/// Users of the TURN server: static long-term credentials, and TURN REST API credentials signed with the secret.
+struct Credentials {
+    users: HashMap<String, String>,
+    secret: Option<String>,
+}
+
+impl Credentials {
+    // REST API usernames are "<expiry>:<id>", and passwords are base64(HMAC-SHA1(secret, username)).
+    fn rest_password(secret: &str, username: &str) -> String {
+        let mut mac =
+            Hmac::<sha1::Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+        mac.update(username.as_bytes());
+        base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+    }
+
+    fn password(&self, username: &str) -> Result<String, Error> {
+        if let Some(password) = self.users.get(username) {
+            return Ok(password.clone());
+        }
+        let Some(secret) = &self.secret else {
+            return Err(Error::Other(format!("unknown TURN user {}", username)));
+        };
+        let expiry = username
+            .split(':')
+            .next()
+            .and_then(|expiry| expiry.parse::<u64>().ok())
+            .ok_or_else(|| Error::Other(format!("unknown TURN user {}", username)))?;
+        if expiry < now().as_secs() {
+            return Err(Error::Other(format!(
+                "TURN credential of {} is expired",
+                username
+            )));
+        }
+        Ok(Self::rest_password(secret, username))
+    }
+}
+
+impl AuthHandler for Credentials {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        match self.password(username) {
+            Ok(password) => Ok(generate_auth_key(username, realm, &password)),
+            Err(err) => {
+                tracing::warn!("TURN authentication from {} failed: {}", src_addr, err);
+                Err(err)
+            }
+        }
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before the epoch")
+}
+
+/// The embedded TURN/STUN server, which listens on the same port for UDP and TCP.
+pub struct Turn {
+    server: Server,
+    ip: IpAddr,
+    port: u16,
+    credentials: Arc<Credentials>,
+    ttl: Duration,
+}
+
+impl Turn {
+    pub async fn start(port: u16) -> std::io::Result<Self> {
+        let ip = config::public_ip().ok_or_else(|| {
+            std::io::Error::other("TURN_PORT requires an IPv4 address in PUBLIC_IP")
+        })?;
+        let ip = IpAddr::V4(ip);
+        let credentials = Arc::new(Credentials {
+            users: config::turn_users().into_iter().collect(),
+            secret: config::turn_secret(),
+        });
+        if credentials.secret.is_none() {
+            tracing::warn!("TURN_SECRET is not set, so participants are only given STUN");
+        }
+        let relay = || -> Box<relay::PublicRelay> {
+            Box::new(relay::PublicRelay(RelayAddressGeneratorStatic {
+                relay_address: ip,
+                address: Ipv4Addr::UNSPECIFIED.to_string(),
+                net: Arc::new(Net::new(None)),
+            }))
+        };
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        let tcp = tcp::TcpConn::new(TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?)?;
+        let server = Server::new(ServerConfig {
+            conn_configs: vec![
+                ConnConfig {
+                    conn: Arc::new(udp),
+                    relay_addr_generator: relay(),
+                },
+                ConnConfig {
+                    conn: Arc::new(tcp),
+                    relay_addr_generator: relay(),
+                },
+            ],
+            realm: config::turn_realm(),
+            auth_handler: credentials.clone(),
+            channel_bind_timeout: Duration::from_secs(0),
+            alloc_close_notify: None,
+        })
+        .await
+        .map_err(std::io::Error::other)?;
+        tracing::info!("TURN server is listening on {}", port);
+        Ok(Self {
+            server,
+            ip,
+            port,
+            credentials,
+            ttl: config::turn_credential_ttl(),
+        })
+    }
+
+    /// ICE servers for a participant. TURN is given with a temporary credential only if the secret is configured,
+    /// because static users are for clients which are configured by hand.
+    pub fn ice_servers(&self, participant_id: &str) -> Vec<IceServer> {
+        let stun = IceServer {
+            urls: vec![format!("stun:{}:{}", self.ip, self.port)],
+            username: String::new(),
+            credential: String::new(),
+        };
+        let Some(secret) = &self.credentials.secret else {
+            return vec![stun];
+        };
+        let username = format!("{}:{}", (now() + self.ttl).as_secs(), participant_id);
+        let credential = Credentials::rest_password(secret, &username);
+        vec![
+            stun,
+            IceServer {
+                urls: vec![
+                    format!("turn:{}:{}?transport=udp", self.ip, self.port),
+                    format!("turn:{}:{}?transport=tcp", self.ip, self.port),
+                ],
+                username,
+                credential,
+            },
+        ]
+    }
+
+    pub async fn close(&self) {
+        if let Err(err) = self.server.close().await {
+            tracing::error!("Failed to close TURN server: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rest_credentials_expire() {
+        let credentials = Credentials {
+            users: HashMap::from([("alice".to_owned(), "wonderland".to_owned())]),
+            secret: Some("secret".to_owned()),
+        };
+        assert_eq!(credentials.password("alice").unwrap(), "wonderland");
+        let valid = format!("{}:participant", now().as_secs() + 60);
+        assert_eq!(
+            credentials.password(&valid).unwrap(),
+            Credentials::rest_password("secret", &valid)
+        );
+        let expired = format!("{}:participant", now().as_secs() - 60);
+        assert!(credentials.password(&expired).is_err());
+        assert!(credentials.password("bob").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+// Relays of the TURN server. Clients could reach this host and its private networks through relays, so peers on
+// loopback, private and link-local addresses are rejected like denied-peer-ip of coturn.
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use webrtc::{
+    turn::relay::{relay_static::RelayAddressGeneratorStatic, RelayAddressGenerator},
+    util::{Conn, Error},
+};
+
+pub struct PublicRelay(pub RelayAddressGeneratorStatic);
+
+#[async_trait]
+impl RelayAddressGenerator for PublicRelay {
+    fn validate(&self) -> Result<(), webrtc::turn::Error> {
+        self.0.validate()
+    }
+
+    async fn allocate_conn(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), webrtc::turn::Error> {
+        let (conn, relay_addr) = self.0.allocate_conn(use_ipv4, requested_port).await?;
+        Ok((Arc::new(PublicConn(conn)), relay_addr))
+    }
+}
+
+/// Whether a relay may send to and receive from the peer.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast())
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_unspecified()
+                    || ip.is_multicast())
+            }
+        },
+    }
+}
+
+struct PublicConn(Arc<dyn Conn + Send + Sync>);
+
+#[async_trait]
+impl Conn for PublicConn {
+    async fn connect(&self, addr: SocketAddr) -> Result<(), Error> {
+        self.0.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.0.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        loop {
+            let (n, source) = self.0.recv_from(buf).await?;
+            if is_public(source.ip()) {
+                return Ok((n, source));
+            }
+            tracing::debug!("Dropped a packet to a TURN relay from {}", source);
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize, Error> {
+        if !is_public(target.ip()) {
+            return Err(Error::Other(format!(
+                "TURN relays do not send to {}",
+                target
+            )));
+        }
+        self.0.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.0.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.0.remote_addr()
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.0.close().await
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Send + Sync) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_internal_peers() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["203.0.113.1", "2001:db8::1", "::ffff:203.0.113.1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// TURN over TCP (RFC 5766 section 2.1). Connections are put together as one packet connection for the TURN server,
+// which sends to clients by their addresses.
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpListener},
+    sync::{mpsc, Mutex},
+};
+use webrtc::util::{Conn, Error};
+
+const STUN_HEADER_SIZE: usize = 20;
+const CHANNEL_DATA_HEADER_SIZE: usize = 4;
+
+pub struct TcpConn {
+    local_addr: SocketAddr,
+    received: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    writers: Arc<Mutex<HashMap<SocketAddr, OwnedWriteHalf>>>,
+}
+
+impl TcpConn {
+    pub fn new(listener: TcpListener) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, received) = mpsc::channel(256);
+        let writers = Arc::new(Mutex::new(HashMap::new()));
+        actix::spawn(accept(listener, sender, writers.clone()));
+        Ok(Self {
+            local_addr,
+            received: Mutex::new(received),
+            writers,
+        })
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    writers: Arc<Mutex<HashMap<SocketAddr, OwnedWriteHalf>>>,
+) {
+    loop {
+        let (stream, source) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!("Failed to accept TURN over TCP: {}", err);
+                continue;
+            }
+        };
+        let sender = sender.clone();
+        let writers = writers.clone();
+        actix::spawn(async move {
+            let (mut reader, writer) = stream.into_split();
+            writers.lock().await.insert(source, writer);
+            while let Ok(frame) = read_frame(&mut reader).await {
+                if sender.send((frame, source)).await.is_err() {
+                    break;
+                }
+            }
+            writers.lock().await.remove(&source);
+        });
+    }
+}
+
+// STUN messages and ChannelData messages are delimited by the lengths in their headers. ChannelData messages are
+// padded to multiples of four bytes on TCP.
+async fn read_frame(reader: &mut (impl AsyncReadExt + Unpin)) -> std::io::Result<Vec<u8>> {
+    let mut header = [0u8; CHANNEL_DATA_HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let rest = match header[0] >> 6 {
+        0 => STUN_HEADER_SIZE - CHANNEL_DATA_HEADER_SIZE + length,
+        1 => length.div_ceil(4) * 4,
+        _ => {
+            return Err(std::io::Error::other(
+                "neither STUN nor ChannelData message",
+            ))
+        }
+    };
+    let mut frame = header.to_vec();
+    frame.resize(CHANNEL_DATA_HEADER_SIZE + rest, 0);
+    reader
+        .read_exact(&mut frame[CHANNEL_DATA_HEADER_SIZE..])
+        .await?;
+    Ok(frame)
+}
+
+#[async_trait]
+impl Conn for TcpConn {
+    async fn connect(&self, _addr: SocketAddr) -> Result<(), Error> {
+        Err(Error::Other("TCP connections are only accepted".to_owned()))
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.recv_from(buf).await.map(|(n, _)| n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        match self.received.lock().await.recv().await {
+            Some((frame, source)) => {
+                let n = frame.len().min(buf.len());
+                buf[..n].copy_from_slice(&frame[..n]);
+                Ok((n, source))
+            }
+            None => Err(Error::Other("TURN listener is closed".to_owned())),
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::Other("the target is required".to_owned()))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize, Error> {
+        let mut writers = self.writers.lock().await;
+        let Some(writer) = writers.get_mut(&target) else {
+            return Err(Error::Other(format!("{} is not connected", target)));
+        };
+        writer.write_all(buf).await?;
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.local_addr)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.writers.lock().await.clear();
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Send + Sync) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn messages_are_framed_by_their_headers() {
+        let mut stream: Vec<u8> = Vec::new();
+        // A binding request with an 8 byte attribute.
+        stream.extend([0x00, 0x01, 0x00, 0x08, 0x21, 0x12, 0xa4, 0x42]);
+        stream.extend([0u8; 12 + 8]);
+        // ChannelData of 5 bytes, which is padded to 8 bytes.
+        stream.extend([0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0]);
+        let mut reader = stream.as_slice();
+        assert_eq!(read_frame(&mut reader).await.unwrap().len(), 28);
+        assert_eq!(
+            read_frame(&mut reader).await.unwrap(),
+            vec![0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0]
+        );
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+}
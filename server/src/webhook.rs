@@ -0,0 +1,199 @@
+// Outgoing webhooks of room lifecycle, for backends which bill meetings. They receive a subset of audit events, signed
+// with HMAC-SHA256 of the shared secret, and failed deliveries are retried with exponential backoff.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use subtle::ConstantTimeEq;
+
+use crate::audit;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub const SIGNATURE_HEADER: &str = "X-Livecamera-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Livecamera-Timestamp";
+// Receivers can ignore retried deliveries by this ID.
+pub const DELIVERY_HEADER: &str = "X-Livecamera-Delivery";
+
+/// An http:// or https:// URL. Certificates of https:// endpoints are verified with the Mozilla root store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint(awc::http::Uri);
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Option<Self> {
+        let uri = url.parse::<awc::http::Uri>().ok()?;
+        if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+            return None;
+        }
+        Some(Self(uri))
+    }
+
+    /// Posts the JSON body, and returns the status code of the response.
+    pub async fn post(&self, headers: &[(&str, String)], body: &[u8]) -> std::io::Result<u16> {
+        let mut request = awc::Client::new()
+            .post(&self.0)
+            .timeout(REQUEST_TIMEOUT)
+            .content_type("application/json");
+        for (name, value) in headers {
+            request = request.insert_header((*name, value.as_str()));
+        }
+        let response = request
+            .send_body(body.to_vec())
+            .await
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+        Ok(response.status().as_u16())
+    }
+}
+
+/// Hex of HMAC-SHA256 over "<timestamp>.<body>", so receivers can also reject replayed requests.
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Checks a signature made by `sign` in constant time.
+pub fn verify(secret: &str, timestamp: u64, body: &[u8], signature: &str) -> bool {
+    sign(secret, timestamp, body)
+        .as_bytes()
+        .ct_eq(signature.as_bytes())
+        .into()
+}
+
+/// Seconds since the Unix epoch, which are signed with bodies.
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Requests which the receiver rejected are not sent again, except when it asks to retry later.
+fn is_retryable(status: u16) -> bool {
+    status >= 500 || status == 408 || status == 429
+}
+
+/// A webhook endpoint, which is fed with audit events.
+pub struct Webhook {
+    endpoint: Endpoint,
+    secret: String,
+    max_attempts: u32,
+}
+
+impl Webhook {
+    pub fn new(endpoint: Endpoint, secret: String, max_attempts: u32) -> Self {
+        Self {
+            endpoint,
+            secret,
+            max_attempts,
+        }
+    }
+
+    fn is_lifecycle(event: &audit::Event) -> bool {
+        matches!(
+            event,
+            audit::Event::RoomCreated { .. }
+                | audit::Event::RoomClosed { .. }
+                | audit::Event::ParticipantJoined { .. }
+                | audit::Event::ParticipantLeft { .. }
+                | audit::Event::TrackPublished { .. }
+        )
+    }
+}
+
+#[async_trait(?Send)]
+impl audit::Sink for Webhook {
+    // Deliveries are sequential, so the receiver gets events in order even while one is retried.
+    async fn write(&mut self, record: &audit::Record) -> std::io::Result<()> {
+        if !Self::is_lifecycle(&record.event) {
+            return Ok(());
+        }
+        let body = serde_json::to_vec(record)?;
+        let delivery = uuid::Uuid::new_v4().to_string();
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let timestamp = timestamp();
+            let headers = [
+                (
+                    SIGNATURE_HEADER,
+                    format!("sha256={}", sign(&self.secret, timestamp, &body)),
+                ),
+                (TIMESTAMP_HEADER, timestamp.to_string()),
+                (DELIVERY_HEADER, delivery.clone()),
+            ];
+            let error = match self.endpoint.post(&headers, &body).await {
+                Ok(status) if (200..300).contains(&status) => return Ok(()),
+                Ok(status) if !is_retryable(status) => {
+                    return Err(std::io::Error::other(format!(
+                        "webhook is rejected with {}",
+                        status
+                    )))
+                }
+                Ok(status) => std::io::Error::other(format!("webhook responded {}", status)),
+                Err(err) => err,
+            };
+            if attempt >= self.max_attempts {
+                return Err(error);
+            }
+            tracing::warn!(
+                "Webhook delivery {} failed, retrying in {:?}: {}",
+                delivery,
+                backoff,
+                error
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_are_parsed() {
+        let endpoint = Endpoint::parse("http://127.0.0.1:8080/hooks").unwrap();
+        assert_eq!(endpoint.0.authority().unwrap(), "127.0.0.1:8080");
+        assert_eq!(endpoint.0.path(), "/hooks");
+        assert_eq!(
+            Endpoint::parse("http://localhost:9000").unwrap().0.path(),
+            "/"
+        );
+        assert!(Endpoint::parse("https://example.com/").is_some());
+        assert!(Endpoint::parse("ftp://example.com/").is_none());
+        assert!(Endpoint::parse("/hooks").is_none());
+    }
+
+    #[test]
+    fn bodies_are_signed_with_timestamps() {
+        // HMAC-SHA256("secret", "1700000000.{}")
+        assert_eq!(
+            sign("secret", 1700000000, b"{}"),
+            "b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+        );
+        assert_ne!(
+            sign("secret", 1700000000, b"{}"),
+            sign("secret", 1700000001, b"{}")
+        );
+    }
+    #[test]
+    fn signatures_are_verified() {
+        let signature = sign("secret", 1700000000, b"{}");
+        assert!(verify("secret", 1700000000, b"{}", &signature));
+        assert!(!verify("other", 1700000000, b"{}", &signature));
+        assert!(!verify("secret", 1700000001, b"{}", &signature));
+        assert!(!verify("secret", 1700000000, b"{ }", &signature));
+        assert!(!verify("secret", 1700000000, b"{}", ""));
+    }
+}
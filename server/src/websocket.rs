@@ -1,79 +1,354 @@
-use std::{
-    collections::HashMap,
-    env,
-    net::{IpAddr, Ipv4Addr},
-    sync::Arc,
-};
+use std::{collections::HashMap, sync::Arc};
 
-use actix::{Actor, AsyncContext, Handler, Message, StreamHandler};
-use actix_web::web::Data;
+use actix::{
+    Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Handler, Message, ResponseFuture,
+    StreamHandler, WrapFuture,
+};
+use actix_web::{
+    web::{self, Data},
+    HttpRequest, HttpResponse,
+};
 use actix_web_actors::ws;
-use rheomesh::{self, publisher::Publisher, subscriber::Subscriber, transport::Transport};
+use rheomesh::{
+    self, data_publisher::DataPublisher, data_subscriber::DataSubscriber, publisher::Publisher,
+    transport::Transport,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use webrtc::{
-    ice_transport::{ice_candidate::RTCIceCandidateInit, ice_server::RTCIceServer},
+    ice_transport::ice_candidate::RTCIceCandidateInit,
     peer_connection::sdp::session_description::RTCSessionDescription,
 };
 
-use crate::room;
+use crate::{
+    audit, chat, config, congestion, encoding, ice_mux, media, protocol, room, stats, subscription,
+    turn,
+};
 
 pub struct WebSocket {
     owner: Data<Mutex<room::RoomOwner>>,
     room: Arc<room::Room>,
-    publish_transport: Arc<rheomesh::publish_transport::PublishTransport>,
-    subscribe_transport: Arc<rheomesh::subscribe_transport::SubscribeTransport>,
+    participant_id: String,
+    moderator: bool,
+    // False while the participant waits in the lobby.
+    admitted: bool,
+    // The place in the room which is taken until the participant joins. Participants in the lobby do not have one.
+    reservation: Option<room::Reservation>,
+    // None until the client sends Hello.
+    session: Option<protocol::Session>,
+    encoding: encoding::Encoding,
+    // None when transports are reached on their own ports.
+    mux: Option<Data<ice_mux::Mux>>,
+    turn: Option<Data<turn::Turn>>,
+    // Transports are created by PublisherInit and SubscriberInit, so viewers do not use ports for publishing.
+    publish_transport: Option<Arc<rheomesh::publish_transport::PublishTransport>>,
+    publish_route: Option<ice_mux::Route>,
+    subscribing: Option<Subscribing>,
+    // Byte counters of the previous stats, for bitrates.
+    meter: Arc<std::sync::Mutex<stats::Meter>>,
     publishers: Arc<Mutex<HashMap<String, Arc<Mutex<Publisher>>>>>,
-    subscribers: Arc<Mutex<HashMap<String, Arc<Mutex<Subscriber>>>>>,
+    data_publishers: Arc<Mutex<HashMap<String, Arc<DataPublisher>>>>,
+    data_subscribers: Arc<Mutex<HashMap<String, DataSubscriber>>>,
+}
+
+// The subscribe transport of a participant, and the state of its subscriptions.
+struct Subscribing {
+    transport: Arc<rheomesh::subscribe_transport::SubscribeTransport>,
+    route: Option<ice_mux::Route>,
+    subscriptions: Arc<subscription::Subscriptions>,
+    congestion: Arc<congestion::Controller>,
+    congestion_task: tokio::task::JoinHandle<()>,
+}
+
+impl Subscribing {
+    fn new(
+        room: Arc<room::Room>,
+        transport: Arc<rheomesh::subscribe_transport::SubscribeTransport>,
+        route: Option<ice_mux::Route>,
+        address: Addr<WebSocket>,
+    ) -> Self {
+        let congestion = Arc::new(congestion::Controller::new(transport.clone()));
+        let subscriptions = Arc::new(subscription::Subscriptions::new(
+            room,
+            transport.clone(),
+            congestion.clone(),
+        ));
+        let congestion_task = actix::spawn(congestion.clone().run(address));
+        Self {
+            transport,
+            route,
+            subscriptions,
+            congestion,
+            congestion_task,
+        }
+    }
+
+    async fn close(self) {
+        self.congestion_task.abort();
+        self.transport
+            .close()
+            .await
+            .expect("failed to close subscribe_transport");
+    }
+}
+
+// Transports behind the ICE mux are given credentials of their routes.
+fn transport_config(
+    mux: Option<Data<ice_mux::Mux>>,
+) -> (
+    rheomesh::config::WebRTCTransportConfig,
+    Option<ice_mux::Route>,
+) {
+    let mut config = config::transport_config();
+    let route = mux.map(ice_mux::Route::new);
+    if let Some(route) = &route {
+        route.configure(&mut config);
+    }
+    (config, route)
+}
+
+async fn create_publish_transport(
+    room: Arc<room::Room>,
+    mux: Option<Data<ice_mux::Mux>>,
+) -> (
+    Arc<rheomesh::publish_transport::PublishTransport>,
+    Option<ice_mux::Route>,
+) {
+    let (config, route) = transport_config(mux);
+    let router = room.router.lock().await;
+    let mut publish_transport = router.create_publish_transport(config).await;
+    let r = Arc::downgrade(&room);
+    publish_transport
+        .on_track(Box::new(move |track, _, _| {
+            if let Some(room) = r.upgrade() {
+                room.set_track_kind(track.id(), track.kind());
+            }
+        }))
+        .await;
+    (Arc::new(publish_transport), route)
+}
+
+async fn create_subscribe_transport(
+    room: Arc<room::Room>,
+    mux: Option<Data<ice_mux::Mux>>,
+) -> (
+    Arc<rheomesh::subscribe_transport::SubscribeTransport>,
+    Option<ice_mux::Route>,
+) {
+    let (config, route) = transport_config(mux);
+    let router = room.router.lock().await;
+    let subscribe_transport = router.create_subscribe_transport(config).await;
+    (Arc::new(subscribe_transport), route)
 }
 
 impl WebSocket {
     // This function is called when a new user connect to this server.
-    pub async fn new(room: Arc<room::Room>, owner: Data<Mutex<room::RoomOwner>>) -> Self {
+    pub fn new(
+        room: Arc<room::Room>,
+        owner: Data<Mutex<room::RoomOwner>>,
+        mux: Option<Data<ice_mux::Mux>>,
+        turn: Option<Data<turn::Turn>>,
+        encoding: encoding::Encoding,
+        moderator: bool,
+        reservation: Option<room::Reservation>,
+    ) -> Self {
         tracing::info!("Starting WebSocket");
-        let r = room.router.clone();
-        let router = r.lock().await;
-
-        let mut config = rheomesh::config::WebRTCTransportConfig::default();
-        // Public IP address of your server.
-        let ip = env::var("PUBLIC_IP").expect("PUBLIC_IP is required");
-        let ipv4 = ip
-            .parse::<Ipv4Addr>()
-            .expect("failed to parse public IP address");
-        config.announced_ips = vec![IpAddr::V4(ipv4)];
-        config.configuration.ice_servers = vec![
-            RTCIceServer {
-                urls: vec!["stun:ice.home.h3poteto.dev:3478".to_owned()],
-                username: "root".to_owned(),
-                credential: "homecluster".to_owned(),
-            },
-            RTCIceServer {
-                urls: vec!["turn:ice.home.h3poteto.dev:3478?transport=udp".to_owned()],
-                username: "root".to_owned(),
-                credential: "homecluster".to_owned(),
-            },
-            RTCIceServer {
-                urls: vec!["turns:ice.home.h3poteto.dev:5349?transport=tcp".to_owned()],
-                username: "root".to_owned(),
-                credential: "homecluster".to_owned(),
-            },
-        ];
-        // Port range of your server.
-        config.port_range = Some(rheomesh::config::PortRange {
-            min: 31300,
-            max: 31331,
-        });
-
-        let publish_transport = router.create_publish_transport(config.clone()).await;
-        let subscribe_transport = router.create_subscribe_transport(config).await;
         Self {
             owner,
             room,
-            publish_transport: Arc::new(publish_transport),
-            subscribe_transport: Arc::new(subscribe_transport),
+            participant_id: uuid::Uuid::new_v4().to_string(),
+            moderator,
+            admitted: reservation.is_some(),
+            reservation,
+            session: None,
+            encoding,
+            mux,
+            turn,
+            publish_transport: None,
+            publish_route: None,
+            subscribing: None,
+            meter: Arc::new(std::sync::Mutex::new(stats::Meter::default())),
             publishers: Arc::new(Mutex::new(HashMap::new())),
-            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            data_publishers: Arc::new(Mutex::new(HashMap::new())),
+            data_subscribers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // The client sends PublisherInit before the messages which need the publish transport.
+    fn publish_transport(
+        &self,
+        address: &Addr<WebSocket>,
+        action: &str,
+    ) -> Option<Arc<rheomesh::publish_transport::PublishTransport>> {
+        if self.publish_transport.is_none() {
+            no_transport(address, action, "PublisherInit");
         }
+        self.publish_transport.clone()
+    }
+
+    // The client sends SubscriberInit before the messages which need the subscribe transport.
+    fn subscribing(&self, address: &Addr<WebSocket>, action: &str) -> Option<&Subscribing> {
+        if self.subscribing.is_none() {
+            no_transport(address, action, "SubscriberInit");
+        }
+        self.subscribing.as_ref()
+    }
+
+    // The participant enters the room, when the connection is started or they are admitted from the lobby.
+    fn join(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let address = ctx.address();
+        self.room.add_user(
+            self.reservation.take(),
+            self.participant_id.clone(),
+            address.clone(),
+            self.moderator,
+        );
+        self.room.audit.emit(audit::Event::ParticipantJoined {
+            room_id: self.room.id.clone(),
+            participant_id: self.participant_id.clone(),
+            moderator: self.moderator,
+        });
+        address.do_send(SendingMessage::Joined {
+            participant_id: self.participant_id.clone(),
+            moderator: self.moderator,
+            media: self.room.media.clone(),
+            ice_servers: self
+                .turn
+                .as_ref()
+                .map(|turn| turn.ice_servers(&self.participant_id))
+                .unwrap_or_default(),
+        });
+        let messages = self.room.chat_history();
+        if !messages.is_empty() {
+            address.do_send(SendingMessage::ChatHistory { messages });
+        }
+        if self.moderator {
+            for participant_id in self.room.waiting_ids() {
+                address.do_send(SendingMessage::LobbyJoined { participant_id });
+            }
+        }
+        if let Some(interval) = config::connection_quality_interval() {
+            ctx.run_interval(interval, |act, _ctx| {
+                let stats = act.stats();
+                let room = act.room.clone();
+                actix::spawn(async move {
+                    let stats = stats.await;
+                    // Nothing is reported until a transport is connected.
+                    if let Some(score) = stats::quality_score(&stats) {
+                        room.broadcast(SendingMessage::ConnectionQuality {
+                            participant_id: stats.participant_id,
+                            score,
+                        });
+                    }
+                });
+            });
+        }
+    }
+
+    // Stats are gathered after the handler returns, so the transports are cloned.
+    fn stats(&self) -> impl std::future::Future<Output = stats::ParticipantStats> {
+        let participant_id = self.participant_id.clone();
+        let publish_transport = self.publish_transport.clone();
+        let subscribing = self
+            .subscribing
+            .as_ref()
+            .map(|s| (s.transport.clone(), s.congestion.clone()));
+        let meter = self.meter.clone();
+        async move {
+            let publish = match publish_transport {
+                Some(transport) => Some(
+                    stats::collect(&transport.id, transport.as_ref(), &meter, &HashMap::new())
+                        .await,
+                ),
+                None => None,
+            };
+            let subscribe = match subscribing {
+                Some((transport, congestion)) => {
+                    let layers = congestion.layers().await;
+                    Some(stats::collect(&transport.id, transport.as_ref(), &meter, &layers).await)
+                }
+                None => None,
+            };
+            stats::ParticipantStats {
+                participant_id,
+                publish,
+                subscribe,
+            }
+        }
+    }
+
+    // Candidates of transports behind the ICE mux are given to the client with the shared port.
+    fn through_mux(&self, msg: SendingMessage) -> Vec<SendingMessage> {
+        let subscribe_route = self.subscribing.as_ref().and_then(|s| s.route.as_ref());
+        match (msg, &self.publish_route, subscribe_route) {
+            (SendingMessage::PublisherIce { candidate }, Some(route), _) => route
+                .candidates(candidate)
+                .into_iter()
+                .map(|candidate| SendingMessage::PublisherIce { candidate })
+                .collect(),
+            (SendingMessage::Answer { sdp }, Some(route), _) => vec![SendingMessage::Answer {
+                sdp: route.session_description(sdp),
+            }],
+            (SendingMessage::SubscriberIce { candidate }, _, Some(route)) => route
+                .candidates(candidate)
+                .into_iter()
+                .map(|candidate| SendingMessage::SubscriberIce { candidate })
+                .collect(),
+            (SendingMessage::Offer { sdp }, _, Some(route)) => vec![SendingMessage::Offer {
+                sdp: route.session_description(sdp),
+            }],
+            (msg, _, _) => vec![msg],
+        }
+    }
+
+    // Publishers of the participant are stopped with the publish transport.
+    fn close_publish_transport(&mut self) {
+        let Some(publish_transport) = self.publish_transport.take() else {
+            return;
+        };
+        self.publish_route = None;
+        let publishers = self.publishers.clone();
+        let data_publishers = self.data_publishers.clone();
+        let room = self.room.clone();
+        let participant_id = self.participant_id.clone();
+        actix::spawn(async move {
+            for (publisher_id, _) in publishers.lock().await.drain() {
+                room.audit.emit(audit::Event::TrackUnpublished {
+                    room_id: room.id.clone(),
+                    participant_id: participant_id.clone(),
+                    publisher_id: publisher_id.clone(),
+                });
+                room.links.unpublish(&room.id, &publisher_id).await;
+            }
+            data_publishers.lock().await.clear();
+            publish_transport
+                .close()
+                .await
+                .expect("failed to close publish_transport");
+        });
+    }
+
+    fn close_subscribe_transport(&mut self) {
+        let Some(subscribing) = self.subscribing.take() else {
+            return;
+        };
+        let data_subscribers = self.data_subscribers.clone();
+        actix::spawn(async move {
+            data_subscribers.lock().await.clear();
+            subscribing.close().await;
+        });
+    }
+
+    // Only moderators can admit or deny participants in the lobby.
+    fn is_moderator(&self, address: &Addr<WebSocket>, action: &str) -> bool {
+        if !self.moderator {
+            address.do_send(SendingMessage::Error {
+                code: protocol::ErrorCode::NotModerator,
+                message: "only moderators can do this".to_owned(),
+                request_action: Some(action.to_owned()),
+            });
+        }
+        self.moderator
     }
 }
 
@@ -82,35 +357,42 @@ impl Actor for WebSocket {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         tracing::info!("New WebSocket connection is started");
+        if self.admitted {
+            self.join(ctx);
+            return;
+        }
         let address = ctx.address();
-        self.room.add_user(address.clone());
+        tracing::info!("{} waits in the lobby", self.participant_id);
+        self.room.wait(self.participant_id.clone(), address.clone());
+        address.do_send(SendingMessage::LobbyJoined {
+            participant_id: self.participant_id.clone(),
+        });
     }
 
     fn stopped(&mut self, ctx: &mut Self::Context) {
         tracing::info!("The WebSocket connection is stopped");
         let address = ctx.address();
-        let subscribe_transport = self.subscribe_transport.clone();
-        let publish_transport = self.publish_transport.clone();
-        actix::spawn(async move {
-            subscribe_transport
-                .close()
-                .await
-                .expect("failed to close subscribe_transport");
-            publish_transport
-                .close()
-                .await
-                .expect("failed to close publish_transport");
-        });
-        let users = self.room.remove_user(address);
-        if users == 0 {
+        if self.admitted {
+            self.close_subscribe_transport();
+            self.close_publish_transport();
+            if let Some(moderator) = self.room.remove_user(address) {
+                moderator.do_send(InternalMessage::Promote);
+            }
+            self.room.audit.emit(audit::Event::ParticipantLeft {
+                room_id: self.room.id.clone(),
+                participant_id: self.participant_id.clone(),
+            });
+            for waiting in self.room.abandon_lobby() {
+                waiting.do_send(InternalMessage::Deny);
+            }
+        } else {
+            self.room.leave_lobby(&address);
+        }
+        if self.room.is_empty() {
             let owner = self.owner.clone();
-            let router = self.room.router.clone();
-            let room_id = self.room.id.clone();
+            let room = self.room.clone();
             actix::spawn(async move {
-                let mut owner = owner.lock().await;
-                owner.remove_room(room_id);
-                let router = router.lock().await;
-                router.close();
+                room::close(&owner, &room).await;
             });
         }
     }
@@ -121,20 +403,57 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
             Ok(ws::Message::Pong(_)) => tracing::info!("Pong received"),
-            Ok(ws::Message::Text(text)) => match serde_json::from_str::<ReceivedMessage>(&text) {
-                Ok(message) => {
-                    ctx.address().do_send(message);
-                }
-                Err(error) => {
-                    tracing::error!("Failed to parse client message: {}\n{}", error, text);
-                }
-            },
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Text(text)) => {
+                let message = serde_json::from_str::<ReceivedMessage>(&text).map_err(|error| {
+                    tracing::warn!("Failed to parse client message: {}\n{}", error, text);
+                    error.to_string()
+                });
+                dispatch(ctx, message, || {
+                    serde_json::from_str::<serde_json::Value>(&text)
+                        .ok()
+                        .and_then(|v| v.get("action")?.as_str().map(str::to_owned))
+                });
+            }
+            Ok(ws::Message::Binary(bin)) => {
+                let message = encoding::from_msgpack::<ReceivedMessage>(&bin).map_err(|error| {
+                    tracing::warn!("Failed to parse binary client message: {}", error);
+                    error.to_string()
+                });
+                dispatch(ctx, message, || {
+                    encoding::decode_value(&bin)
+                        .ok()
+                        .and_then(|v| v.get("action")?.as_str().map(str::to_owned))
+                });
+            }
             Ok(ws::Message::Close(reason)) => ctx.close(reason),
             _ => (),
         }
     }
 }
+// Messages of unknown actions and malformed messages are answered with errors, with the action if it is readable.
+fn dispatch(
+    ctx: &mut ws::WebsocketContext<WebSocket>,
+    message: Result<ReceivedMessage, String>,
+    request_action: impl FnOnce() -> Option<String>,
+) {
+    let (code, message) = match message {
+        Ok(ReceivedMessage::Unknown) => (
+            protocol::ErrorCode::UnknownAction,
+            "unknown action".to_owned(),
+        ),
+        Ok(message) => {
+            ctx.address().do_send(message);
+            return;
+        }
+        Err(error) => (protocol::ErrorCode::InvalidMessage, error),
+    };
+    ctx.address().do_send(SendingMessage::Error {
+        code,
+        message,
+        request_action: request_action(),
+    });
+}
+
 impl Handler<ReceivedMessage> for WebSocket {
     type Result = ();
 
@@ -142,13 +461,53 @@ impl Handler<ReceivedMessage> for WebSocket {
         let address = ctx.address();
         tracing::debug!("received message: {:?}", msg);
 
+        if !self.admitted && !matches!(msg, ReceivedMessage::Ping | ReceivedMessage::Hello { .. }) {
+            address.do_send(SendingMessage::Error {
+                code: protocol::ErrorCode::NotAdmitted,
+                message: "the participant waits in the lobby".to_owned(),
+                request_action: None,
+            });
+            return;
+        }
+
         match msg {
             ReceivedMessage::Ping => {
                 address.do_send(SendingMessage::Pong);
             }
+            ReceivedMessage::Hello {
+                protocol_version,
+                capabilities,
+            } => match protocol::Session::negotiate(protocol_version, capabilities) {
+                Some(session) => {
+                    address.do_send(SendingMessage::Welcome {
+                        protocol_version: session.protocol_version,
+                        capabilities: session.capabilities.iter().copied().collect(),
+                        participant_id: self.participant_id.clone(),
+                    });
+                    self.session = Some(session);
+                }
+                None => {
+                    address.do_send(SendingMessage::Error {
+                        code: protocol::ErrorCode::UnsupportedVersion,
+                        message: format!(
+                            "protocol version {} is not supported, the minimum is {}",
+                            protocol_version,
+                            protocol::MIN_PROTOCOL_VERSION
+                        ),
+                        request_action: Some("Hello".to_owned()),
+                    });
+                }
+            },
             ReceivedMessage::PublisherInit => {
+                let room = self.room.clone();
+                let mux = self.mux.clone();
                 let publish_transport = self.publish_transport.clone();
-                tokio::spawn(async move {
+                // Messages are not handled until the transport is ready.
+                let init = async move {
+                    let (publish_transport, route) = match publish_transport {
+                        Some(publish_transport) => (publish_transport, None),
+                        None => create_publish_transport(room, mux).await,
+                    };
                     let addr = address.clone();
                     publish_transport
                         .on_ice_candidate(Box::new(move |candidate| {
@@ -156,12 +515,30 @@ impl Handler<ReceivedMessage> for WebSocket {
                             addr.do_send(SendingMessage::PublisherIce { candidate: init });
                         }))
                         .await;
-                });
+                    (publish_transport, route)
+                };
+                ctx.wait(
+                    init.into_actor(self)
+                        .map(|(publish_transport, route), act, _| {
+                            if act.publish_transport.is_none() {
+                                act.publish_transport = Some(publish_transport);
+                                act.publish_route = route;
+                            }
+                        }),
+                );
             }
             ReceivedMessage::SubscriberInit => {
-                let subscribe_transport = self.subscribe_transport.clone();
                 let room = self.room.clone();
-                tokio::spawn(async move {
+                let mux = self.mux.clone();
+                let subscribe_transport = self
+                    .subscribing
+                    .as_ref()
+                    .map(|subscribing| subscribing.transport.clone());
+                let init = async move {
+                    let (subscribe_transport, route) = match subscribe_transport {
+                        Some(subscribe_transport) => (subscribe_transport, None),
+                        None => create_subscribe_transport(room.clone(), mux).await,
+                    };
                     let addr = address.clone();
                     let addr2 = address.clone();
                     subscribe_transport
@@ -177,33 +554,64 @@ impl Handler<ReceivedMessage> for WebSocket {
                         .await;
 
                     let router = room.router.lock().await;
-                    let ids = router.publisher_ids();
+                    let mut ids = router.publisher_ids();
+                    ids.extend(room.links.relayed_publisher_ids());
                     tracing::info!("router publisher ids {:#?}", ids);
                     address.do_send(SendingMessage::Published { publisher_ids: ids });
-                });
+                    let data_ids = router.data_publisher_ids();
+                    if !data_ids.is_empty() {
+                        address.do_send(SendingMessage::DataPublished {
+                            data_publisher_ids: data_ids,
+                        });
+                    }
+                    (subscribe_transport, route)
+                };
+                ctx.wait(
+                    init.into_actor(self)
+                        .map(|(subscribe_transport, route), act, ctx| {
+                            if act.subscribing.is_none() {
+                                act.subscribing = Some(Subscribing::new(
+                                    act.room.clone(),
+                                    subscribe_transport,
+                                    route,
+                                    ctx.address(),
+                                ));
+                            }
+                        }),
+                );
             }
+            ReceivedMessage::PublisherClose => self.close_publish_transport(),
+            ReceivedMessage::SubscriberClose => self.close_subscribe_transport(),
 
             ReceivedMessage::RequestPublish => address.do_send(SendingMessage::StartAsPublisher),
             ReceivedMessage::PublisherIce { candidate } => {
-                let publish_transport = self.publish_transport.clone();
+                let Some(publish_transport) = self.publish_transport(&address, "PublisherIce")
+                else {
+                    return;
+                };
                 actix::spawn(async move {
-                    let _ = publish_transport
+                    publish_transport
                         .add_ice_candidate(candidate)
                         .await
                         .expect("failed to add ICE candidate");
                 });
             }
             ReceivedMessage::SubscriberIce { candidate } => {
-                let subscribe_transport = self.subscribe_transport.clone();
+                let Some(subscribing) = self.subscribing(&address, "SubscriberIce") else {
+                    return;
+                };
+                let subscribe_transport = subscribing.transport.clone();
                 actix::spawn(async move {
-                    let _ = subscribe_transport
+                    subscribe_transport
                         .add_ice_candidate(candidate)
                         .await
                         .expect("failed to add ICE candidate");
                 });
             }
             ReceivedMessage::Offer { sdp } => {
-                let publish_transport = self.publish_transport.clone();
+                let Some(publish_transport) = self.publish_transport(&address, "Offer") else {
+                    return;
+                };
                 actix::spawn(async move {
                     let answer = publish_transport
                         .get_answer(sdp)
@@ -216,30 +624,32 @@ impl Handler<ReceivedMessage> for WebSocket {
             ReceivedMessage::Subscribe {
                 publisher_id: track_id,
             } => {
-                let subscribe_transport = self.subscribe_transport.clone();
-                let subscribers = self.subscribers.clone();
+                let Some(subscribing) = self.subscribing(&address, "Subscribe") else {
+                    return;
+                };
+                let subscriptions = subscribing.subscriptions.clone();
+                let room = self.room.clone();
+                let participant_id = self.participant_id.clone();
                 actix::spawn(async move {
-                    let (subscriber, offer) = subscribe_transport
-                        .subscribe(track_id)
+                    let subscriber_id = subscriptions
+                        .subscribe(track_id.clone(), &address)
                         .await
                         .expect("failed to connect subscribe_transport");
-
-                    #[allow(unused)]
-                    let mut id = "".to_owned();
-                    {
-                        let guard = subscriber.lock().await;
-                        id = guard.id.clone();
-                    }
-                    let mut s = subscribers.lock().await;
-                    s.insert(id.clone(), subscriber);
-                    address.do_send(SendingMessage::Offer { sdp: offer });
-                    address.do_send(SendingMessage::Subscribed { subscriber_id: id })
+                    room.audit.emit(audit::Event::Subscribed {
+                        room_id: room.id.clone(),
+                        participant_id,
+                        publisher_id: track_id,
+                        subscriber_id,
+                    });
                 });
             }
             ReceivedMessage::Answer { sdp } => {
-                let subscribe_transport = self.subscribe_transport.clone();
+                let Some(subscribing) = self.subscribing(&address, "Answer") else {
+                    return;
+                };
+                let subscribe_transport = subscribing.transport.clone();
                 actix::spawn(async move {
-                    let _ = subscribe_transport
+                    subscribe_transport
                         .set_answer(sdp)
                         .await
                         .expect("failed to set answer");
@@ -247,8 +657,11 @@ impl Handler<ReceivedMessage> for WebSocket {
             }
             ReceivedMessage::Publish { track_id } => {
                 let room = self.room.clone();
-                let publish_transport = self.publish_transport.clone();
+                let Some(publish_transport) = self.publish_transport(&address, "Publish") else {
+                    return;
+                };
                 let publishers = self.publishers.clone();
+                let participant_id = self.participant_id.clone();
                 actix::spawn(async move {
                     match publish_transport.publish(track_id).await {
                         Ok(publisher) => {
@@ -269,6 +682,12 @@ impl Handler<ReceivedMessage> for WebSocket {
                                     publisher_ids: vec![track_id.clone()],
                                 });
                             });
+                            room.audit.emit(audit::Event::TrackPublished {
+                                room_id: room.id.clone(),
+                                participant_id,
+                                publisher_id: track_id.clone(),
+                            });
+                            room.links.publish(&room.id, track_id, publisher).await;
                         }
                         Err(err) => {
                             tracing::error!("{}", err);
@@ -277,43 +696,158 @@ impl Handler<ReceivedMessage> for WebSocket {
                 });
             }
             ReceivedMessage::StopPublish { publisher_id } => {
+                let room = self.room.clone();
                 let publishers = self.publishers.clone();
+                let participant_id = self.participant_id.clone();
                 actix::spawn(async move {
                     let mut p = publishers.lock().await;
                     if let Some(publisher) = p.remove(&publisher_id) {
-                        let publisher = publisher.lock().await;
-                        publisher.close().await;
+                        publisher.lock().await.close().await;
+                        room.audit.emit(audit::Event::TrackUnpublished {
+                            room_id: room.id.clone(),
+                            participant_id,
+                            publisher_id: publisher_id.clone(),
+                        });
+                        room.links.unpublish(&room.id, &publisher_id).await;
+                    }
+                });
+            }
+            ReceivedMessage::PublishData { label } => {
+                let room = self.room.clone();
+                let Some(publish_transport) = self.publish_transport(&address, "PublishData")
+                else {
+                    return;
+                };
+                let data_publishers = self.data_publishers.clone();
+                actix::spawn(async move {
+                    match publish_transport.data_publish(label).await {
+                        Ok(data_publisher) => {
+                            let id = data_publisher.id.clone();
+                            tracing::debug!("published a data channel: {}", id);
+                            data_publishers
+                                .lock()
+                                .await
+                                .insert(id.clone(), data_publisher);
+                            room.get_peers(&address).iter().for_each(|peer| {
+                                peer.do_send(SendingMessage::DataPublished {
+                                    data_publisher_ids: vec![id.clone()],
+                                });
+                            });
+                        }
+                        Err(err) => {
+                            tracing::error!("{}", err);
+                        }
+                    }
+                });
+            }
+            ReceivedMessage::StopPublishData { data_publisher_id } => {
+                let data_publishers = self.data_publishers.clone();
+                actix::spawn(async move {
+                    let removed = data_publishers.lock().await.remove(&data_publisher_id);
+                    if let Some(data_publisher) = removed {
+                        data_publisher.close().await;
+                    }
+                });
+            }
+            ReceivedMessage::SubscribeData { data_publisher_id } => {
+                let Some(subscribing) = self.subscribing(&address, "SubscribeData") else {
+                    return;
+                };
+                let subscribe_transport = subscribing.transport.clone();
+                let data_subscribers = self.data_subscribers.clone();
+                actix::spawn(async move {
+                    match subscribe_transport
+                        .data_subscribe(data_publisher_id.clone())
+                        .await
+                    {
+                        Ok((data_subscriber, offer)) => {
+                            let id = data_subscriber.id.clone();
+                            data_subscribers
+                                .lock()
+                                .await
+                                .insert(id.clone(), data_subscriber);
+                            address.do_send(SendingMessage::Offer { sdp: offer });
+                            address.do_send(SendingMessage::DataSubscribed {
+                                data_subscriber_id: id,
+                                data_publisher_id,
+                            });
+                        }
+                        Err(err) => {
+                            tracing::error!("{}", err);
+                        }
                     }
                 });
             }
+            ReceivedMessage::StopSubscribeData { data_subscriber_id } => {
+                let data_subscribers = self.data_subscribers.clone();
+                actix::spawn(async move {
+                    let removed = data_subscribers.lock().await.remove(&data_subscriber_id);
+                    if let Some(data_subscriber) = removed {
+                        data_subscriber.close().await;
+                    }
+                });
+            }
+            ReceivedMessage::SendChat { text, to } => {
+                if text.is_empty() || text.chars().count() > chat::MAX_TEXT_LENGTH {
+                    tracing::warn!(
+                        "Chat message of {} is empty or too long",
+                        self.participant_id
+                    );
+                    return;
+                }
+                let message = chat::ChatMessage::new(self.participant_id.clone(), to, text);
+                if !self.room.send_chat(message) {
+                    tracing::warn!(
+                        "Recipient of a chat message is not in room {}",
+                        self.room.id
+                    );
+                }
+            }
             ReceivedMessage::StopSubscribe { subscriber_id } => {
-                let subscribers = self.subscribers.clone();
+                let Some(subscribing) = self.subscribing(&address, "StopSubscribe") else {
+                    return;
+                };
+                let subscriptions = subscribing.subscriptions.clone();
+                let room = self.room.clone();
+                let participant_id = self.participant_id.clone();
                 actix::spawn(async move {
-                    let mut s = subscribers.lock().await;
-                    if let Some(subscriber) = s.remove(&subscriber_id) {
-                        let subscriber = subscriber.lock().await;
-                        subscriber.close().await;
+                    if subscriptions.stop(&subscriber_id).await {
+                        room.audit.emit(audit::Event::Unsubscribed {
+                            room_id: room.id.clone(),
+                            participant_id,
+                            subscriber_id,
+                        });
                     }
                 });
             }
+            ReceivedMessage::SetViewport { tiles } => {
+                let Some(subscribing) = self.subscribing(&address, "SetViewport") else {
+                    return;
+                };
+                let subscriptions = subscribing.subscriptions.clone();
+                actix::spawn(async move {
+                    subscriptions.set_viewport(tiles).await;
+                });
+            }
             ReceivedMessage::SetPreferredLayer {
                 subscriber_id,
                 sid,
                 tid,
             } => {
-                let subscribers = self.subscribers.clone();
+                // The server switches layers automatically, below the layer which the client prefers.
+                let Some(subscribing) = self.subscribing(&address, "SetPreferredLayer") else {
+                    return;
+                };
+                let congestion = subscribing.congestion.clone();
                 actix::spawn(async move {
-                    let s = subscribers.lock().await;
-                    if let Some(subscriber) = s.get(&subscriber_id) {
-                        let mut subscriber = subscriber.lock().await;
-                        if let Err(err) = subscriber.set_preferred_layer(sid, tid).await {
-                            tracing::error!("Failed to set preferred layer: {}", err);
-                        }
-                    }
+                    congestion.set_ceiling(&subscriber_id, sid, tid).await;
                 });
             }
-            ReceivedMessage::RestartICE {} => {
-                let subscribe_transport = self.subscribe_transport.clone();
+            ReceivedMessage::RestartICE => {
+                let Some(subscribing) = self.subscribing(&address, "RestartICE") else {
+                    return;
+                };
+                let subscribe_transport = subscribing.transport.clone();
                 actix::spawn(async move {
                     match subscribe_transport.restart_ice().await {
                         Ok(offer) => {
@@ -325,6 +859,45 @@ impl Handler<ReceivedMessage> for WebSocket {
                     }
                 });
             }
+            ReceivedMessage::GetStats => {
+                let stats = self.stats();
+                actix::spawn(async move {
+                    let stats = stats.await;
+                    address.do_send(SendingMessage::Stats {
+                        publish: stats.publish,
+                        subscribe: stats.subscribe,
+                    });
+                });
+            }
+            ReceivedMessage::Admit { participant_id } => {
+                if !self.is_moderator(&address, "Admit") {
+                    return;
+                }
+                match self.room.take_waiting(&participant_id) {
+                    Some(waiting) => waiting.do_send(InternalMessage::Admit),
+                    None => tracing::warn!("{} is not in the lobby", participant_id),
+                }
+            }
+            ReceivedMessage::Deny { participant_id } => {
+                if !self.is_moderator(&address, "Deny") {
+                    return;
+                }
+                match self.room.take_waiting(&participant_id) {
+                    Some(waiting) => {
+                        waiting.do_send(InternalMessage::Deny);
+                        self.room.audit.emit(audit::Event::ParticipantDenied {
+                            room_id: self.room.id.clone(),
+                            participant_id: participant_id.clone(),
+                            moderator_id: self.participant_id.clone(),
+                        });
+                        self.room
+                            .notify_moderators(SendingMessage::LobbyLeft { participant_id });
+                    }
+                    None => tracing::warn!("{} is not in the lobby", participant_id),
+                }
+            }
+            // Answered in dispatch, with the action which the client sent.
+            ReceivedMessage::Unknown => (),
         }
     }
 }
@@ -333,27 +906,121 @@ impl Handler<SendingMessage> for WebSocket {
     type Result = ();
 
     fn handle(&mut self, msg: SendingMessage, ctx: &mut Self::Context) -> Self::Result {
+        if let (Some(session), Some(capability)) = (&self.session, msg.capability()) {
+            if !session.capabilities.contains(&capability) {
+                return;
+            }
+        }
         tracing::debug!("sending message: {:?}", msg);
-        ctx.text(serde_json::to_string(&msg).expect("failed to parse SendingMessage"));
+        for msg in self.through_mux(msg) {
+            send(ctx, self.encoding, &msg);
+        }
+    }
+}
+
+pub fn send<A>(
+    ctx: &mut ws::WebsocketContext<A>,
+    encoding: encoding::Encoding,
+    msg: &SendingMessage,
+) where
+    A: Actor<Context = ws::WebsocketContext<A>>,
+{
+    match encoding {
+        encoding::Encoding::Json => {
+            ctx.text(serde_json::to_string(msg).expect("failed to parse SendingMessage"))
+        }
+        encoding::Encoding::MessagePack => {
+            ctx.binary(encoding::to_msgpack(msg).expect("failed to encode SendingMessage"))
+        }
+    }
+}
+
+// The subprotocol which the client requested is accepted in the handshake.
+pub fn start_socket<A>(
+    actor: A,
+    req: &HttpRequest,
+    stream: web::Payload,
+    protocol: Option<&'static str>,
+) -> Result<HttpResponse, actix_web::Error>
+where
+    A: Actor<Context = ws::WebsocketContext<A>>
+        + StreamHandler<Result<ws::Message, ws::ProtocolError>>,
+{
+    match protocol {
+        Some(protocol) => ws::WsResponseBuilder::new(actor, req, stream)
+            .protocols(&[protocol])
+            .start(),
+        None => ws::start(actor, req, stream),
     }
 }
 
 impl Handler<InternalMessage> for WebSocket {
     type Result = ();
 
-    fn handle(&mut self, _msg: InternalMessage, _ctx: &mut Self::Context) -> Self::Result {}
+    fn handle(&mut self, msg: InternalMessage, ctx: &mut Self::Context) -> Self::Result {
+        let address = ctx.address();
+        match msg {
+            InternalMessage::Admit => {
+                tracing::info!("{} is admitted from the lobby", self.participant_id);
+                self.admitted = true;
+                let admitted = SendingMessage::Admitted {
+                    participant_id: self.participant_id.clone(),
+                };
+                self.room.notify_moderators(admitted.clone());
+                address.do_send(admitted);
+                self.join(ctx);
+            }
+            InternalMessage::Promote => {
+                tracing::info!("{} is promoted to a moderator", self.participant_id);
+                self.moderator = true;
+                for participant_id in self.room.waiting_ids() {
+                    address.do_send(SendingMessage::LobbyJoined { participant_id });
+                }
+            }
+            InternalMessage::Deny => {
+                tracing::info!("{} is denied from the lobby", self.participant_id);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("denied".to_owned()),
+                }));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+// Messages which need a transport are refused before the client creates it.
+fn no_transport(address: &Addr<WebSocket>, action: &str, init: &str) {
+    address.do_send(SendingMessage::Error {
+        code: protocol::ErrorCode::NoTransport,
+        message: format!("{} is required first", init),
+        request_action: Some(action.to_owned()),
+    });
 }
 
+/// Messages from clients to the server.
 #[derive(Deserialize, Message, Debug)]
 #[serde(tag = "action")]
 #[rtype(result = "()")]
 enum ReceivedMessage {
     #[serde(rename_all = "camelCase")]
     Ping,
+    // Clients of version 2 or later send this first. Unknown capabilities are ignored.
+    #[serde(rename_all = "camelCase")]
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
     #[serde(rename_all = "camelCase")]
     PublisherInit,
     #[serde(rename_all = "camelCase")]
     SubscriberInit,
+    // The transport is closed when the client does not use it any more. Init creates it again.
+    #[serde(rename_all = "camelCase")]
+    PublisherClose,
+    #[serde(rename_all = "camelCase")]
+    SubscriberClose,
     #[serde(rename_all = "camelCase")]
     RequestPublish,
     // Seems like client-side (JS) RTCIceCandidate struct is equal RTCIceCandidateInit.
@@ -373,6 +1040,18 @@ enum ReceivedMessage {
     StopPublish { publisher_id: String },
     #[serde(rename_all = "camelCase")]
     StopSubscribe { subscriber_id: String },
+    // The client creates the data channel with this label before sending it.
+    #[serde(rename_all = "camelCase")]
+    PublishData { label: String },
+    #[serde(rename_all = "camelCase")]
+    StopPublishData { data_publisher_id: String },
+    #[serde(rename_all = "camelCase")]
+    SubscribeData { data_publisher_id: String },
+    #[serde(rename_all = "camelCase")]
+    StopSubscribeData { data_subscriber_id: String },
+    // The message is sent to everyone in the room, or only to the participant of `to`.
+    #[serde(rename_all = "camelCase")]
+    SendChat { text: String, to: Option<String> },
     #[serde(rename_all = "camelCase")]
     SetPreferredLayer {
         subscriber_id: String,
@@ -381,15 +1060,54 @@ enum ReceivedMessage {
     },
     #[serde(rename_all = "camelCase")]
     RestartICE,
+    // Participants on the screen. Video of the others is forwarded in the lowest layer.
+    #[serde(rename_all = "camelCase")]
+    SetViewport { tiles: Vec<subscription::Tile> },
+    // Moderators let a participant in the lobby join the room, or turn them away.
+    #[serde(rename_all = "camelCase")]
+    Admit { participant_id: String },
+    #[serde(rename_all = "camelCase")]
+    Deny { participant_id: String },
+    // Stats of the transports of the participant, for debugging.
+    #[serde(rename_all = "camelCase")]
+    GetStats,
+    // Actions which this server does not know. They are answered with UnknownAction before they are handled.
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Serialize, Message, Debug)]
+/// Messages from the server to clients.
+#[derive(Serialize, Message, Debug, Clone)]
 #[serde(tag = "action")]
 #[rtype(result = "()")]
-enum SendingMessage {
+pub enum SendingMessage {
     #[serde(rename_all = "camelCase")]
     Pong,
     #[serde(rename_all = "camelCase")]
+    Welcome {
+        protocol_version: u32,
+        capabilities: Vec<protocol::Capability>,
+        participant_id: String,
+    },
+    // `request_action` is the action of the client message, when it can be read.
+    #[serde(rename_all = "camelCase")]
+    Error {
+        code: protocol::ErrorCode,
+        message: String,
+        request_action: Option<String>,
+    },
+    // The room is on another node of the cluster. The client connects to `url` instead.
+    #[serde(rename_all = "camelCase")]
+    Redirect { url: String },
+    #[serde(rename_all = "camelCase")]
+    Joined {
+        participant_id: String,
+        moderator: bool,
+        media: media::Media,
+        // ICE servers which the client uses for its peer connections. They are empty without the embedded TURN server.
+        ice_servers: Vec<turn::IceServer>,
+    },
+    #[serde(rename_all = "camelCase")]
     StartAsPublisher,
     #[serde(rename_all = "camelCase")]
     Answer { sdp: RTCSessionDescription },
@@ -402,9 +1120,184 @@ enum SendingMessage {
     #[serde(rename_all = "camelCase")]
     Published { publisher_ids: Vec<String> },
     #[serde(rename_all = "camelCase")]
+    DataPublished { data_publisher_ids: Vec<String> },
+    #[serde(rename_all = "camelCase")]
+    DataSubscribed {
+        data_subscriber_id: String,
+        data_publisher_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Chat { message: chat::ChatMessage },
+    // Messages sent to the room before joining, from the oldest.
+    #[serde(rename_all = "camelCase")]
+    ChatHistory { messages: Vec<chat::ChatMessage> },
+    #[serde(rename_all = "camelCase")]
     Subscribed { subscriber_id: String },
+    #[serde(rename_all = "camelCase")]
+    LayerChanged {
+        subscriber_id: String,
+        sid: u8,
+        tid: Option<u8>,
+    },
+    // The dominant speaker comes first. Levels are from 0 (silence) to 127 (loudest).
+    #[serde(rename_all = "camelCase")]
+    ActiveSpeakers {
+        publisher_ids: Vec<String>,
+        levels: Vec<u8>,
+    },
+    // The participant waits in the lobby. It is also sent to moderators for each participant in the lobby.
+    #[serde(rename_all = "camelCase")]
+    LobbyJoined { participant_id: String },
+    // The participant is admitted, and Joined follows for them. It is also sent to moderators.
+    #[serde(rename_all = "camelCase")]
+    Admitted { participant_id: String },
+    // The participant left the lobby without being admitted.
+    #[serde(rename_all = "camelCase")]
+    LobbyLeft { participant_id: String },
+    // Response to GetStats. A transport is null before the client creates it.
+    #[serde(rename_all = "camelCase")]
+    Stats {
+        publish: Option<stats::TransportStats>,
+        subscribe: Option<stats::TransportStats>,
+    },
+    // Sent to the room periodically for each participant. Scores are from 0 (lost) to 5 (excellent).
+    #[serde(rename_all = "camelCase")]
+    ConnectionQuality { participant_id: String, score: u8 },
+}
+
+impl SendingMessage {
+    // Notifications which are only sent to clients with the capability.
+    fn capability(&self) -> Option<protocol::Capability> {
+        match self {
+            SendingMessage::ActiveSpeakers { .. } => Some(protocol::Capability::ActiveSpeakers),
+            SendingMessage::LayerChanged { .. } => Some(protocol::Capability::LayerChanged),
+            SendingMessage::Chat { .. } | SendingMessage::ChatHistory { .. } => {
+                Some(protocol::Capability::Chat)
+            }
+            SendingMessage::DataPublished { .. } => Some(protocol::Capability::DataChannel),
+            SendingMessage::ConnectionQuality { .. } => {
+                Some(protocol::Capability::ConnectionQuality)
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
-enum InternalMessage {}
+enum InternalMessage {
+    // A moderator admitted or denied the participant in the lobby. They are also denied when nobody is left.
+    Admit,
+    Deny,
+    // The last moderator left, and this participant takes over the lobby.
+    Promote,
+}
+
+/// Stats of the participant for the stats endpoint.
+#[derive(Message)]
+#[rtype(result = "stats::ParticipantStats")]
+pub struct CollectStats;
+
+impl Handler<CollectStats> for WebSocket {
+    type Result = ResponseFuture<stats::ParticipantStats>;
+
+    fn handle(&mut self, _msg: CollectStats, _ctx: &mut Self::Context) -> Self::Result {
+        Box::pin(self.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn received_messages_match_json() {
+        let messages = [
+            json!({ "action": "Ping" }),
+            json!({ "action": "Hello", "protocolVersion": 2, "capabilities": ["chat", "unknown"] }),
+            json!({ "action": "Offer", "sdp": { "type": "offer", "sdp": "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\n" } }),
+            json!({ "action": "SubscriberIce", "candidate": {
+                "candidate": "candidate:1 1 udp 2130706431 192.0.2.1 31300 typ host",
+                "sdpMid": "0",
+                "sdpMLineIndex": 0,
+                "usernameFragment": null,
+            } }),
+            json!({ "action": "SetPreferredLayer", "subscriberId": "s", "sid": 1, "tid": null }),
+            json!({ "action": "SetViewport", "tiles": [{ "publisherId": "p", "width": 640, "height": 360 }] }),
+            json!({ "action": "SendChat", "text": "hello", "to": "participant" }),
+            json!({ "action": "Admit", "participantId": "p" }),
+            json!({ "action": "PublisherClose" }),
+        ];
+        for message in messages {
+            let bytes = encoding::to_msgpack(&message).unwrap();
+            let decoded: ReceivedMessage = encoding::from_msgpack(&bytes).unwrap();
+            let expected: ReceivedMessage = serde_json::from_value(message).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", expected));
+        }
+    }
+
+    #[test]
+    fn sending_messages_match_json() {
+        let sdp = serde_json::from_value::<RTCSessionDescription>(
+            json!({ "type": "answer", "sdp": "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\n" }),
+        )
+        .unwrap();
+        let messages = [
+            SendingMessage::Pong,
+            SendingMessage::Answer { sdp },
+            SendingMessage::PublisherIce {
+                candidate: RTCIceCandidateInit {
+                    candidate: "candidate:1 1 udp 2130706431 192.0.2.1 31300 typ host".to_owned(),
+                    sdp_mid: Some("0".to_owned()),
+                    sdp_mline_index: Some(0),
+                    username_fragment: None,
+                },
+            },
+            SendingMessage::Published {
+                publisher_ids: vec!["a".to_owned(), "b".to_owned()],
+            },
+            SendingMessage::LayerChanged {
+                subscriber_id: "s".to_owned(),
+                sid: 0,
+                tid: None,
+            },
+            SendingMessage::ActiveSpeakers {
+                publisher_ids: vec!["a".to_owned()],
+                levels: vec![127],
+            },
+            SendingMessage::Welcome {
+                protocol_version: protocol::PROTOCOL_VERSION,
+                capabilities: vec![protocol::Capability::Chat],
+                participant_id: "p".to_owned(),
+            },
+            SendingMessage::Error {
+                code: protocol::ErrorCode::UnknownAction,
+                message: "unknown variant".to_owned(),
+                request_action: None,
+            },
+            SendingMessage::Chat {
+                message: chat::ChatMessage::new("a".to_owned(), None, "hello".to_owned()),
+            },
+        ];
+        for message in messages {
+            let bytes = encoding::to_msgpack(&message).unwrap();
+            assert_eq!(
+                encoding::decode_value(&bytes).unwrap(),
+                serde_json::to_value(&message).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_actions_are_parsed() {
+        let bytes = encoding::to_msgpack(&json!({ "action": "Nope", "a": 1 })).unwrap();
+        let message = encoding::from_msgpack::<ReceivedMessage>(&bytes).unwrap();
+        assert!(matches!(message, ReceivedMessage::Unknown));
+        let message = serde_json::from_str::<ReceivedMessage>(r#"{"action":"Nope"}"#).unwrap();
+        assert!(matches!(message, ReceivedMessage::Unknown));
+        // Known actions with invalid fields are still errors.
+        assert!(serde_json::from_str::<ReceivedMessage>(r#"{"action":"Admit"}"#).is_err());
+    }
+}